@@ -38,6 +38,11 @@ fn main() {
     } else {
         panic!("LLVM version 3.6 or higher is required. (Found {})", version);
     };
+    // The debug info C API codegen uses with this feature is newer.
+    if std::env::var_os("CARGO_FEATURE_DEBUG_INFO").is_some() &&
+       !VersionReq::parse(">=11.0").unwrap().matches(&version) {
+        panic!("The debug-info feature needs LLVM version 11 or higher. (Found {})", version);
+    }
 
     // Parse library linking flags from llvm-config.
     for arg in llvm_config("--ldflags").split_whitespace() {
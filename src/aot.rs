@@ -0,0 +1,95 @@
+//! This module contains ahead-of-time compilation to native object files.
+
+use std::path::Path;
+use llvm::*;
+use error::CompileError;
+
+/// Compiles `module` to a native object file at `path`.
+///
+/// `target_triple` picks the platform to compile for, like `x86_64-unknown-linux-gnu`.
+/// When it is `None` the object file is built for the host.
+pub fn compile_to_object(module: &Module, target_triple: Option<&str>, path: &Path) -> Result<(), CompileError> {
+    if let Some(triple) = target_triple {
+        module.set_target(triple);
+    }
+    module.compile(path, 2).map_err(|e| CompileError::ObjectFile(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs::File;
+    use std::io::Read;
+    use parser;
+    use codegen;
+    #[test]
+    fn test_compile_to_object() {
+        let mut parser = parser::Parser::from_source("def main() 1 + 2");
+        let ast = parser.parse_definition().unwrap();
+        let ctx = Context::new();
+        let builder = Builder::new(&ctx);
+        let module = Module::new("test", &ctx);
+        codegen::generate_function(&ast, &builder, &module, &ctx).unwrap();
+        let path = env::temp_dir().join("kaleidoscope_aot_test.o");
+        compile_to_object(&module, None, &path).unwrap();
+        let mut bytes = Vec::new();
+        File::open(&path).unwrap().read_to_end(&mut bytes).unwrap();
+        assert!(bytes.len() > 4);
+        let elf = &bytes[..4] == b"\x7fELF";
+        let mach_o = &bytes[..4] == &[0xcf, 0xfa, 0xed, 0xfe] || &bytes[..4] == &[0xce, 0xfa, 0xed, 0xfe];
+        assert!(elf || mach_o);
+    }
+    #[test]
+    fn test_generate_main() {
+        let ctx = Context::new();
+        let builder = Builder::new(&ctx);
+        let module = Module::new("test", &ctx);
+        let double = parser::Parser::from_source("def double(x) x + x").parse_definition().unwrap();
+        codegen::generate_function(&double, &builder, &module, &ctx).unwrap();
+        let exprs: Vec<_> = ["1+1", "double(2)"].iter().map(|source| {
+            parser::Parser::from_source(source).parse_top_level_expr().unwrap().body
+        }).collect();
+        codegen::generate_main(&exprs, &builder, &module, &ctx).unwrap();
+        let ir = codegen::emit_ir(&module);
+        assert!(ir.contains("define i32 @main()"));
+        assert!(ir.contains("declare i32 @printf(i8*, ...)"));
+        assert_eq!(ir.matches("@printf(").count(), 3);
+        let path = env::temp_dir().join("kaleidoscope_main_test.o");
+        compile_to_object(&module, None, &path).unwrap();
+    }
+    #[test]
+    fn test_generate_main_with_extern_printf() {
+        let ctx = Context::new();
+        let builder = Builder::new(&ctx);
+        let module = Module::new("test", &ctx);
+        let printf = parser::Parser::from_source("extern printf(x)").parse_extern().unwrap();
+        codegen::generate_prototype(&printf, &module, &ctx).unwrap();
+        let exprs = vec![parser::Parser::from_source("1+1").parse_top_level_expr().unwrap().body];
+        assert_eq!(codegen::generate_main(&exprs, &builder, &module, &ctx).err(),
+                   Some(CompileError::ConflictingDeclaration(String::from("printf"))));
+        // main isn't left half built
+        assert!(module.get_function("main").is_none());
+        module.verify().unwrap();
+    }
+    #[test]
+    fn test_generate_main_failures() {
+        let ctx = Context::new();
+        let builder = Builder::new(&ctx);
+        let module = Module::new("test", &ctx);
+        let before = codegen::emit_ir(&module);
+        let exprs = vec![parser::Parser::from_source("1+1").parse_top_level_expr().unwrap().body,
+                         parser::Parser::from_source("x").parse_top_level_expr().unwrap().body];
+        assert_eq!(codegen::generate_main(&exprs, &builder, &module, &ctx).err(),
+                   Some(CompileError::UnknownVariable(String::from("x"))));
+        // Neither main, printf nor the format string are left behind
+        assert_eq!(codegen::emit_ir(&module), before);
+        // A main from the program isn't quietly renamed out of the way
+        let main = parser::Parser::from_source("def main() 1").parse_definition().unwrap();
+        codegen::generate_function(&main, &builder, &module, &ctx).unwrap();
+        assert_eq!(codegen::generate_main(&exprs[..1], &builder, &module, &ctx).err(),
+                   Some(CompileError::ConflictingDeclaration(String::from("main"))));
+        let ir = codegen::emit_ir(&module);
+        assert!(!ir.contains("main1") && !ir.contains("printf"), "{}", ir);
+    }
+}
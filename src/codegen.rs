@@ -3,81 +3,1098 @@ use llvm::*;
 use llvm::Attribute::*;
 use parser::*;
 use parser;
+use error::CompileError;
 use llvm::Function;
+use std::ffi::CString;
+use std::os::raw::{c_char, c_int, c_uint, c_void};
+use std::ptr;
 
+// The bindings don't expose LLVM's inliner or individual passes, so we use the C API directly.
+// A &Module is a pointer to the underlying LLVMModule.
+extern "C" {
+    fn LLVMCreatePassManager() -> *mut c_void;
+    fn LLVMAddAlwaysInlinerPass(pass_manager: *mut c_void);
+    fn LLVMRunPassManager(pass_manager: *mut c_void, module: *mut c_void) -> c_int;
+    fn LLVMDisposePassManager(pass_manager: *mut c_void);
+    // llvm-alt can't make variadic function types, which printf needs
+    fn LLVMFunctionType(ret: *mut c_void, params: *mut *mut c_void, count: c_uint, is_var_arg: c_int) -> *mut c_void;
+    fn LLVMAddFunction(module: *mut c_void, name: *const c_char, ty: *mut c_void) -> *mut c_void;
+    fn LLVMIsFunctionVarArg(ty: *mut c_void) -> c_int;
+    fn LLVMSetTailCall(call: *mut c_void, is_tail: c_int);
+    fn LLVMAddTailCallEliminationPass(pass_manager: *mut c_void);
+    fn LLVMAddPromoteMemoryToRegisterPass(pass_manager: *mut c_void);
+    fn LLVMAddInstructionCombiningPass(pass_manager: *mut c_void);
+    fn LLVMAddReassociatePass(pass_manager: *mut c_void);
+    fn LLVMAddGVNPass(pass_manager: *mut c_void);
+    fn LLVMAddCFGSimplificationPass(pass_manager: *mut c_void);
+    fn LLVMAddAggressiveDCEPass(pass_manager: *mut c_void);
+    fn LLVMTypeOf(value: *mut c_void) -> *mut c_void;
+    fn LLVMGetElementType(ty: *mut c_void) -> *mut c_void;
+    fn LLVMSetValueName(value: *mut c_void, name: *const c_char);
+    fn LLVMReplaceAllUsesWith(old: *mut c_void, new: *mut c_void);
+    fn LLVMDeleteFunction(func: *mut c_void);
+    fn LLVMClearInsertionPosition(builder: *mut c_void);
+    fn LLVMDeleteGlobal(global: *mut c_void);
+    fn LLVMGetUndef(ty: *mut c_void) -> *mut c_void;
+    // The bindings only make functions that return something
+    fn LLVMVoidTypeInContext(context: *mut c_void) -> *mut c_void;
+    fn LLVMBuildCall(builder: *mut c_void, func: *mut c_void, args: *mut *mut c_void, count: c_uint,
+                     name: *const c_char) -> *mut c_void;
+    fn LLVMBuildUnreachable(builder: *mut c_void) -> *mut c_void;
+    fn LLVMGetFirstUse(value: *mut c_void) -> *mut c_void;
+    fn LLVMGetNextUse(using: *mut c_void) -> *mut c_void;
+    fn LLVMGetUser(using: *mut c_void) -> *mut c_void;
+    fn LLVMIsAInstruction(value: *mut c_void) -> *mut c_void;
+    fn LLVMGetInstructionParent(instr: *mut c_void) -> *mut c_void;
+    fn LLVMGetBasicBlockParent(block: *mut c_void) -> *mut c_void;
+    fn LLVMBuildFCmp(builder: *mut c_void, pred: c_uint, lhs: *mut c_void, rhs: *mut c_void,
+                     name: *const c_char) -> *mut c_void;
+}
 
-pub fn generate_expression<'a, 'b>(node: &'b Expr,
-                               values: &'a HashMap<&String, &'a Arg>,
-                               builder: &'a CSemiBox<'a, Builder>,
-                               module: &'a CSemiBox<'a, Module>,
-                               context: &'a CBox<Context>) -> Result<&'a Value, String> {
-    match *node {
-        Expr::Number(n) => Ok(n.compile(&context)),
-        Expr::Variable(ref v) => Ok(values.get(v).ok_or(
-                                format!("There is no variable named {}", v))?
-                            ),
-        Expr::Binary {op, ref lhs, ref rhs} => {
-            let l = generate_expression(&*lhs, &values, &builder, &module, &context)?;
-            let r = generate_expression(&*rhs, &values, &builder, &module, &context)?;
-            match op {
-                '+' => Ok(builder.build_add(&l, &r)),
-                '-' => Ok(builder.build_sub(&l, &r)),
-                '*' => Ok(builder.build_mul(&l, &r)),
-                '<' => {
-                    let comp = builder.build_cmp(&l, &r, Predicate::LessThan);
-                    let res = builder.build_bit_cast(&comp, &Type::get::<f64>(&context));
-                    Ok(res)
-                }
-                _ => return Err(format!("{} is an invalid operator!", op))
-            }
+// The bindings don't know about debug info at all, and the C API for it
+// needs LLVM 11, so it is only used with the debug-info feature.
+#[cfg(feature = "debug-info")]
+extern "C" {
+    fn LLVMCreateDIBuilder(module: *mut c_void) -> *mut c_void;
+    fn LLVMDIBuilderFinalize(di_builder: *mut c_void);
+    fn LLVMDisposeDIBuilder(di_builder: *mut c_void);
+    fn LLVMDIBuilderCreateFile(di_builder: *mut c_void, name: *const c_char, name_len: usize,
+                               dir: *const c_char, dir_len: usize) -> *mut c_void;
+    fn LLVMDIBuilderCreateCompileUnit(di_builder: *mut c_void, lang: c_uint, file: *mut c_void,
+                                      producer: *const c_char, producer_len: usize, is_optimized: c_int,
+                                      flags: *const c_char, flags_len: usize, runtime_version: c_uint,
+                                      split_name: *const c_char, split_name_len: usize, kind: c_uint,
+                                      dwo_id: c_uint, split_debug_inlining: c_int, for_profiling: c_int,
+                                      sys_root: *const c_char, sys_root_len: usize,
+                                      sdk: *const c_char, sdk_len: usize) -> *mut c_void;
+    fn LLVMDIBuilderCreateBasicType(di_builder: *mut c_void, name: *const c_char, name_len: usize,
+                                    bits: u64, encoding: c_uint, flags: c_int) -> *mut c_void;
+    fn LLVMDIBuilderCreateSubroutineType(di_builder: *mut c_void, file: *mut c_void, types: *mut *mut c_void,
+                                         count: c_uint, flags: c_int) -> *mut c_void;
+    fn LLVMDIBuilderCreateFunction(di_builder: *mut c_void, scope: *mut c_void, name: *const c_char,
+                                   name_len: usize, linkage_name: *const c_char, linkage_name_len: usize,
+                                   file: *mut c_void, line: c_uint, ty: *mut c_void, is_local: c_int,
+                                   is_definition: c_int, scope_line: c_uint, flags: c_int,
+                                   is_optimized: c_int) -> *mut c_void;
+    fn LLVMSetSubprogram(func: *mut c_void, subprogram: *mut c_void);
+    fn LLVMDIBuilderCreateDebugLocation(context: *mut c_void, line: c_uint, col: c_uint,
+                                        scope: *mut c_void, inlined_at: *mut c_void) -> *mut c_void;
+    fn LLVMValueAsMetadata(value: *mut c_void) -> *mut c_void;
+    fn LLVMGetCurrentDebugLocation2(builder: *mut c_void) -> *mut c_void;
+    fn LLVMSetCurrentDebugLocation2(builder: *mut c_void, location: *mut c_void);
+    fn LLVMGetModuleFlag(module: *mut c_void, key: *const c_char, key_len: usize) -> *mut c_void;
+    fn LLVMAddModuleFlag(module: *mut c_void, behavior: c_uint, key: *const c_char, key_len: usize,
+                         value: *mut c_void);
+    fn LLVMDebugMetadataVersion() -> c_uint;
+    fn LLVMGetModuleIdentifier(module: *mut c_void, len: *mut usize) -> *const c_char;
+}
+
+/// The type of a function that takes `params` followed by any number of extra arguments.
+fn vararg_function_type(ret: &Type, params: &[&Type]) -> *mut c_void {
+    let mut params: Vec<*mut c_void> = params.iter().map(|ty| *ty as *const Type as *mut c_void).collect();
+    unsafe {
+        LLVMFunctionType(ret as *const Type as *mut c_void, params.as_mut_ptr(), params.len() as c_uint, 1)
+    }
+}
+
+/// Declares a function that takes `params` followed by any number of extra arguments.
+fn add_vararg_function<'a>(name: &str,
+                           ret: &Type,
+                           params: &[&Type],
+                           module: &'a CSemiBox<'a, Module>) -> &'a Function {
+    let ty = vararg_function_type(ret, params);
+    let name = CString::new(name).unwrap();
+    unsafe {
+        let func = LLVMAddFunction(&**module as *const Module as *mut c_void, name.as_ptr(), ty);
+        &*(func as *const Function)
+    }
+}
+
+/// The type of `func` itself, as opposed to the pointer to it that is its value.
+/// Types are unique within a context, so these can be compared.
+fn function_type(func: &Function) -> *mut c_void {
+    unsafe { LLVMGetElementType(LLVMTypeOf(func as *const Function as *mut c_void)) }
+}
+
+fn is_vararg(func: &Function) -> bool {
+    unsafe { LLVMIsFunctionVarArg(func.get_signature() as *const FunctionType as *mut c_void) != 0 }
+}
+
+
+/// The function guarded code calls on every function call and loop iteration.
+/// It returns 0.0 once the code should stop, which makes every function return
+/// 0.0 straight away and every loop end.
+///
+/// Code is only guarded if the module it is generated into declares this, so
+/// code that doesn't need a guard doesn't pay for one.
+pub const STEP_FUNCTION: &'static str = "kaleidoscope_step";
+
+/// Creates a stack slot for a variable at the start of the function's entry block.
+/// Keeping all the allocas in the entry block lets mem2reg turn them into registers.
+fn create_entry_block_alloca<'a>(func: &'a Function,
+                                 context: &'a CBox<Context>) -> &'a Value {
+    let builder = Builder::new(&context);
+    let entry = func.get_entry().unwrap();
+    match entry.get_first() {
+        Some(instr) => builder.position_before(instr),
+        None => builder.position_at_end(entry),
+    }
+    builder.build_alloca(Type::get::<f64>(&context))
+}
+
+/// The LLVM intrinsic that calls to `name` are lowered to when there is no
+/// function called `name`. All of them take one argument, see `parser::builtin_arity`.
+pub fn intrinsic(name: &str) -> Option<&'static str> {
+    match name {
+        "sin" => Some("llvm.sin.f64"),
+        "cos" => Some("llvm.cos.f64"),
+        "sqrt" => Some("llvm.sqrt.f64"),
+        _ => None,
+    }
+}
+
+/// Errors unless `args` are as many as the builtin `name` takes.
+fn check_builtin_arity(name: &str, args: &[Box<Expr>]) -> Result<(), CompileError> {
+    let expected = parser::builtin_arity(name).unwrap();
+    if args.len() != expected {
+        return Err(CompileError::ArityMismatch {
+            name: String::from(name),
+            expected: expected,
+            found: args.len(),
+        })
+    }
+    Ok(())
+}
+
+/// Declares the intrinsic or C function `name`, unless that was already done.
+fn declare_intrinsic<'a>(name: &str,
+                         arity: usize,
+                         module: &'a CSemiBox<'a, Module>,
+                         context: &'a CBox<Context>) -> Result<&'a Function, CompileError> {
+    match module.get_function(name) {
+        Some(func) => Ok(func),
+        None => {
+            let args = (0..arity).map(|i| format!("x{}", i)).collect();
+            generate_prototype(&Prototype::new(String::from(name), args), module, context)
         },
-        Expr::Call {ref name, ref args} => {
-            let func = module.get_function(name).ok_or(format!("There is no function named {}!", name))?;
-            let passed_args = args.len();
-            let expected_args = func.get_signature().num_params();
-            if expected_args != passed_args {
-                return Err(format!("{} takes {} args, but you passed {}!", name, expected_args, passed_args))
+    }
+}
+
+/// Declares C's `void abort()`, which never returns.
+fn declare_abort<'a>(module: &'a CSemiBox<'a, Module>,
+                     context: &'a CBox<Context>) -> Result<&'a Function, CompileError> {
+    let ty = unsafe {
+        LLVMFunctionType(LLVMVoidTypeInContext(&**context as *const Context as *mut c_void), ptr::null_mut(), 0, 0)
+    };
+    match module.get_function("abort") {
+        Some(abort) if function_type(abort) == ty => Ok(abort),
+        // A Kaleidoscope abort returns a double, and might not stop anything
+        Some(_) => Err(CompileError::ConflictingDeclaration(String::from("abort"))),
+        None => {
+            let name = CString::new("abort").unwrap();
+            let abort = unsafe {
+                let func = LLVMAddFunction(&**module as *const Module as *mut c_void, name.as_ptr(), ty);
+                &*(func as *const Function)
+            };
+            abort.add_attribute(NoReturn);
+            Ok(abort)
+        },
+    }
+}
+
+fn is_integer(value: &Value) -> bool {
+    value.get_type().is_integer()
+}
+
+/// Compares two doubles the way IEEE 754 says to, where every comparison with
+/// a NaN is false except `!=`, which is true. That means the ordered LLVM
+/// predicates for everything but `!=`, which is unordered. The bindings don't
+/// let us choose, so the instruction is built directly.
+fn build_float_cmp<'a>(builder: &'a Builder, l: &Value, r: &Value, pred: Predicate) -> &'a Value {
+    // Values of LLVMRealPredicate
+    let pred = match pred {
+        Predicate::Equal => 1, // oeq
+        Predicate::GreaterThan => 2, // ogt
+        Predicate::GreaterThanOrEqual => 3, // oge
+        Predicate::LessThan => 4, // olt
+        Predicate::LessThanOrEqual => 5, // ole
+        Predicate::NotEqual => 14, // une
+    };
+    let name = CString::new("").unwrap();
+    unsafe {
+        let cmp = LLVMBuildFCmp(builder as *const Builder as *mut c_void, pred,
+                                l as *const Value as *mut c_void, r as *const Value as *mut c_void,
+                                name.as_ptr());
+        &*(cmp as *const Value)
+    }
+}
+
+/// Everything needed to generate code into the body of `func`.
+///
+/// `'a` is the lifetime of the LLVM objects and `'b` the lifetime of the
+/// syntax tree being compiled.
+#[derive(Clone)]
+pub struct CodegenCtx<'a, 'b> {
+    builder: &'a CSemiBox<'a, Builder>,
+    module: &'a CSemiBox<'a, Module>,
+    context: &'a CBox<Context>,
+    func: &'a Function,
+    /// Maps every variable in scope to the alloca holding its value.
+    values: HashMap<&'b String, &'a Value>,
+    /// The function's debug info, if it has any, which the instructions are
+    /// attributed to along with the position of the node they came from.
+    #[cfg(feature = "debug-info")]
+    debug_scope: Option<*mut c_void>,
+}
+
+impl<'a, 'b> CodegenCtx<'a, 'b> {
+    /// A context for generating code at the builder's current position in
+    /// `func`, with no variables in scope.
+    pub fn new(builder: &'a CSemiBox<'a, Builder>,
+               module: &'a CSemiBox<'a, Module>,
+               context: &'a CBox<Context>,
+               func: &'a Function) -> CodegenCtx<'a, 'b> {
+        CodegenCtx {
+            builder: builder,
+            module: module,
+            context: context,
+            func: func,
+            values: HashMap::new(),
+            #[cfg(feature = "debug-info")]
+            debug_scope: None,
+        }
+    }
+
+    /// Attributes the instructions built from now on to `span`, if the
+    /// function has debug info and there is a span. Returns the location
+    /// to go back to afterwards with `restore_debug_location`.
+    #[cfg(feature = "debug-info")]
+    fn set_debug_location(&self, span: Option<SourceRange>) -> Option<*mut c_void> {
+        let (scope, span) = match (self.debug_scope, span) {
+            (Some(scope), Some(span)) => (scope, span),
+            _ => return None,
+        };
+        let builder: &Builder = self.builder;
+        let builder = builder as *const Builder as *mut c_void;
+        let context = &**self.context as *const Context as *mut c_void;
+        unsafe {
+            let outer = LLVMGetCurrentDebugLocation2(builder);
+            let location = LLVMDIBuilderCreateDebugLocation(context, span.start.line, span.start.col,
+                                                            scope, ptr::null_mut());
+            LLVMSetCurrentDebugLocation2(builder, location);
+            Some(outer)
+        }
+    }
+
+    #[cfg(not(feature = "debug-info"))]
+    fn set_debug_location(&self, _span: Option<SourceRange>) -> Option<*mut c_void> {
+        None
+    }
+
+    #[cfg(feature = "debug-info")]
+    fn restore_debug_location(&self, outer: Option<*mut c_void>) {
+        if let Some(outer) = outer {
+            let builder: &Builder = self.builder;
+            unsafe {
+                LLVMSetCurrentDebugLocation2(builder as *const Builder as *mut c_void, outer);
             }
-            let mut passed = Vec::new();
-            for arg in args {
-                passed.push(generate_expression(&arg, &values, &builder, &module, &context)?)
+        }
+    }
+
+    #[cfg(not(feature = "debug-info"))]
+    fn restore_debug_location(&self, _outer: Option<*mut c_void>) {}
+
+    /// Takes a step in guarded code, branching to `stop` if that was one too many.
+    fn build_step(&self, stop: &BasicBlock, step: &Function) {
+        let go_on = self.builder.build_call(step, &[]);
+        let go_on = self.build_truth(go_on);
+        let next_block = self.func.append("step");
+        self.builder.build_cond_br(&go_on, &next_block, Some(stop));
+        self.builder.position_at_end(next_block);
+    }
+
+    /// Ends an iteration of a loop, going around again if `cond` is true.
+    fn build_loop_br(&self, cond: &Value, loop_block: &BasicBlock, after_block: &BasicBlock) {
+        match self.module.get_function(STEP_FUNCTION) {
+            Some(step) => {
+                let step_block = self.func.append("loopstep");
+                self.builder.build_cond_br(cond, &step_block, Some(after_block));
+                self.builder.position_at_end(step_block);
+                self.build_step(after_block, step);
+                self.builder.build_br(loop_block);
+            },
+            None => {
+                self.builder.build_cond_br(cond, loop_block, Some(after_block));
+            },
+        }
+    }
+
+    /// Converts integers to doubles, leaving doubles alone.
+    fn to_float(&self, value: &'a Value) -> &'a Value {
+        if is_integer(value) {
+            self.builder.build_si_to_fp(value, Type::get::<f64>(&self.context))
+        } else {
+            value
+        }
+    }
+
+    /// Turns a double into an i1 for branching on. Any value but 0.0 is
+    /// true, including NaN, just like in C.
+    fn build_truth(&self, value: &Value) -> &'a Value {
+        let zero = 0.0f64.compile(&self.context);
+        build_float_cmp(self.builder, value, zero, Predicate::NotEqual)
+    }
+
+    /// Compares `l` and `r`, giving 1.0 if `pred` holds and 0.0 if it doesn't.
+    /// Doubles are compared as described on `build_float_cmp`, so `x != x`
+    /// is 1.0 and every other comparison is 0.0 when `x` is NaN.
+    fn gen_comparison(&self, l: &Value, r: &Value, pred: Predicate) -> &'a Value {
+        // The comparison gives us an i1, which we convert to 0.0 or 1.0
+        let comp = if is_integer(l) {
+            self.builder.build_cmp(l, r, pred)
+        } else {
+            build_float_cmp(self.builder, l, r, pred)
+        };
+        self.builder.build_ui_to_fp(&comp, &Type::get::<f64>(&self.context))
+    }
+
+    /// Generates an expression whose value has to be a double.
+    ///
+    /// Integers only exist inside expressions. Function arguments and return
+    /// values, variables and conditions are always doubles.
+    fn generate_float(&self, node: &'b Expr) -> Result<&'a Value, CompileError> {
+        let value = self.generate_expression(node)?;
+        Ok(self.to_float(value))
+    }
+
+    /// Generates the code for an expression.
+    pub fn generate_expression(&self, node: &'b Expr) -> Result<&'a Value, CompileError> {
+        self.generate(node, false)
+    }
+
+    /// Generates the code for an expression, which is in tail position if
+    /// `tail` is true, meaning its value is what the function returns.
+    fn generate(&self, node: &'b Expr, tail: bool) -> Result<&'a Value, CompileError> {
+        // Whatever the parent node builds after this one is still the parent's
+        let outer = self.set_debug_location(node.span);
+        let value = self.generate_node(node, tail);
+        self.restore_debug_location(outer);
+        value
+    }
+
+    fn generate_node(&self, node: &'b Expr, tail: bool) -> Result<&'a Value, CompileError> {
+        let CodegenCtx {builder, module, context, func, ref values, ..} = *self;
+        match node.kind {
+            ExprKind::Number(n) => Ok(n.compile(&context)),
+            ExprKind::Integer(n) => Ok(n.compile(&context)),
+            ExprKind::Str(ref string) => {
+                // Strings are stored as NUL terminated i8 arrays so they can be passed to C
+                let mut chars = string.clone();
+                chars.push('\0');
+                let global = module.add_global_constant("str", chars.as_str().compile(&context));
+                let zero = 0i32.compile(&context);
+                Ok(builder.build_gep(global, &[zero, zero]))
+            },
+            ExprKind::Variable(ref v) => match values.get(v) {
+                Some(ptr) => Ok(builder.build_load(ptr)),
+                // The name of a function gives its address, smuggled through a
+                // double so it can be passed around like any other value
+                None => {
+                    let callee = module.get_function(v).ok_or(CompileError::UnknownVariable(v.clone()))?;
+                    let addr = builder.build_ptr_to_int(callee, Type::get::<u64>(&context));
+                    Ok(builder.build_bit_cast(addr, Type::get::<f64>(&context)))
+                },
+            },
+            // Assignment is special because the left hand side isn't evaluated
+            ExprKind::Binary {ref op, ref lhs, ref rhs} if op == "=" => {
+                let name = match lhs.kind {
+                    ExprKind::Variable(ref name) => name,
+                    _ => return Err(CompileError::InvalidAssignment),
+                };
+                let value = self.generate_float(&*rhs)?;
+                let ptr = values.get(name).ok_or(CompileError::UnknownVariable(name.clone()))?;
+                builder.build_store(value, ptr);
+                Ok(value)
+            },
+            // The value of the left hand side of `:` is thrown away
+            ExprKind::Binary {ref op, ref lhs, ref rhs} if op == ":" => {
+                self.generate_expression(&*lhs)?;
+                self.generate(&*rhs, tail)
+            },
+            // The right hand side of a logical operator is only evaluated when it
+            // decides the result, so these are more like an if than arithmetic.
+            ExprKind::Binary {ref op, ref lhs, ref rhs} if op == "&&" || op == "||" => {
+                let l = self.generate_float(&*lhs)?;
+                let l = self.build_truth(l);
+                let lhs_end = builder.get_insert_block();
+                let rhs_block = func.append("logicrhs");
+                let merge_block = func.append("logiccont");
+                // The value when the right hand side is skipped
+                let short_value = if op == "&&" {
+                    builder.build_cond_br(&l, &rhs_block, Some(&merge_block));
+                    0.0f64.compile(&context)
+                } else {
+                    builder.build_cond_br(&l, &merge_block, Some(&rhs_block));
+                    1.0f64.compile(&context)
+                };
+
+                builder.position_at_end(rhs_block);
+                let r = self.generate_float(&*rhs)?;
+                let r = self.build_truth(r);
+                let rhs_value = builder.build_ui_to_fp(&r, &Type::get::<f64>(&context));
+                builder.build_br(&merge_block);
+                let rhs_end = builder.get_insert_block();
+
+                builder.position_at_end(merge_block);
+                Ok(builder.build_phi(Type::get::<f64>(&context),
+                                     &[(short_value, lhs_end), (rhs_value, rhs_end)]))
+            },
+            ExprKind::Binary {ref op, ref lhs, ref rhs} => {
+                let l = self.generate_expression(&*lhs)?;
+                let r = self.generate_expression(&*rhs)?;
+                // Arithmetic on two integers stays integer arithmetic, but if
+                // either side is a double the other one gets promoted.
+                let (l, r) = if is_integer(l) && is_integer(r) {
+                    (l, r)
+                } else {
+                    (self.to_float(l), self.to_float(r))
+                };
+                match op.as_str() {
+                    "+" => Ok(builder.build_add(&l, &r)),
+                    "-" => Ok(builder.build_sub(&l, &r)),
+                    "*" => Ok(builder.build_mul(&l, &r)),
+                    "/" => Ok(builder.build_div(&l, &r)),
+                    "%" => Ok(builder.build_rem(&l, &r)),
+                    "^" => {
+                        let pow = declare_intrinsic("llvm.pow.f64", 2, &module, &context)?;
+                        let l = self.to_float(l);
+                        let r = self.to_float(r);
+                        Ok(builder.build_call(&pow, &[l, r]))
+                    },
+                    "<" => Ok(self.gen_comparison(l, r, Predicate::LessThan)),
+                    ">" => Ok(self.gen_comparison(l, r, Predicate::GreaterThan)),
+                    "<=" => Ok(self.gen_comparison(l, r, Predicate::LessThanOrEqual)),
+                    ">=" => Ok(self.gen_comparison(l, r, Predicate::GreaterThanOrEqual)),
+                    "==" => Ok(self.gen_comparison(l, r, Predicate::Equal)),
+                    "!=" => Ok(self.gen_comparison(l, r, Predicate::NotEqual)),
+                    // Anything else has to be a user-defined operator, which is
+                    // just a call to the function implementing it.
+                    _ => {
+                        let op_func = module.get_function(&format!("binary{}", op))
+                            .ok_or(CompileError::InvalidOperator(op.clone()))?;
+                        let l = self.to_float(l);
+                        let r = self.to_float(r);
+                        Ok(builder.build_call(&op_func, &[l, r]))
+                    }
+                }
+            },
+            // Calling a variable calls the function whose address it holds
+            ExprKind::Call {ref name, ref args} if values.contains_key(name) => {
+                let addr = builder.build_load(values[name]);
+                let addr = builder.build_bit_cast(addr, Type::get::<u64>(&context));
+                let arg_types = vec![Type::get::<f64>(&context); args.len()];
+                let sig = FunctionType::new(Type::get::<f64>(&context), &arg_types);
+                let callee = builder.build_int_to_ptr(addr, PointerType::new(sig));
+                let mut passed = Vec::new();
+                for arg in args {
+                    passed.push(self.generate_float(&arg)?)
+                }
+                // build_call only takes functions, but LLVM is happy to call any pointer to one
+                let callee = unsafe { &*(callee as *const Value as *const Function) };
+                Ok(builder.build_call(callee, &passed))
+            },
+            // Unless the user defined their own, max and min pick an argument
+            // with a select instead of branching. Like `a > b ? a : b` in C,
+            // they give the second argument when either one is NaN.
+            ExprKind::Call {ref name, ref args} if (name == "max" || name == "min") && module.get_function(name).is_none() => {
+                check_builtin_arity(name, args)?;
+                let lhs = self.generate_float(&args[0])?;
+                let rhs = self.generate_float(&args[1])?;
+                let pred = if name == "max" { Predicate::GreaterThan } else { Predicate::LessThan };
+                let cond = build_float_cmp(builder, lhs, rhs, pred);
+                Ok(builder.build_select(cond, lhs, rhs))
+            },
+            // assert(cond) aborts the whole program if cond is false, and
+            // evaluates to cond otherwise
+            ExprKind::Call {ref name, ref args} if name == "assert" && module.get_function(name).is_none() => {
+                check_builtin_arity(name, args)?;
+                let abort = declare_abort(&module, &context)?;
+                let cond = self.generate_float(&args[0])?;
+                let holds = self.build_truth(cond);
+                let fail_block = func.append("assertfail");
+                let ok_block = func.append("assertok");
+                builder.build_cond_br(&holds, &ok_block, Some(&fail_block));
+
+                builder.position_at_end(fail_block);
+                let b: &Builder = builder;
+                let b = b as *const Builder as *mut c_void;
+                unsafe {
+                    LLVMBuildCall(b, abort as *const Function as *mut c_void, ptr::null_mut(), 0,
+                                  "\0".as_ptr() as *const c_char);
+                    LLVMBuildUnreachable(b);
+                }
+
+                builder.position_at_end(ok_block);
+                Ok(cond)
+            },
+            ExprKind::Call {ref name, ref args} => {
+                // Functions the user defined or declared win over intrinsics with the same name
+                let callee = match (module.get_function(name), intrinsic(name)) {
+                    (Some(callee), _) => callee,
+                    (None, Some(intrinsic)) => declare_intrinsic(intrinsic, 1, &module, &context)?,
+                    (None, None) => return Err(CompileError::UnknownFunction(name.clone())),
+                };
+                let passed_args = args.len();
+                let expected_args = callee.get_signature().num_params();
+                if expected_args != passed_args && !(is_vararg(callee) && passed_args > expected_args) {
+                    return Err(CompileError::ArityMismatch {
+                        name: name.clone(),
+                        expected: expected_args,
+                        found: passed_args,
+                    })
+                }
+                let mut passed = Vec::new();
+                for arg in args {
+                    passed.push(self.generate_float(&arg)?)
+                }
+                let call = builder.build_call(&callee, &passed);
+                // A function calling itself as the last thing it does can
+                // jump back to its start instead, see `optimize`
+                if tail && callee as *const Function == func as *const Function {
+                    unsafe {
+                        LLVMSetTailCall(call as *const Value as *mut c_void, 1);
+                    }
+                }
+                Ok(call)
+            },
+            ExprKind::Unary {op, ref operand} => {
+                let value = self.generate_expression(&*operand)?;
+                match op {
+                    '-' => {
+                        let zero = if is_integer(value) {
+                            0i64.compile(&context)
+                        } else {
+                            0.0f64.compile(&context)
+                        };
+                        Ok(builder.build_sub(&zero, &value))
+                    },
+                    '!' => {
+                        let value = self.to_float(value);
+                        let zero = 0.0f64.compile(&context);
+                        Ok(self.gen_comparison(value, &zero, Predicate::Equal))
+                    },
+                    _ => Err(CompileError::InvalidOperator(op.to_string()))
+                }
+            },
+            ExprKind::IfElse {ref pred, ref if_clause, ref else_clause} => {
+                let cond = self.generate_float(&*pred)?;
+                // Comparisons evaluate to 1.0 or 0.0, and any nonzero value is true.
+                let cond = self.build_truth(cond);
+                let then_block = func.append("then");
+                let else_block = func.append("else");
+                let merge_block = func.append("ifcont");
+                builder.build_cond_br(&cond, &then_block, Some(&else_block));
+
+                builder.position_at_end(then_block);
+                let then_value = self.to_float(self.generate(&*if_clause, tail)?);
+                builder.build_br(&merge_block);
+                // Codegen of the clause can change the current block, so we need
+                // to get the block that actually ends up branching to merge_block.
+                let then_end = builder.get_insert_block();
+
+                builder.position_at_end(else_block);
+                let else_value = self.to_float(self.generate(&*else_clause, tail)?);
+                builder.build_br(&merge_block);
+                let else_end = builder.get_insert_block();
+
+                builder.position_at_end(merge_block);
+                Ok(builder.build_phi(Type::get::<f64>(&context),
+                                     &[(then_value, then_end), (else_value, else_end)]))
+            },
+            ExprKind::For {ref var, ref start, ref cond, ref step, ref body} => {
+                let variable = create_entry_block_alloca(&func, &context);
+                let start_value = self.generate_float(&*start)?;
+                builder.build_store(start_value, variable);
+                let loop_block = func.append("loop");
+                builder.build_br(&loop_block);
+
+                builder.position_at_end(loop_block);
+                // The loop variable shadows any existing variable with the same name
+                // for the duration of the loop.
+                let mut loop_ctx = self.clone();
+                loop_ctx.values.insert(var, variable);
+                loop_ctx.generate_expression(&*body)?;
+                let step_value = match *step {
+                    Some(ref step) => loop_ctx.generate_float(&*step)?,
+                    None => 1.0f64.compile(&context),
+                };
+                let end_cond = loop_ctx.generate_float(&*cond)?;
+                // The body may have assigned to the variable, so it has to be reloaded
+                let current_value = builder.build_load(variable);
+                let next_value = builder.build_add(&current_value, &step_value);
+                builder.build_store(next_value, variable);
+                let end_cond = self.build_truth(end_cond);
+                let after_block = func.append("afterloop");
+                self.build_loop_br(&end_cond, &loop_block, &after_block);
+
+                builder.position_at_end(after_block);
+                // A for loop always evaluates to 0.0
+                Ok(0.0f64.compile(&context))
+            },
+            ExprKind::While {ref cond, ref body} => {
+                let cond_block = func.append("whilecond");
+                let body_block = func.append("whilebody");
+                let after_block = func.append("afterwhile");
+                builder.build_br(&cond_block);
+
+                builder.position_at_end(cond_block);
+                let cond_value = self.generate_float(&*cond)?;
+                let cond_value = self.build_truth(cond_value);
+                self.build_loop_br(&cond_value, &body_block, &after_block);
+
+                builder.position_at_end(body_block);
+                self.generate_expression(&*body)?;
+                builder.build_br(&cond_block);
+
+                builder.position_at_end(after_block);
+                // Like a for loop, a while loop always evaluates to 0.0
+                Ok(0.0f64.compile(&context))
+            },
+            ExprKind::Sequence(ref exprs) => {
+                let mut last = 0.0f64.compile(&context);
+                for (i, expr) in exprs.iter().enumerate() {
+                    last = self.generate(&*expr, tail && i + 1 == exprs.len())?;
+                }
+                Ok(last)
+            },
+            ExprKind::VarIn {ref vars, ref body} => {
+                let mut body_ctx = self.clone();
+                for &(ref name, ref init) in vars {
+                    // Each initializer can see the variables declared before it,
+                    // but not the one it initializes.
+                    let init_value = match *init {
+                        Some(ref init) => body_ctx.generate_float(&*init)?,
+                        None => 0.0f64.compile(&context),
+                    };
+                    let variable = create_entry_block_alloca(&func, &context);
+                    builder.build_store(init_value, variable);
+                    body_ctx.values.insert(name, variable);
+                }
+                body_ctx.generate(&*body, tail)
             }
-            Ok(builder.build_call(&func, &passed))
         }
     }
 }
 pub fn generate_prototype<'a>(prototype: &Prototype,
                           module: &'a CSemiBox<'a, Module>,
-                          context: &'a CBox<Context>) -> Result<&'a Function, String> {
+                          context: &'a CBox<Context>) -> Result<&'a Function, CompileError> {
     let arg_types = vec![Type::get::<f64>(&context); prototype.args.len()];
-    let sig = FunctionType::new(Type::get::<f64>(&context), &arg_types);
-    let func = module.add_function(&prototype.name, sig);
+    let func = if prototype.is_vararg {
+        add_vararg_function(&prototype.name, Type::get::<f64>(&context), &arg_types, module)
+    } else {
+        let sig = FunctionType::new(Type::get::<f64>(&context), &arg_types);
+        module.add_function(&prototype.name, sig)
+    };
+    if prototype.always_inline {
+        func.add_attribute(AlwaysInline);
+    }
     for arg_index in 0..prototype.args.len() {
         &func[arg_index].set_name(&prototype.args[arg_index]);
     }
     Ok(func)
 }
+/// Whether anything outside of `func`'s own body refers to it.
+unsafe fn used_elsewhere(func: *mut c_void) -> bool {
+    let mut using = LLVMGetFirstUse(func);
+    while !using.is_null() {
+        let user = LLVMGetUser(using);
+        if LLVMIsAInstruction(user).is_null() ||
+           LLVMGetBasicBlockParent(LLVMGetInstructionParent(user)) != func {
+            return true
+        }
+        using = LLVMGetNextUse(using);
+    }
+    false
+}
+/// Starts replacing `old` with a new function for `prototype` without a
+/// body. The new function takes over the name, so that its body calls
+/// itself, but everything else keeps using `old` until `finish_replacing`.
+/// If `prototype` has different parameters, nothing else is allowed to use `old`.
+fn start_replacing<'a>(old: &'a Function,
+                       prototype: &Prototype,
+                       module: &'a CSemiBox<'a, Module>,
+                       context: &'a CBox<Context>) -> Result<&'a Function, CompileError> {
+    let hidden = CString::new(format!("{}.old", prototype.name)).unwrap();
+    unsafe {
+        LLVMSetValueName(old as *const Function as *mut c_void, hidden.as_ptr());
+    }
+    let func = match generate_prototype(prototype, module, context) {
+        Ok(func) => func,
+        Err(err) => {
+            let name = CString::new(prototype.name.as_str()).unwrap();
+            unsafe {
+                LLVMSetValueName(old as *const Function as *mut c_void, name.as_ptr());
+            }
+            return Err(err)
+        },
+    };
+    let (old_ptr, new) = (old as *const Function as *mut c_void, func as *const Function as *mut c_void);
+    unsafe {
+        if LLVMTypeOf(old_ptr) != LLVMTypeOf(new) && used_elsewhere(old_ptr) {
+            abandon_replacing(old, func, &prototype.name);
+            return Err(CompileError::IncompatibleRedefinition(prototype.name.clone()))
+        }
+    }
+    Ok(func)
+}
+/// Makes the code which called `old` call `new` from now on, and deletes `old`.
+fn finish_replacing(old: &Function, new: &Function) {
+    let (old, new) = (old as *const Function as *mut c_void, new as *const Function as *mut c_void);
+    unsafe {
+        if LLVMTypeOf(old) == LLVMTypeOf(new) {
+            LLVMReplaceAllUsesWith(old, new);
+        }
+        // Deleting the whole function drops the references between the
+        // instructions in its body first, which deleting blocks doesn't do
+        LLVMDeleteFunction(old);
+    }
+}
+/// Deletes `new` and gives `old` its name back, as if `start_replacing` never happened.
+fn abandon_replacing(old: &Function, new: &Function, name: &str) {
+    let name = CString::new(name).unwrap();
+    unsafe {
+        LLVMDeleteFunction(new as *const Function as *mut c_void);
+        LLVMSetValueName(old as *const Function as *mut c_void, name.as_ptr());
+    }
+}
+/// Choices about the code `generate_function_with` generates.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct CodegenOptions {
+    /// Give the function DWARF debug info, which points every instruction
+    /// at the expression it came from, so a debugger can step through it.
+    ///
+    /// This needs kaleidoscope to be built with the `debug-info` feature,
+    /// and so LLVM 11 or newer. Without it asking for debug info is an error.
+    pub debug: bool,
+}
+/// The debug info of a single function. Each function gets a compile unit
+/// of its own, for a file named after the module, since the JIT compiles
+/// every definition into a module of its own anyway.
+///
+/// Dropping this finishes the debug info and stops the builder from
+/// pointing any more instructions at the function.
+#[cfg(feature = "debug-info")]
+struct DebugInfo {
+    di_builder: *mut c_void,
+    builder: *mut c_void,
+    subprogram: *mut c_void,
+}
+#[cfg(feature = "debug-info")]
+impl DebugInfo {
+    fn new(function_ast: &parser::Function,
+           func: &Function,
+           builder: &Builder,
+           module: &Module,
+           context: &CBox<Context>) -> DebugInfo {
+        let prototype = &function_ast.prototype;
+        // Top level expressions don't have a prototype in the source
+        let line = prototype.span.or(function_ast.body.span).map_or(0, |range| range.start.line);
+        let module = module as *const Module as *mut c_void;
+        let name = prototype.name.as_ptr() as *const c_char;
+        unsafe {
+            let di_builder = LLVMCreateDIBuilder(module);
+            let mut len = 0;
+            let file_name = LLVMGetModuleIdentifier(module, &mut len);
+            let file = LLVMDIBuilderCreateFile(di_builder, file_name, len, ptr::null(), 0);
+            let producer = "kaleidoscope";
+            // 1 is LLVMDWARFSourceLanguageC and LLVMDWARFEmissionFull
+            LLVMDIBuilderCreateCompileUnit(di_builder, 1, file, producer.as_ptr() as *const c_char, producer.len(),
+                                           0, ptr::null(), 0, 0, ptr::null(), 0, 1, 0, 0, 0,
+                                           ptr::null(), 0, ptr::null(), 0);
+            // Every argument and return value is a double, and 4 is DW_ATE_float
+            let double = LLVMDIBuilderCreateBasicType(di_builder, "double".as_ptr() as *const c_char, 6, 64, 4, 0);
+            let mut types = vec![double; prototype.arity() + 1];
+            let ty = LLVMDIBuilderCreateSubroutineType(di_builder, file, types.as_mut_ptr(), types.len() as c_uint, 0);
+            let subprogram = LLVMDIBuilderCreateFunction(di_builder, file, name, prototype.name.len(),
+                                                         name, prototype.name.len(), file, line, ty,
+                                                         0, 1, line, 0, 0);
+            LLVMSetSubprogram(func as *const Function as *mut c_void, subprogram);
+            // Without this LLVM throws the debug info away
+            let key = "Debug Info Version";
+            if LLVMGetModuleFlag(module, key.as_ptr() as *const c_char, key.len()).is_null() {
+                let version = (LLVMDebugMetadataVersion() as i32).compile(&context);
+                // 1 is LLVMModuleFlagBehaviorWarning
+                LLVMAddModuleFlag(module, 1, key.as_ptr() as *const c_char, key.len(),
+                                  LLVMValueAsMetadata(version as *const Value as *mut c_void));
+            }
+            DebugInfo {
+                di_builder: di_builder,
+                builder: builder as *const Builder as *mut c_void,
+                subprogram: subprogram,
+            }
+        }
+    }
+}
+#[cfg(feature = "debug-info")]
+impl Drop for DebugInfo {
+    fn drop(&mut self) {
+        unsafe {
+            LLVMSetCurrentDebugLocation2(self.builder, ptr::null_mut());
+            LLVMDIBuilderFinalize(self.di_builder);
+            LLVMDisposeDIBuilder(self.di_builder);
+        }
+    }
+}
 pub fn generate_function<'a>(function_ast: &parser::Function,
                          builder: &'a CSemiBox<'a, Builder>,
                          module: &'a CSemiBox<'a, Module>,
-                         context: &'a CBox<Context>) -> Result<&'a Function, String> {
-    let mut func = module.get_function(&function_ast.prototype.name);
-    let func = if func.is_none() {
-        generate_prototype(&function_ast.prototype, &module, &context)?
-    } else {
-        func.unwrap()
+                         context: &'a CBox<Context>) -> Result<&'a Function, CompileError> {
+    generate_function_with(function_ast, builder, module, context, &CodegenOptions::default())
+}
+/// Like `generate_function`, but with `options` instead of the defaults.
+pub fn generate_function_with<'a>(function_ast: &parser::Function,
+                                  builder: &'a CSemiBox<'a, Builder>,
+                                  module: &'a CSemiBox<'a, Module>,
+                                  context: &'a CBox<Context>,
+                                  options: &CodegenOptions) -> Result<&'a Function, CompileError> {
+    let prototype = &function_ast.prototype;
+    // Whatever was declared or defined before, the new definition wins, but
+    // only once it has been generated without errors
+    let old = module.get_function(&prototype.name);
+    let func = match old {
+        Some(old) => start_replacing(old, prototype, module, context)?,
+        None => generate_prototype(prototype, &module, &context)?,
     };
+    match generate_body(function_ast, func, builder, module, context, options) {
+        Ok(()) => {
+            if let Some(old) = old {
+                finish_replacing(old, func);
+            }
+            Ok(func)
+        },
+        Err(err) => {
+            // The builder is still in the body that is about to go
+            let builder: &Builder = builder;
+            unsafe {
+                LLVMClearInsertionPosition(builder as *const Builder as *mut c_void);
+            }
+            match old {
+                Some(old) => abandon_replacing(old, func, &prototype.name),
+                None => unsafe { LLVMDeleteFunction(func as *const Function as *mut c_void) },
+            }
+            Err(err)
+        },
+    }
+}
+/// Generates the body of `func`, which has just been declared for `function_ast`.
+fn generate_body<'a>(function_ast: &parser::Function,
+                     func: &'a Function,
+                     builder: &'a CSemiBox<'a, Builder>,
+                     module: &'a CSemiBox<'a, Module>,
+                     context: &'a CBox<Context>,
+                     options: &CodegenOptions) -> Result<(), CompileError> {
+    if options.debug && !cfg!(feature = "debug-info") {
+        return Err(CompileError::NoDebugInfo)
+    }
     let block = func.append("entry");
     builder.position_at_end(block);
-    let mut values = HashMap::new();
+    let mut ctx = CodegenCtx::new(builder, module, context, func);
+    #[cfg(feature = "debug-info")]
+    let debug_info = if options.debug {
+        Some(DebugInfo::new(function_ast, func, builder, module, context))
+    } else {
+        None
+    };
+    #[cfg(feature = "debug-info")]
+    if let Some(ref debug_info) = debug_info {
+        ctx.debug_scope = Some(debug_info.subprogram);
+        // Storing the arguments and counting the call's step happen at the prototype
+        ctx.set_debug_location(function_ast.prototype.span.or(function_ast.body.span));
+    }
+    // Arguments get stack slots just like other variables, so they can be assigned to
     for (i, name) in function_ast.prototype.args.iter().enumerate() {
-        values.insert(name, &func[i]);
+        let variable = create_entry_block_alloca(&func, &context);
+        builder.build_store(&func[i], variable);
+        ctx.values.insert(name, variable);
+    }
+    if let Some(step) = module.get_function(STEP_FUNCTION) {
+        let stop_block = func.append("outofsteps");
+        ctx.build_step(&stop_block, step);
+        let body_block = builder.get_insert_block();
+        builder.position_at_end(stop_block);
+        builder.build_ret(0.0f64.compile(&context));
+        builder.position_at_end(body_block);
     }
-    let ret = generate_expression(&function_ast.body, &values,
-                                       &builder, &module, &context)?;
+    let ret = ctx.to_float(ctx.generate(&function_ast.body, true)?);
     builder.build_ret(ret);
-    module.verify().unwrap();
-    Ok(func)
+    // The debug info can only be verified once it is finished
+    #[cfg(feature = "debug-info")]
+    drop(debug_info);
+    module.verify().map_err(|e| CompileError::Verification(e.to_string()))
+}
+/// Generates a `main` function that evaluates each of `exprs` in turn and
+/// prints its value with `printf`, for programs compiled ahead of time.
+///
+/// Any functions the expressions call have to be in `module` already, and
+/// `main` must not be. On errors `module` is left as it was.
+pub fn generate_main<'a>(exprs: &[Box<Expr>],
+                         builder: &'a CSemiBox<'a, Builder>,
+                         module: &'a CSemiBox<'a, Module>,
+                         context: &'a CBox<Context>) -> Result<&'a Function, CompileError> {
+    // add_function would quietly call this one main1 instead
+    if module.get_function("main").is_some() {
+        return Err(CompileError::ConflictingDeclaration(String::from("main")))
+    }
+    // int printf(char *format, ...)
+    let int = Type::get::<i32>(&context);
+    let char_ptr: &Type = PointerType::new(Type::get::<i8>(&context));
+    let (printf, declared_printf) = match module.get_function("printf") {
+        Some(printf) if function_type(printf) == vararg_function_type(int, &[char_ptr]) => (printf, false),
+        // Like every extern, a Kaleidoscope printf returns a double, so it
+        // can't be called like this. It has to be the same C function though.
+        Some(_) => return Err(CompileError::ConflictingDeclaration(String::from("printf"))),
+        None => (add_vararg_function("printf", int, &[char_ptr], module), true),
+    };
+    let main = module.add_function("main", FunctionType::new(int, &[]));
+    let block = main.append("entry");
+    builder.position_at_end(block);
+    let format: &Value = module.add_global_constant("str", "%f\n\0".compile(&context));
+    let result = print_values(exprs, printf, format, main, builder, module, context);
+    if result.is_err() {
+        // Take out everything this added, so the module is as it was
+        let builder: &Builder = builder;
+        let format = format as *const Value as *mut c_void;
+        unsafe {
+            LLVMClearInsertionPosition(builder as *const Builder as *mut c_void);
+            LLVMDeleteFunction(main as *const Function as *mut c_void);
+            if declared_printf {
+                LLVMDeleteFunction(printf as *const Function as *mut c_void);
+            }
+            // The pointer to the string's first character is a constant,
+            // which lives on after main and would keep using the string
+            LLVMReplaceAllUsesWith(format, LLVMGetUndef(LLVMTypeOf(format)));
+            LLVMDeleteGlobal(format);
+        }
+    }
+    result.map(|_| main)
+}
+/// Generates the body of `generate_main`'s `main`, which prints each of
+/// `exprs` with `format`.
+fn print_values<'a>(exprs: &[Box<Expr>],
+                    printf: &'a Function,
+                    format: &'a Value,
+                    main: &'a Function,
+                    builder: &'a CSemiBox<'a, Builder>,
+                    module: &'a CSemiBox<'a, Module>,
+                    context: &'a CBox<Context>) -> Result<(), CompileError> {
+    let ctx = CodegenCtx::new(builder, module, context, main);
+    let zero = 0i32.compile(&context);
+    let format = builder.build_gep(format, &[zero, zero]);
+    for expr in exprs {
+        let value = ctx.generate_float(expr)?;
+        builder.build_call(printf, &[format, value]);
+    }
+    builder.build_ret(0i32.compile(&context));
+    module.verify().map_err(|e| CompileError::Verification(e.to_string()))
+}
+/// Runs LLVM's standard optimization pipeline over `module`.
+///
+/// Level 0 leaves the module alone. Anything higher runs, among others, the
+/// mem2reg, instcombine, reassociate, gvn and simplifycfg passes, with more
+/// aggressive passes being added at each level up to 3. Functions marked
+/// `always_inline` are inlined into their callers first, and tail calls of a
+/// function to itself are turned into loops afterwards, so that tail
+/// recursion doesn't use up the stack.
+pub fn optimize(module: &Module, opt_level: usize) {
+    optimize_with(module, &PassConfig::from_opt_level(opt_level));
+}
+
+/// Which optimizations `optimize_with` runs over a module.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PassConfig {
+    /// Leaves the generated code exactly as it is.
+    None,
+    /// The standard pipeline at level 1.
+    Less,
+    /// The standard pipeline at level 2.
+    Default,
+    /// The standard pipeline at level 3.
+    Aggressive,
+    /// Only the given passes, in order.
+    Passes(Vec<Pass>),
+}
+
+impl PassConfig {
+    /// The preset `optimize` uses for `opt_level`.
+    pub fn from_opt_level(opt_level: usize) -> PassConfig {
+        match opt_level {
+            0 => PassConfig::None,
+            1 => PassConfig::Less,
+            2 => PassConfig::Default,
+            _ => PassConfig::Aggressive,
+        }
+    }
+}
+
+/// A single LLVM pass that can be listed in `PassConfig::Passes`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Pass {
+    AlwaysInline,
+    PromoteMemoryToRegister,
+    InstructionCombining,
+    Reassociate,
+    Gvn,
+    CfgSimplification,
+    AggressiveDce,
+    TailCallElimination,
+}
+
+impl Pass {
+    fn add_fn(self) -> unsafe extern "C" fn(*mut c_void) {
+        match self {
+            Pass::AlwaysInline => LLVMAddAlwaysInlinerPass,
+            Pass::PromoteMemoryToRegister => LLVMAddPromoteMemoryToRegisterPass,
+            Pass::InstructionCombining => LLVMAddInstructionCombiningPass,
+            Pass::Reassociate => LLVMAddReassociatePass,
+            Pass::Gvn => LLVMAddGVNPass,
+            Pass::CfgSimplification => LLVMAddCFGSimplificationPass,
+            Pass::AggressiveDce => LLVMAddAggressiveDCEPass,
+            Pass::TailCallElimination => LLVMAddTailCallEliminationPass,
+        }
+    }
+}
+
+/// Like `optimize`, but with the passes chosen by `config` instead of a level.
+pub fn optimize_with(module: &Module, config: &PassConfig) {
+    let opt_level = match *config {
+        PassConfig::None => return,
+        PassConfig::Less => 1,
+        PassConfig::Default => 2,
+        PassConfig::Aggressive => 3,
+        PassConfig::Passes(ref passes) => {
+            let add_passes: Vec<_> = passes.iter().map(|pass| pass.add_fn()).collect();
+            return run_passes(module, &add_passes);
+        },
+    };
+    run_passes(module, &[LLVMAddAlwaysInlinerPass]);
+    module.optimize(opt_level, 0);
+    run_passes(module, &[LLVMAddTailCallEliminationPass]);
+}
+
+fn run_passes(module: &Module, add_passes: &[unsafe extern "C" fn(*mut c_void)]) {
+    unsafe {
+        let pass_manager = LLVMCreatePassManager();
+        for add_pass in add_passes {
+            add_pass(pass_manager);
+        }
+        LLVMRunPassManager(pass_manager, module as *const Module as *mut c_void);
+        LLVMDisposePassManager(pass_manager);
+    }
+}
+
+/// Returns the human readable LLVM IR for everything compiled into `module`.
+pub fn emit_ir(module: &Module) -> String {
+    format!("{}", module)
 }
 
 
@@ -85,6 +1102,17 @@ pub fn generate_function<'a>(function_ast: &parser::Function,
 mod tests {
     use super::*;
     use parser;
+    fn run_top_level(source: &str) -> f64 {
+        let mut parser = parser::Parser::from_source(source);
+        let ast = parser.parse_top_level_expr().unwrap();
+        let ctx = Context::new();
+        let builder = Builder::new(&ctx);
+        let module = Module::new("test", &ctx);
+        let func = generate_function(&ast, &builder, &module, &ctx).unwrap();
+        let engine = JitEngine::new(&module, JitOptions {opt_level: 0}).unwrap();
+        let res = engine.run_function(&func, &[]);
+        f64::from_generic(&res, &ctx)
+    }
     #[test]
     fn test_codegen() {
         let mut parser = parser::Parser::from_source("def foo(a) a + a");
@@ -105,4 +1133,567 @@ mod tests {
         let func = generate_function(&ast, &builder, &module, &ctx).unwrap();
         module.write_bitcode("test.bitcode").unwrap();
     }
+    #[test]
+    fn test_if_else_codegen() {
+        let mut parser = parser::Parser::from_source("def choose(a) if a then 2 else 3");
+        let ast = parser.parse_definition().unwrap();
+        let ctx = Context::new();
+        let builder = Builder::new(&ctx);
+        let module = Module::new("test", &ctx);
+        generate_function(&ast, &builder, &module, &ctx).unwrap();
+        module.verify().unwrap();
+    }
+    #[test]
+    fn test_for_codegen() {
+        let mut parser = parser::Parser::from_source("def count(n) for i = 0, i < n in i + 1");
+        let ast = parser.parse_definition().unwrap();
+        let ctx = Context::new();
+        let builder = Builder::new(&ctx);
+        let module = Module::new("test", &ctx);
+        generate_function(&ast, &builder, &module, &ctx).unwrap();
+        module.verify().unwrap();
+        let mut parser = parser::Parser::from_source("def count(n) for i = 0, i < n, 2 in i");
+        let ast = parser.parse_definition().unwrap();
+        let module = Module::new("test", &ctx);
+        generate_function(&ast, &builder, &module, &ctx).unwrap();
+        module.verify().unwrap();
+    }
+    #[test]
+    fn test_comparison_codegen() {
+        let ctx = Context::new();
+        let builder = Builder::new(&ctx);
+        for source in &["def cmp(a b) a <= b", "def cmp(a b) a >= b",
+                        "def cmp(a b) a == b", "def cmp(a b) a != b"] {
+            let mut parser = parser::Parser::from_source(source);
+            let ast = parser.parse_definition().unwrap();
+            let module = Module::new("test", &ctx);
+            generate_function(&ast, &builder, &module, &ctx).unwrap();
+            module.verify().unwrap();
+        }
+    }
+    #[test]
+    fn test_comparison_results() {
+        assert_eq!(run_top_level("(2 < 3)"), 1.0);
+        assert_eq!(run_top_level("(3 < 2)"), 0.0);
+        assert_eq!(run_top_level("if 2 < 3 then 7 else 9"), 7.0);
+        let cases = [("2 < 3", 1.0), ("3 < 3", 0.0),
+                     ("3 > 2", 1.0), ("3 > 3", 0.0),
+                     ("3 <= 3", 1.0), ("4 <= 3", 0.0),
+                     ("3 >= 3", 1.0), ("2 >= 3", 0.0),
+                     ("3 == 3", 1.0), ("2 == 3", 0.0),
+                     ("2 != 3", 1.0), ("3 != 3", 0.0),
+                     ("2i < 3i", 1.0), ("3i != 3i", 0.0)];
+        for &(source, expected) in &cases {
+            assert_eq!(run_top_level(source), expected, "{}", source);
+        }
+    }
+    #[test]
+    fn test_arithmetic_on_comparisons() {
+        // Comparisons are exactly 0.0 or 1.0, so adding them counts how many are true
+        assert_eq!(run_top_level("(1 < 2) + (3 < 4)"), 2.0);
+        assert_eq!(run_top_level("(1 < 2) + (4 < 3) + (5 == 5)"), 2.0);
+        assert_eq!(run_top_level("(2 > 1) * 10 + (1 > 2) * 100"), 10.0);
+        assert_eq!(run_top_level("1 - (1i < 2i)"), 0.0);
+        assert_eq!(run_top_level("-(3 != 4)"), -1.0);
+        let ctx = Context::new();
+        let builder = Builder::new(&ctx);
+        let module = Module::new("test", &ctx);
+        let ast = parser::Parser::from_source("def count(a b c d) (a < b) + (c < d)").parse_definition().unwrap();
+        let func = generate_function(&ast, &builder, &module, &ctx).unwrap();
+        let engine = JitEngine::new(&module, JitOptions {opt_level: 0}).unwrap();
+        for &(args, expected) in &[([1.0, 2.0, 3.0, 4.0], 2.0), ([2.0, 1.0, 3.0, 4.0], 1.0), ([2.0, 1.0, 4.0, 3.0], 0.0)] {
+            let args: Vec<GenericValue> = args.iter().map(|arg| arg.to_generic(&ctx)).collect();
+            let args: Vec<&GenericValue> = args.iter().collect();
+            let res = engine.run_function(&func, &args);
+            assert_eq!(f64::from_generic(&res, &ctx), expected);
+        }
+    }
+    #[test]
+    fn test_normalized_comparisons() {
+        for source in &["3 > 2", "2 > 3", "3 >= 3", "2 >= 3", "(1 > 0) + (2 >= 3) * 10"] {
+            let ast = parser::Parser::from_source(source).parse_top_level_expr().unwrap();
+            let ast = parser::Function::new(ast.prototype, parser::normalize_comparisons(ast.body));
+            assert!(!parser::pretty(&ast.body).contains('>'));
+            let ctx = Context::new();
+            let builder = Builder::new(&ctx);
+            let module = Module::new("test", &ctx);
+            let func = generate_function(&ast, &builder, &module, &ctx).unwrap();
+            let engine = JitEngine::new(&module, JitOptions {opt_level: 0}).unwrap();
+            let res = engine.run_function(&func, &[]);
+            assert_eq!(f64::from_generic(&res, &ctx), run_top_level(source), "{}", source);
+        }
+    }
+    #[test]
+    fn test_sequence_operator() {
+        assert_eq!(run_top_level("1 : 2"), 2.0);
+        assert_eq!(run_top_level("1 : 2 + 3"), 5.0);
+        assert_eq!(run_top_level("var x = 1 in x = x + 1 : x = x * 10 : x"), 20.0);
+        assert_eq!(run_top_level("(1 ? 2 : 3) : 4i"), 4.0);
+    }
+    #[test]
+    fn test_division() {
+        assert_eq!(run_top_level("10 / 4"), 2.5);
+    }
+    #[test]
+    fn test_modulo() {
+        assert_eq!(run_top_level("10 % 3"), 1.0);
+        assert_eq!(run_top_level("5.5 % 2.0"), 1.5);
+        assert_eq!(run_top_level("-7 % 3"), -1.0);
+        assert_eq!(run_top_level("7i % 3i"), 1.0);
+    }
+    #[test]
+    fn test_emit_ir() {
+        let mut parser = parser::Parser::from_source("def foo(a) a + a");
+        let ast = parser.parse_definition().unwrap();
+        let ctx = Context::new();
+        let builder = Builder::new(&ctx);
+        let module = Module::new("test", &ctx);
+        generate_function(&ast, &builder, &module, &ctx).unwrap();
+        let ir = emit_ir(&module);
+        assert!(ir.contains("fadd"));
+        assert!(ir.contains("@foo"));
+    }
+    #[test]
+    fn test_unary_minus() {
+        assert_eq!(run_top_level("-5"), -5.0);
+        assert_eq!(run_top_level("3 - -2"), 5.0);
+        assert_eq!(run_top_level("--4"), 4.0);
+        assert_eq!(run_top_level("-(1 + 2) * 2"), -6.0);
+    }
+    #[test]
+    fn test_user_defined_binary_operator() {
+        let mut parser = parser::Parser::from_source("
+            def binary| 5 (a b) if a then 1 else if b then 1 else 0
+            0 | 0 | 3 < 4");
+        let op_def = parser.parse_definition().unwrap();
+        let expr = parser.parse_top_level_expr().unwrap();
+        let ctx = Context::new();
+        let builder = Builder::new(&ctx);
+        let module = Module::new("test", &ctx);
+        generate_function(&op_def, &builder, &module, &ctx).unwrap();
+        let func = generate_function(&expr, &builder, &module, &ctx).unwrap();
+        let engine = JitEngine::new(&module, JitOptions {opt_level: 0}).unwrap();
+        let res = engine.run_function(&func, &[]);
+        assert_eq!(f64::from_generic(&res, &ctx), 1.0);
+    }
+    #[test]
+    fn test_mutable_variables() {
+        assert_eq!(run_top_level("var x = 4 in x = x + 1"), 5.0);
+        assert_eq!(run_top_level("var a = 1, b = a + 1, c in a + b + c"), 3.0);
+        assert_eq!(run_top_level("var x = 1 in (var x = 10 in x) + x"), 11.0);
+        // The condition is checked with the value the body saw, so the body runs for i = 0 to 4
+        assert_eq!(run_top_level("var total = 0 in (for i = 0, i < 4 in total = total + i) + total"), 10.0);
+    }
+    #[test]
+    fn test_invalid_assignment() {
+        let mut parser = parser::Parser::from_source("def foo(a) 1 = a");
+        let ast = parser.parse_definition().unwrap();
+        let ctx = Context::new();
+        let builder = Builder::new(&ctx);
+        let module = Module::new("test", &ctx);
+        assert_eq!(generate_function(&ast, &builder, &module, &ctx).err(),
+                   Some(CompileError::InvalidAssignment));
+    }
+    #[test]
+    fn test_optimize() {
+        let mut parser = parser::Parser::from_source("def foo(x) var a = 1 in (a + 2) + x");
+        let ast = parser.parse_definition().unwrap();
+        let ctx = Context::new();
+        let builder = Builder::new(&ctx);
+        let module = Module::new("test", &ctx);
+        generate_function(&ast, &builder, &module, &ctx).unwrap();
+        let before = emit_ir(&module);
+        optimize(&module, 0);
+        assert_eq!(emit_ir(&module), before);
+        assert!(before.contains("alloca"));
+        assert!(!before.contains("3.000000e+00"));
+        optimize(&module, 2);
+        let after = emit_ir(&module);
+        assert!(!after.contains("alloca"));
+        assert!(after.contains("3.000000e+00"));
+    }
+    #[test]
+    fn test_redefinition() {
+        let ctx = Context::new();
+        let builder = Builder::new(&ctx);
+        let module = Module::new("test", &ctx);
+        // The old body has several blocks that refer to each other, and g calls it
+        let mut parser = parser::Parser::from_source("
+            def f(x) var s = 0 in (for i = 0, i < x in s = s + i); s
+            def g(x) f(x) * 10
+            def f(x) x + 2
+            f(1) + g(1)");
+        for _ in 0..3 {
+            generate_function(&parser.parse_definition().unwrap(), &builder, &module, &ctx).unwrap();
+        }
+        let expr = parser.parse_top_level_expr().unwrap();
+        let func = generate_function(&expr, &builder, &module, &ctx).unwrap();
+        module.verify().unwrap();
+        let engine = JitEngine::new(&module, JitOptions {opt_level: 0}).unwrap();
+        let res = engine.run_function(&func, &[]);
+        assert_eq!(f64::from_generic(&res, &ctx), 33.0);
+    }
+    #[test]
+    fn test_redefinition_with_other_params() {
+        let ctx = Context::new();
+        let builder = Builder::new(&ctx);
+        let module = Module::new("test", &ctx);
+        let mut parser = parser::Parser::from_source("
+            def f(x) if x < 1 then 0 else f(x - 1)
+            def f(x y) x + y
+            def g(x) f(x, 1)
+            def f(x) x
+            def g(x y z) x
+            def f(x) x");
+        let defs: Vec<_> = (0..6).map(|_| parser.parse_definition().unwrap()).collect();
+        let define = |i: usize| generate_function(&defs[i], &builder, &module, &ctx);
+        // Only the first f calls itself, so it can change
+        define(0).unwrap();
+        assert_eq!(define(1).unwrap().get_signature().num_params(), 2);
+        // Now g calls f, which keeps f's parameters fixed
+        define(2).unwrap();
+        assert_eq!(define(3).err(), Some(CompileError::IncompatibleRedefinition(String::from("f"))));
+        module.verify().unwrap();
+        assert_eq!(module.get_function("f").unwrap().get_signature().num_params(), 2);
+        // Nothing calls g, and once it's gone nothing calls f either
+        assert_eq!(define(4).unwrap().get_signature().num_params(), 3);
+        assert_eq!(define(5).unwrap().get_signature().num_params(), 1);
+        module.verify().unwrap();
+    }
+    #[test]
+    fn test_assert_abort() {
+        let ctx = Context::new();
+        let builder = Builder::new(&ctx);
+        let module = Module::new("test", &ctx);
+        let mut parser = parser::Parser::from_source("def f(x) assert(x) def g(x) assert(x - 1) def abort() 0 def h(x) assert(x)");
+        for _ in 0..2 {
+            generate_function(&parser.parse_definition().unwrap(), &builder, &module, &ctx).unwrap();
+        }
+        let ir = emit_ir(&module);
+        assert_eq!(ir.matches("declare void @abort()").count(), 1, "{}", ir);
+        assert!(ir.contains("noreturn"));
+        assert_eq!(ir.matches("call void @abort()\n  unreachable").count(), 2, "{}", ir);
+        // An abort from the program can't be called in its place
+        let module = Module::new("test", &ctx);
+        generate_function(&parser.parse_definition().unwrap(), &builder, &module, &ctx).unwrap();
+        assert_eq!(generate_function(&parser.parse_definition().unwrap(), &builder, &module, &ctx).err(),
+                   Some(CompileError::ConflictingDeclaration(String::from("abort"))));
+        module.verify().unwrap();
+    }
+    #[test]
+    fn test_failed_redefinition() {
+        let ctx = Context::new();
+        let builder = Builder::new(&ctx);
+        let module = Module::new("test", &ctx);
+        let mut parser = parser::Parser::from_source("
+            def f(x) x + 1
+            def g(x) f(x) * 10
+            def f(x) f(x - 1) + y
+            def f(x y) x + y
+            def h(x) z
+            g(1)");
+        for _ in 0..2 {
+            generate_function(&parser.parse_definition().unwrap(), &builder, &module, &ctx).unwrap();
+        }
+        // Definitions that don't compile leave the f that g calls where it was
+        let unknown = parser.parse_definition().unwrap();
+        assert_eq!(generate_function(&unknown, &builder, &module, &ctx).err(),
+                   Some(CompileError::UnknownVariable(String::from("y"))));
+        let incompatible = parser.parse_definition().unwrap();
+        assert_eq!(generate_function(&incompatible, &builder, &module, &ctx).err(),
+                   Some(CompileError::IncompatibleRedefinition(String::from("f"))));
+        let h = parser.parse_definition().unwrap();
+        assert!(generate_function(&h, &builder, &module, &ctx).is_err());
+        assert!(module.get_function("h").is_none());
+        let expr = parser.parse_top_level_expr().unwrap();
+        let func = generate_function(&expr, &builder, &module, &ctx).unwrap();
+        module.verify().unwrap();
+        let ir = emit_ir(&module);
+        assert!(!ir.contains("f.old"), "{}", ir);
+        assert_eq!(ir.matches("define double @f(").count(), 1);
+        let engine = JitEngine::new(&module, JitOptions {opt_level: 0}).unwrap();
+        let res = engine.run_function(&func, &[]);
+        assert_eq!(f64::from_generic(&res, &ctx), 20.0);
+    }
+    #[test]
+    fn test_sequence() {
+        assert_eq!(run_top_level("1; 2; 3"), 3.0);
+        assert_eq!(run_top_level("var x = 1 in x = x * 5; x + 1"), 6.0);
+    }
+    #[test]
+    fn test_integer_arithmetic() {
+        // Integer division truncates
+        assert_eq!(run_top_level("7i / 2i"), 3.0);
+        assert_eq!(run_top_level("2i * 3i - 10i"), -4.0);
+        assert_eq!(run_top_level("-5i + 1i"), -4.0);
+        // Mixing integers and doubles promotes to double
+        assert_eq!(run_top_level("1i + 2.0"), 3.0);
+        assert_eq!(run_top_level("7i / 2"), 3.5);
+        assert_eq!(run_top_level("2i < 3i"), 1.0);
+        assert_eq!(run_top_level("var x = 3i in x / 2i"), 1.5);
+    }
+    #[test]
+    fn test_while_codegen() {
+        assert_eq!(run_top_level("var n = 5, steps in (while n in (n = n - 1; steps = steps + 1)); n + steps"), 5.0);
+        // The body doesn't run at all if the condition starts out false
+        assert_eq!(run_top_level("var n = 0 in (while n in n = 10); n"), 0.0);
+    }
+    #[test]
+    fn test_string_codegen() {
+        let ctx = Context::new();
+        let builder = Builder::new(&ctx);
+        let module = Module::new("test", &ctx);
+        let proto = Prototype::new(String::from("greeting"), vec![]);
+        let func = generate_prototype(&proto, &module, &ctx).unwrap();
+        builder.position_at_end(func.append("entry"));
+        let string = Expr::new(ExprKind::Str(String::from("hi\n")));
+        CodegenCtx::new(&builder, &module, &ctx, func).generate_expression(&string).unwrap();
+        assert!(emit_ir(&module).contains(r#"c"hi\0A\00""#));
+    }
+    #[test]
+    fn test_codegen_ctx() {
+        // The labels and instructions of name's body, leaving out the types,
+        // value names and alignments, which depend on the LLVM version
+        fn body_shape(ir: &str, name: &str) -> String {
+            let define = format!("@{}(", name);
+            ir.lines()
+                .skip_while(|line| !(line.starts_with("define") && line.contains(&define)))
+                .skip(1)
+                .take_while(|line| *line != "}")
+                .filter_map(|line| {
+                    let words: Vec<&str> = line.split_whitespace().collect();
+                    let words = if words.len() > 2 && words[1] == "=" { &words[2..] } else { &words[..] };
+                    match words.first() {
+                        Some(&"call") => {
+                            let callee = words.iter().find(|word| word.starts_with('@')).unwrap();
+                            Some(format!("call {}", callee.split('(').next().unwrap()))
+                        },
+                        Some(word) => Some(word.to_string()),
+                        None => None,
+                    }
+                })
+                .collect::<Vec<_>>()
+                .join("\n")
+        }
+        // What codegen generated for this before the context struct existed
+        let expected = "entry:\n\
+                        alloca\nalloca\nalloca\nstore\nstore\n\
+                        load\nfmul\nload\nfadd\nstore\n\
+                        load\nload\nload\nfsub\ncall @g\nfsub\nstore\n\
+                        load\nload\nfmul\nret";
+        let mut parser = parser::Parser::from_source("extern g(a b)
+                                                      def f(x y) var z = x * 2 + y in z = z - g(z, -x) : z * z");
+        let ctx = Context::new();
+        let builder = Builder::new(&ctx);
+        let module = Module::new("test", &ctx);
+        generate_prototype(&parser.parse_extern().unwrap(), &module, &ctx).unwrap();
+        generate_function(&parser.parse_definition().unwrap(), &builder, &module, &ctx).unwrap();
+        assert_eq!(body_shape(&emit_ir(&module), "f"), expected);
+    }
+    #[test]
+    fn test_logical_operators() {
+        let cases = [("0 && 0", 0.0), ("0 && 1", 0.0), ("1 && 0", 0.0), ("1 && 1", 1.0),
+                     ("0 || 0", 0.0), ("0 || 1", 1.0), ("1 || 0", 1.0), ("1 || 1", 1.0),
+                     // Any nonzero value is true, but the result is always 0 or 1
+                     ("2 && -3", 1.0), ("0.5 || 0", 1.0)];
+        for &(source, expected) in &cases {
+            assert_eq!(run_top_level(source), expected, "{}", source);
+        }
+        // The assignment on the right only happens when it decides the result
+        assert_eq!(run_top_level("var x = 0 in (0 && (x = 1)); x"), 0.0);
+        assert_eq!(run_top_level("var x = 0 in (1 && (x = 1)); x"), 1.0);
+        assert_eq!(run_top_level("var x = 0 in (1 || (x = 1)); x"), 0.0);
+        assert_eq!(run_top_level("var x = 0 in (0 || (x = 1)); x"), 1.0);
+    }
+    #[test]
+    fn test_ternary() {
+        assert_eq!(run_top_level("1 < 2 ? 10 : 20"), 10.0);
+        assert_eq!(run_top_level("var x = 3 in x > 5 ? 1 : x > 2 ? 2 : 3"), 2.0);
+    }
+    #[test]
+    fn test_always_inline() {
+        let ctx = Context::new();
+        let builder = Builder::new(&ctx);
+        for &inline in &[true, false] {
+            let module = Module::new("test", &ctx);
+            let mut tiny = parser::Parser::from_source("def tiny(x) x + 1").parse_definition().unwrap();
+            tiny.prototype.always_inline = inline;
+            generate_function(&tiny, &builder, &module, &ctx).unwrap();
+            let caller = parser::Parser::from_source("def caller(y) tiny(y) * 2").parse_definition().unwrap();
+            generate_function(&caller, &builder, &module, &ctx).unwrap();
+            optimize(&module, 1);
+            assert_eq!(emit_ir(&module).contains("call double @tiny"), !inline);
+        }
+    }
+    #[test]
+    fn test_verification_error() {
+        // Strings can't be returned, since functions always return doubles
+        let mut parser = parser::Parser::from_source("def f() \"oops\"");
+        let ast = parser.parse_definition().unwrap();
+        let ctx = Context::new();
+        let builder = Builder::new(&ctx);
+        let module = Module::new("test", &ctx);
+        match generate_function(&ast, &builder, &module, &ctx) {
+            Err(CompileError::Verification(_)) => (),
+            other => panic!("expected a verification error, got {:?}", other.map(|_| ())),
+        }
+    }
+    #[test]
+    fn test_intrinsics() {
+        assert_eq!(run_top_level("sqrt(16)"), 4.0);
+        assert_eq!(run_top_level("sin(0) + cos(0) + sqrt(4)"), 3.0);
+        // Calling one twice reuses the declaration
+        assert_eq!(run_top_level("sqrt(sqrt(16))"), 2.0);
+        // Arguments can contain control flow
+        assert_eq!(run_top_level("sqrt(if 1 then 9 else 4)"), 3.0);
+        // Validation has to agree with codegen about them
+        for name in &["sin", "cos", "sqrt"] {
+            assert!(intrinsic(name).is_some());
+            assert_eq!(parser::builtin_arity(name), Some(1));
+        }
+    }
+    #[test]
+    fn test_user_functions_beat_intrinsics() {
+        let ctx = Context::new();
+        let builder = Builder::new(&ctx);
+        let module = Module::new("test", &ctx);
+        let sqrt = parser::Parser::from_source("def sqrt(x) x + 1").parse_definition().unwrap();
+        generate_function(&sqrt, &builder, &module, &ctx).unwrap();
+        let ast = parser::Parser::from_source("sqrt(16)").parse_top_level_expr().unwrap();
+        let func = generate_function(&ast, &builder, &module, &ctx).unwrap();
+        let engine = JitEngine::new(&module, JitOptions {opt_level: 0}).unwrap();
+        let res = engine.run_function(&func, &[]);
+        assert_eq!(f64::from_generic(&res, &ctx), 17.0);
+    }
+    #[test]
+    fn test_max_min() {
+        assert_eq!(run_top_level("max(3, 7)"), 7.0);
+        assert_eq!(run_top_level("min(3, 7)"), 3.0);
+        assert_eq!(run_top_level("max(min(1, 2), 0i) + min(-1, -2)"), -1.0);
+        let ctx = Context::new();
+        let builder = Builder::new(&ctx);
+        let module = Module::new("test", &ctx);
+        // A user-defined max wins over the builtin
+        let max = parser::Parser::from_source("def max(a b) a").parse_definition().unwrap();
+        generate_function(&max, &builder, &module, &ctx).unwrap();
+        let ast = parser::Parser::from_source("max(3, 7)").parse_top_level_expr().unwrap();
+        let func = generate_function(&ast, &builder, &module, &ctx).unwrap();
+        let engine = JitEngine::new(&module, JitOptions {opt_level: 0}).unwrap();
+        let res = engine.run_function(&func, &[]);
+        assert_eq!(f64::from_generic(&res, &ctx), 3.0);
+    }
+    #[test]
+    fn test_tail_calls() {
+        let ctx = Context::new();
+        let builder = Builder::new(&ctx);
+        let module = Module::new("test", &ctx);
+        let source = "def sum(n total) if n < 1 then total else (var m = n - 1 in sum(m, total + n))
+                      def count(n) if n < 1 then 0 else 1 + count(n - 1)";
+        let mut parser = parser::Parser::from_source(source);
+        generate_function(&parser.parse_definition().unwrap(), &builder, &module, &ctx).unwrap();
+        generate_function(&parser.parse_definition().unwrap(), &builder, &module, &ctx).unwrap();
+        let ir = emit_ir(&module);
+        assert!(ir.contains("tail call double @sum"));
+        // The result of count's recursive call still needs adding to
+        assert!(ir.contains("call double @count"));
+        assert!(!ir.contains("tail call double @count"));
+    }
+    #[test]
+    fn test_pass_config() {
+        fn optimized_ir(source: &str, config: PassConfig) -> String {
+            let ctx = Context::new();
+            let builder = Builder::new(&ctx);
+            let module = Module::new("test", &ctx);
+            let mut parser = parser::Parser::from_source(source);
+            generate_function(&parser.parse_definition().unwrap(), &builder, &module, &ctx).unwrap();
+            optimize_with(&module, &config);
+            emit_ir(&module)
+        }
+        let folded = "def f() var a = 1 in a + 2";
+        let ir = optimized_ir(folded, PassConfig::None);
+        assert!(ir.contains("alloca"));
+        assert!(ir.contains("fadd"));
+        let ir = optimized_ir(folded, PassConfig::Default);
+        assert!(!ir.contains("fadd"));
+        assert!(ir.contains("ret double 3.000000e+00"));
+        // x * 2 is never used
+        let dead = "def g(x) x * 2 : x";
+        assert!(optimized_ir(dead, PassConfig::None).contains("fmul"));
+        assert!(!optimized_ir(dead, PassConfig::Aggressive).contains("fmul"));
+        let ir = optimized_ir(dead, PassConfig::Passes(vec![Pass::PromoteMemoryToRegister]));
+        assert!(!ir.contains("alloca"));
+        assert!(ir.contains("fmul"));
+        let ir = optimized_ir(dead, PassConfig::Passes(vec![Pass::PromoteMemoryToRegister, Pass::AggressiveDce]));
+        assert!(!ir.contains("fmul"));
+    }
+    #[test]
+    fn test_vararg_extern() {
+        let ctx = Context::new();
+        let builder = Builder::new(&ctx);
+        let module = Module::new("test", &ctx);
+        let mut parser = parser::Parser::from_source("extern sum(n ...) extern fixed(n) def f(x) sum(1) + sum(x, 2, \"three\")");
+        let sum = parser.parse_extern().unwrap();
+        let fixed = parser.parse_extern().unwrap();
+        let func = parser.parse_definition().unwrap();
+        generate_prototype(&sum, &module, &ctx).unwrap();
+        generate_prototype(&fixed, &module, &ctx).unwrap();
+        generate_function(&func, &builder, &module, &ctx).unwrap();
+        let ir = emit_ir(&module);
+        assert!(ir.contains("declare double @sum(double, ...)"));
+        assert!(ir.contains("declare double @fixed(double)"));
+        // Only varargs functions take extra arguments
+        let call = parser::Parser::from_source("fixed(1, 2)").parse_top_level_expr().unwrap();
+        assert_eq!(generate_function(&call, &builder, &module, &ctx).err(), Some(CompileError::ArityMismatch {
+            name: String::from("fixed"),
+            expected: 1,
+            found: 2,
+        }));
+    }
+    #[test]
+    fn test_exponent() {
+        assert_eq!(run_top_level("2 ^ 3 ^ 2"), 512.0);
+        assert_eq!(run_top_level("2 ^ 10"), 1024.0);
+        assert_eq!(run_top_level("2i ^ 3i + 4 ^ 0.5"), 10.0);
+    }
+    #[test]
+    #[cfg(feature = "debug-info")]
+    fn test_debug_info() {
+        let ctx = Context::new();
+        let builder = Builder::new(&ctx);
+        let module = Module::new("test.ks", &ctx);
+        let mut parser = parser::Parser::from_source("extern g(a)\ndef f(x)\n  x * 2 + g(x)\ndef h(y) y");
+        generate_prototype(&parser.parse_extern().unwrap(), &module, &ctx).unwrap();
+        let options = CodegenOptions {debug: true};
+        generate_function_with(&parser.parse_definition().unwrap(), &builder, &module, &ctx, &options).unwrap();
+        // Without the option the builder doesn't attach anything
+        generate_function(&parser.parse_definition().unwrap(), &builder, &module, &ctx).unwrap();
+        let ir = emit_ir(&module);
+        assert!(ir.contains("!llvm.dbg.cu = !{"));
+        assert!(ir.contains(r#"!DIFile(filename: "test.ks""#));
+        let subprogram = ir.lines().find(|line| line.contains(r#"!DISubprogram(name: "f""#)).unwrap();
+        assert!(subprogram.contains("line: 2,"), "{}", subprogram);
+        // Every instruction but the allocas, which come from their own builder
+        let f: Vec<&str> = ir.lines().skip_while(|line| !line.starts_with("define double @f(")).take_while(|line| *line != "}").collect();
+        assert!(f[0].contains("!dbg"));
+        for line in f.iter().filter(|line| line.starts_with("  ") && !line.contains("alloca")) {
+            assert!(line.contains("!dbg"), "{}", line);
+        }
+        // The call to g is where it was in the source
+        assert!(ir.contains("!DILocation(line: 3, column: 11"));
+        let h: Vec<&str> = ir.lines().skip_while(|line| !line.starts_with("define double @h(")).take_while(|line| *line != "}").collect();
+        assert!(!h.is_empty());
+        assert!(h.iter().all(|line| !line.contains("!dbg")), "{:?}", h);
+    }
+    #[test]
+    #[cfg(not(feature = "debug-info"))]
+    fn test_no_debug_info() {
+        let ctx = Context::new();
+        let builder = Builder::new(&ctx);
+        let module = Module::new("test.ks", &ctx);
+        let function = parser::Parser::from_source("def f(x) x").parse_definition().unwrap();
+        let options = CodegenOptions {debug: true};
+        assert_eq!(generate_function_with(&function, &builder, &module, &ctx, &options).unwrap_err(),
+                   CompileError::NoDebugInfo);
+        assert!(module.get_function("f").is_none());
+    }
 }
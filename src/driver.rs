@@ -0,0 +1,140 @@
+//! This module contains batch mode, which runs a whole source file at once.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use llvm::*;
+use parser;
+use parser::Item;
+use codegen;
+use jit;
+use error::CompileError;
+
+/// Runs the Kaleidoscope program in the file at `path`, printing the value
+/// of every top-level expression.
+pub fn compile_file(path: &Path, opt_level: usize) -> Result<(), CompileError> {
+    compile_source(&read_source(path)?, opt_level)
+}
+
+/// Reads the Kaleidoscope program in the file at `path`.
+pub fn read_source(path: &Path) -> Result<String, CompileError> {
+    let mut source = String::new();
+    File::open(path)
+        .and_then(|mut file| file.read_to_string(&mut source))
+        .map_err(|e| CompileError::ReadFile(format!("{}: {}", path.display(), e)))?;
+    Ok(source)
+}
+
+/// Like `compile_file`, but with the program already read. Errors can be
+/// shown with the line they are on using `CompileError::render`.
+pub fn compile_source(source: &str, opt_level: usize) -> Result<(), CompileError> {
+    for value in run_program(source, opt_level)? {
+        println!("{}", value);
+    }
+    Ok(())
+}
+
+/// Compiles all of `source` into `module`, returning the names given to its
+/// top-level expressions in order.
+///
+/// Unlike in the REPL, every prototype is declared before any body is
+/// compiled, so functions can call functions that are defined further down.
+pub fn compile_program<'a>(source: &str,
+                           module: &'a CSemiBox<'a, Module>,
+                           context: &'a CBox<Context>) -> Result<Vec<String>, CompileError> {
+    let items = parser::Parser::from_source(source).parse_program()?;
+    let builder = Builder::new(context);
+    let mut known = HashMap::new();
+    for item in &items {
+        let proto = match *item {
+            Item::Definition(ref func) => &func.prototype,
+            Item::Extern(ref proto) => proto,
+            Item::TopLevel(_) => continue,
+        };
+        // A function defined twice is only declared once, or LLVM would
+        // call the second declaration f1
+        if module.get_function(&proto.name).is_none() {
+            codegen::generate_prototype(proto, &module, &context)?;
+        }
+        known.insert(proto.name.clone(), proto.clone());
+    }
+    let mut top_level = Vec::new();
+    for item in items {
+        match item {
+            Item::Definition(func) => {
+                parser::validate(&func, &known)?;
+                codegen::generate_function(&func, &builder, &module, &context)?;
+            },
+            Item::Extern(_) => (),
+            Item::TopLevel(mut func) => {
+                // Each expression needs its own name now that they share a module
+                func.prototype.name = format!("__top_level_expr{}", top_level.len());
+                parser::validate(&func, &known)?;
+                codegen::generate_function(&func, &builder, &module, &context)?;
+                top_level.push(func.prototype.name);
+            },
+        }
+    }
+    Ok(top_level)
+}
+
+/// Compiles all of `source` into one module and runs its top-level
+/// expressions in order, returning their values.
+pub fn run_program(source: &str, opt_level: usize) -> Result<Vec<f64>, CompileError> {
+    let context = Context::new();
+    let module = Module::new("program", &context);
+    let top_level = compile_program(source, &module, &context)?;
+    codegen::optimize(&module, opt_level);
+    let engine = JitEngine::new(&module, JitOptions {
+        opt_level: opt_level,
+    }).map_err(|e| CompileError::Jit(e.to_string()))?;
+    for &name in &["putchard", "printd"] {
+        if let (Some(func), Some(addr)) = (module.get_function(name), jit::host_function(name)) {
+            unsafe {
+                engine.add_global_mapping(func, addr);
+            }
+        }
+    }
+    Ok(top_level.iter().map(|name| {
+        let func = module.get_function(name).unwrap();
+        let res = engine.run_function(func, &[]);
+        f64::from_generic(&res, &context)
+    }).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_compile_file() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("examples/fib.k");
+        assert_eq!(compile_file(&path, 0), Ok(()));
+        let mut source = String::new();
+        File::open(&path).unwrap().read_to_string(&mut source).unwrap();
+        assert_eq!(run_program(&source, 2), Ok(vec![0.0, 55.0]));
+        match compile_file(Path::new("no/such/file.k"), 0) {
+            Err(CompileError::ReadFile(_)) => (),
+            other => panic!("expected a ReadFile error, got {:?}", other),
+        }
+        let source = "def f(x)\n  x + )";
+        let err = compile_source(source, 0).unwrap_err();
+        assert!(err.render(source).ends_with("\n  x + )\n      ^"), "{}", err.render(source));
+    }
+    #[test]
+    fn test_functions_can_be_used_before_their_definition() {
+        assert_eq!(run_program("def a(x) b(x) * 2  a(3)  def b(x) x + 1", 0), Ok(vec![8.0]));
+        assert_eq!(run_program("def a(x) c(x)", 0), Err(CompileError::UnknownFunction(String::from("c"))));
+    }
+    #[test]
+    fn test_repeated_definition() {
+        let source = "def f(x) x + 1  extern f(x)  def f(x) x * 10  f(2)";
+        let context = Context::new();
+        let module = Module::new("test", &context);
+        compile_program(source, &module, &context).unwrap();
+        let ir = codegen::emit_ir(&module);
+        assert_eq!(ir.matches("define double @f(").count(), 1, "{}", ir);
+        assert!(!ir.contains("@f1"), "{}", ir);
+        assert_eq!(run_program(source, 0), Ok(vec![20.0]));
+    }
+}
@@ -0,0 +1,215 @@
+//! This module contains the error type shared by the parser and codegen.
+
+use std::error::Error;
+use std::fmt;
+use lexer::{Token, Span, LexError, snippet};
+
+/// All the ways compiling Kaleidoscope can fail.
+///
+/// The `Display` output is the human readable message printed by the REPL.
+#[derive(Debug, PartialEq, Clone)]
+pub enum CompileError {
+    /// The lexer couldn't make sense of part of the source.
+    Lex(LexError, Span),
+    /// The parser found a token it didn't expect.
+    /// `expected` describes what it was looking for instead.
+    UnexpectedToken {
+        expected: String,
+        found: Token,
+        span: Span,
+    },
+    /// The parser needed the start of an expression but found something else.
+    ExpectedExpression(Token, Span),
+    /// The input ended in the middle of an item. The span is just past the last token.
+    UnexpectedEof(Span),
+    UnknownVariable(String),
+    UnknownFunction(String),
+    /// A function was called with the wrong number of arguments.
+    ArityMismatch {
+        name: String,
+        expected: usize,
+        found: usize,
+    },
+    InvalidOperator(String),
+    /// A function was redefined with different parameters, but code
+    /// compiled earlier still calls it with the old ones.
+    IncompatibleRedefinition(String),
+    /// Codegen needs to declare a C function itself, but the program
+    /// already declared it with a different type.
+    ConflictingDeclaration(String),
+    /// A prototype lists the same parameter more than once.
+    DuplicateParam {
+        name: String,
+        param: String,
+        span: Span,
+    },
+    /// Something other than a variable is on the left of an `=`.
+    InvalidAssignment,
+    /// LLVM rejected the generated code. This is a bug in codegen.
+    Verification(String),
+    /// LLVM couldn't write an object file.
+    ObjectFile(String),
+    /// LLVM couldn't write a bitcode file.
+    Bitcode(String),
+    /// A source file couldn't be read.
+    ReadFile(String),
+    /// LLVM couldn't make an execution engine to run the code with.
+    Jit(String),
+    /// Running code was stopped after taking this many steps.
+    OutOfSteps(usize),
+    /// Debug info was asked for, but kaleidoscope was built without the
+    /// `debug-info` feature.
+    NoDebugInfo,
+}
+
+impl CompileError {
+    /// Where in the source the error happened, for the errors that know.
+    pub fn span(&self) -> Option<Span> {
+        match *self {
+            CompileError::Lex(_, span) |
+            CompileError::ExpectedExpression(_, span) |
+            CompileError::UnexpectedEof(span) |
+            CompileError::UnexpectedToken {span, ..} |
+            CompileError::DuplicateParam {span, ..} => Some(span),
+            _ => None,
+        }
+    }
+    /// The error message, followed by the line of `source` it happened on
+    /// with a caret pointing at where, if the error knows.
+    pub fn render(&self, source: &str) -> String {
+        match self.span() {
+            Some(span) => format!("{}\n{}", self, snippet(source, span)),
+            None => self.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CompileError::Lex(ref err, span) =>
+                write!(f, "{} at {}", err, span),
+            CompileError::UnexpectedToken {ref expected, ref found, span} =>
+                write!(f, "Expected {}, found {} at {}", expected, found, span),
+            CompileError::ExpectedExpression(ref found, span) =>
+                write!(f, "Unknown token {} when expecting an expression at {}", found, span),
+            CompileError::UnexpectedEof(span) =>
+                write!(f, "Unexpected end of input at {}", span),
+            CompileError::UnknownVariable(ref name) =>
+                write!(f, "There is no variable named {}", name),
+            CompileError::UnknownFunction(ref name) =>
+                write!(f, "There is no function named {}!", name),
+            CompileError::ArityMismatch {ref name, expected, found} =>
+                write!(f, "{} takes {} args, but you passed {}!", name, expected, found),
+            CompileError::InvalidOperator(ref op) =>
+                write!(f, "{} is an invalid operator!", op),
+            CompileError::IncompatibleRedefinition(ref name) =>
+                write!(f, "{} can't change its parameters, it is already called with the old ones", name),
+            CompileError::ConflictingDeclaration(ref name) =>
+                write!(f, "{} is already declared with a different type", name),
+            CompileError::DuplicateParam {ref name, ref param, span} =>
+                write!(f, "{} has more than one parameter named {} at {}", name, param, span),
+            CompileError::InvalidAssignment =>
+                write!(f, "Only variables can be assigned to!"),
+            CompileError::Verification(ref msg) =>
+                write!(f, "Generated invalid code: {}", msg),
+            CompileError::ObjectFile(ref msg) =>
+                write!(f, "Could not write object file: {}", msg),
+            CompileError::Bitcode(ref msg) =>
+                write!(f, "Could not write bitcode file: {}", msg),
+            CompileError::ReadFile(ref msg) =>
+                write!(f, "Could not read source file: {}", msg),
+            CompileError::Jit(ref msg) =>
+                write!(f, "Could not start the JIT: {}", msg),
+            CompileError::OutOfSteps(steps) =>
+                write!(f, "Stopped after {} steps, the code might never finish", steps),
+            CompileError::NoDebugInfo =>
+                write!(f, "Debug info needs kaleidoscope to be built with the debug-info feature"),
+        }
+    }
+}
+
+impl Error for CompileError {
+    fn description(&self) -> &str {
+        match *self {
+            CompileError::Lex(..) => "invalid token",
+            CompileError::UnexpectedToken {..} => "unexpected token",
+            CompileError::ExpectedExpression(..) => "expected an expression",
+            CompileError::UnexpectedEof(_) => "unexpected end of input",
+            CompileError::UnknownVariable(_) => "unknown variable",
+            CompileError::UnknownFunction(_) => "unknown function",
+            CompileError::ArityMismatch {..} => "wrong number of arguments",
+            CompileError::InvalidOperator(_) => "invalid operator",
+            CompileError::IncompatibleRedefinition(_) => "incompatible redefinition",
+            CompileError::ConflictingDeclaration(_) => "conflicting declaration",
+            CompileError::DuplicateParam {..} => "duplicate parameter",
+            CompileError::InvalidAssignment => "invalid assignment",
+            CompileError::Verification(_) => "generated invalid code",
+            CompileError::ObjectFile(_) => "could not write object file",
+            CompileError::Bitcode(_) => "could not write bitcode file",
+            CompileError::ReadFile(_) => "could not read source file",
+            CompileError::Jit(_) => "could not start the jit",
+            CompileError::OutOfSteps(_) => "ran out of steps",
+            CompileError::NoDebugInfo => "debug info not supported",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_display_matches_messages() {
+        let err = CompileError::Lex(LexError::MalformedNumber(String::from("1.2.3")),
+                                    Span { line: 1, col: 1 });
+        assert_eq!(err.to_string(), "Malformed number 1.2.3 at line 1, column 1");
+        let err = CompileError::UnexpectedToken {
+            expected: String::from(")"),
+            found: Token::Number(1.0),
+            span: Span { line: 1, col: 4 },
+        };
+        assert_eq!(err.to_string(), "Expected ), found number 1 at line 1, column 4");
+        let err = CompileError::ExpectedExpression(Token::Then, Span { line: 2, col: 1 });
+        assert_eq!(err.to_string(), "Unknown token 'then' when expecting an expression at line 2, column 1");
+        let err = CompileError::UnexpectedEof(Span { line: 1, col: 4 });
+        assert_eq!(err.to_string(), "Unexpected end of input at line 1, column 4");
+        let err = CompileError::UnknownVariable(String::from("x"));
+        assert_eq!(err.to_string(), "There is no variable named x");
+        let err = CompileError::UnknownFunction(String::from("foo"));
+        assert_eq!(err.to_string(), "There is no function named foo!");
+        let err = CompileError::ArityMismatch {
+            name: String::from("foo"),
+            expected: 2,
+            found: 1,
+        };
+        assert_eq!(err.to_string(), "foo takes 2 args, but you passed 1!");
+        let err = CompileError::InvalidOperator(String::from("&"));
+        assert_eq!(err.to_string(), "& is an invalid operator!");
+        let err = CompileError::IncompatibleRedefinition(String::from("foo"));
+        assert_eq!(err.to_string(), "foo can't change its parameters, it is already called with the old ones");
+        let err = CompileError::ConflictingDeclaration(String::from("printf"));
+        assert_eq!(err.to_string(), "printf is already declared with a different type");
+        let err = CompileError::DuplicateParam {
+            name: String::from("foo"),
+            param: String::from("a"),
+            span: Span { line: 1, col: 7 },
+        };
+        assert_eq!(err.to_string(), "foo has more than one parameter named a at line 1, column 7");
+        assert_eq!(CompileError::InvalidAssignment.to_string(), "Only variables can be assigned to!");
+        assert_eq!(CompileError::OutOfSteps(10).to_string(), "Stopped after 10 steps, the code might never finish");
+        let err = CompileError::Jit(String::from("no target"));
+        assert_eq!(err.to_string(), "Could not start the JIT: no target");
+        assert_eq!(CompileError::NoDebugInfo.to_string(),
+                   "Debug info needs kaleidoscope to be built with the debug-info feature");
+    }
+    #[test]
+    fn test_render() {
+        use parser::Parser;
+        let source = "def f(x)\n  x + )";
+        let err = Parser::from_source(source).parse_definition().unwrap_err();
+        assert_eq!(err.render(source),
+                   "Unknown token ')' when expecting an expression at line 2, column 7\n  x + )\n      ^");
+        let err = CompileError::UnknownVariable(String::from("y"));
+        assert_eq!(err.render(source), "There is no variable named y");
+    }
+}
@@ -1,64 +1,1063 @@
-use std::io::{Read, Write, stdin, stdout};
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::mem;
+use std::io::{BufRead, Read, Write, stdin, stdout};
+use std::path::PathBuf;
+use std::process;
 use llvm::*;
 use llvm::Attribute::*;
 use llvm::Function;
 use parser;
 use lexer::Token;
 use codegen;
+use driver;
+use error::CompileError;
 
-pub fn run(opt_level: usize) {
+/// Prints `x` as an ASCII character. C's `putchar` takes an int, which we
+/// can't pass from Kaleidoscope since everything is a double.
+#[no_mangle]
+pub extern "C" fn putchard(x: f64) -> f64 {
+    print!("{}", x as u8 as char);
+    stdout().flush().unwrap();
+    0.0
+}
+
+/// Prints `x` followed by a newline.
+#[no_mangle]
+pub extern "C" fn printd(x: f64) -> f64 {
+    println!("{}", x);
+    0.0
+}
+
+thread_local! {
+    // How many more steps guarded code may take, or None if there's no limit
+    static STEPS_LEFT: Cell<Option<usize>> = Cell::new(None);
+    // Whether guarded code tried to take a step after running out
+    static OUT_OF_STEPS: Cell<bool> = Cell::new(false);
+}
+
+/// Guarded code calls this on every function call and loop iteration, see
+/// `codegen::STEP_FUNCTION`.
+#[no_mangle]
+pub extern "C" fn kaleidoscope_step() -> f64 {
+    STEPS_LEFT.with(|steps| match steps.get() {
+        Some(0) => {
+            OUT_OF_STEPS.with(|out| out.set(true));
+            0.0
+        },
+        Some(n) => {
+            steps.set(Some(n - 1));
+            1.0
+        },
+        None => 1.0,
+    })
+}
+
+/// Functions in this crate that Kaleidoscope code can call after declaring them with `extern`.
+///
+/// These get mapped into the engine explicitly, because whether the dynamic
+/// linker can find symbols in our own binary depends on how it was linked.
+pub fn host_function(name: &str) -> Option<*const ()> {
+    match name {
+        "putchard" => Some(putchard as *const ()),
+        "printd" => Some(printd as *const ()),
+        _ => None,
+    }
+}
+
+/// Looks up a compiled function, checking that it takes `arity` arguments.
+fn compiled_function(engine: &JitEngine, module: &Module, name: &str, arity: usize)
+                     -> Option<extern "C" fn(f64) -> f64> {
+    let func = module.get_function(name)?;
+    if func.get_signature().num_params() != arity || func.get_entry().is_none() {
+        return None
+    }
+    unsafe {
+        Some(engine.get_function::<f64, f64>(func))
+    }
+}
+
+/// Gets a pointer to the one argument function `name` in `module`, which
+/// has to have been added to `engine`, so it can be called from Rust.
+///
+/// Returns None if there is no such function, it doesn't take exactly one
+/// argument, or it's only declared in `module`.
+///
+/// The pointer comes from the engine as an untyped address, which has to be
+/// transmuted into a function pointer. That's only sound because every
+/// Kaleidoscope function takes and returns doubles and we check the arity.
+/// It dangles once `module` is removed from the engine, so don't call it after that.
+pub fn get_function_address(engine: &JitEngine, module: &Module, name: &str)
+                            -> Option<extern "C" fn(f64) -> f64> {
+    compiled_function(engine, module, name, 1)
+}
+
+/// Like `get_function_address`, but for functions without arguments.
+pub fn get_function_address0(engine: &JitEngine, module: &Module, name: &str)
+                             -> Option<extern "C" fn() -> f64> {
+    compiled_function(engine, module, name, 0).map(|f| unsafe {
+        mem::transmute::<extern "C" fn(f64) -> f64, extern "C" fn() -> f64>(f)
+    })
+}
+
+/// Like `get_function_address`, but for functions with two arguments.
+pub fn get_function_address2(engine: &JitEngine, module: &Module, name: &str)
+                             -> Option<extern "C" fn(f64, f64) -> f64> {
+    compiled_function(engine, module, name, 2).map(|f| unsafe {
+        mem::transmute::<extern "C" fn(f64) -> f64, extern "C" fn(f64, f64) -> f64>(f)
+    })
+}
+
+/// Compiles and runs a single expression, without a REPL session.
+///
+/// `args` gives the values of the variables the expression uses, like
+/// `jit_eval("x * y", &[("x", 3.0), ("y", 4.0)])`. Any other variable is an error.
+pub fn jit_eval(source: &str, args: &[(&str, f64)]) -> Result<f64, CompileError> {
+    let mut parser = parser::Parser::from_source(source);
+    let mut function = parser.parse_top_level_expr()?;
+    function.prototype.args = args.iter().map(|&(name, _)| String::from(name)).collect();
+    parser::validate(&function, &HashMap::new())?;
     let context = Context::new();
-    let module = Module::new("my jit", &context);
-    let engine = JitEngine::new(&module, JitOptions {
-        opt_level: opt_level,
-    }).unwrap();
-    loop {
-        let builder = Builder::new(&context);
-        let mut input = String::new();
-        print!("> ", );
-        stdout().flush();
-        match stdin().read_line(&mut input) {
-            Ok(_) => (),
-            Err(_) => break,
+    let module = Module::new("jit_eval", &context);
+    let builder = Builder::new(&context);
+    let func = codegen::generate_function(&function, &builder, &module, &context)?;
+    let engine = JitEngine::new(&module, JitOptions {opt_level: 0}).unwrap();
+    let values: Vec<GenericValue> = args.iter().map(|&(_, value)| value.to_generic(&context)).collect();
+    let values: Vec<&GenericValue> = values.iter().collect();
+    let res = engine.run_function(func, &values);
+    Ok(f64::from_generic(&res, &context))
+}
+
+const HELP: &'static str = "\
+Commands:
+  .dump         Print the LLVM IR for everything defined so far
+  .help         Print this message
+  .precision N  Show results with N decimal places, or in full without an N
+  .reset        Forget everything defined so far
+  exit          Quit the REPL";
+
+/// The state of a REPL session.
+///
+/// Externs can refer to any C function that takes and returns doubles, like
+/// `sin`, as well as `putchard` and `printd` which are provided by this crate
+/// and anything added with `register_symbol`.
+///
+/// Every definition and extern gets compiled into its own module, which is
+/// handed to the engine and kept alive for the rest of the session. Each
+/// module starts out with declarations for all the functions known so far, so
+/// code in one module can call functions defined in earlier ones.
+pub struct Session<'a> {
+    context: &'a CBox<Context>,
+    engine: &'a JitEngine,
+    modules: Vec<CSemiBox<'a, Module>>,
+    // The definition compiled into each of the modules
+    definitions: Vec<parser::Function>,
+    prototypes: HashMap<String, parser::Prototype>,
+    // Host functions added by register_symbol, by the name externs use for them
+    symbols: HashMap<String, *const ()>,
+    passes: codegen::PassConfig,
+    // Warnings about what was evaluated, until take_warnings is called
+    warnings: Vec<String>,
+    step_limit: Option<usize>,
+}
+
+/// The step limit the REPL starts with, which is enough for a few seconds of work.
+const DEFAULT_STEP_LIMIT: usize = 100_000_000;
+
+impl<'a> Session<'a> {
+    pub fn new(context: &'a CBox<Context>, engine: &'a JitEngine, opt_level: usize) -> Session<'a> {
+        Session {
+            context: context,
+            engine: engine,
+            modules: Vec::new(),
+            definitions: Vec::new(),
+            prototypes: HashMap::new(),
+            symbols: HashMap::new(),
+            passes: codegen::PassConfig::from_opt_level(opt_level),
+            warnings: Vec::new(),
+            step_limit: None,
         }
-        if input.trim_left() == "" {
-            continue;
+    }
+
+    /// Chooses the optimizations run over newly compiled code, in place of
+    /// the ones `opt_level` picked. `PassConfig::None` turns them all off,
+    /// which helps when debugging codegen.
+    pub fn set_passes(&mut self, passes: codegen::PassConfig) {
+        self.passes = passes;
+    }
+
+    /// Limits how long top-level expressions can run, so an infinite loop or
+    /// recursion gives an error instead of hanging.
+    ///
+    /// Code compiled while there is a limit counts a step on every function
+    /// call and loop iteration. When it runs out of steps every function
+    /// returns 0.0 right away and every loop ends, so the call unwinds, and
+    /// `eval` returns `CompileError::OutOfSteps`. Code compiled without a
+    /// limit isn't guarded, and runs as fast as it would otherwise. Guarded
+    /// code still runs to the end once the limit is taken away again.
+    pub fn set_step_limit(&mut self, limit: Option<usize>) {
+        self.step_limit = limit;
+    }
+
+    /// Makes `extern name(...)` refer to the host function at `addr`, taking
+    /// priority over any C function with the same name. Symbols stay
+    /// registered when the session is reset.
+    ///
+    /// This is unsafe because `addr` has to point to an `extern "C"` function
+    /// that takes as many doubles as the extern declares and returns a double.
+    pub unsafe fn register_symbol(&mut self, name: &str, addr: *const ()) {
+        self.symbols.insert(String::from(name), addr);
+    }
+
+    /// Creates a module that declares every function defined so far.
+    fn new_module(&self, name: &str) -> CSemiBox<'a, Module> {
+        let module = Module::new(name, self.context);
+        if self.step_limit.is_some() {
+            let step = parser::Prototype::new(String::from(codegen::STEP_FUNCTION), Vec::new());
+            let func = codegen::generate_prototype(&step, &module, self.context).unwrap();
+            unsafe {
+                self.engine.add_global_mapping(func, kaleidoscope_step as *const ());
+            }
+        }
+        for proto in self.prototypes.values() {
+            let func = codegen::generate_prototype(proto, &module, self.context).unwrap();
+            // Anything else declared with extern, like sin from libm, is
+            // looked up in the process when the module is compiled.
+            let addr = self.symbols.get(&proto.name).cloned().or_else(|| host_function(&proto.name));
+            if let Some(addr) = addr {
+                unsafe {
+                    self.engine.add_global_mapping(func, addr);
+                }
+            }
+        }
+        module
+    }
+
+    /// Compiles a definition into a module of its own, without adding it to the engine.
+    fn compile_definition(&self, func: &parser::Function) -> Result<CSemiBox<'a, Module>, CompileError> {
+        let builder = Builder::new(self.context);
+        let module = self.new_module(&func.prototype.name);
+        codegen::generate_function(func, &builder, &module, self.context)?;
+        codegen::optimize_with(&module, &self.passes);
+        Ok(module)
+    }
+
+    /// The definitions that use `name`, directly or through each other, in
+    /// the order they were defined.
+    fn dependents(&self, name: &str) -> Vec<&parser::Function> {
+        let mut used = vec![String::from(name)];
+        let mut found = vec![false; self.definitions.len()];
+        // Keep going until no more definitions use anything found so far
+        while let Some(name) = used.pop() {
+            for (i, def) in self.definitions.iter().enumerate() {
+                if found[i] || def.prototype.name == name {
+                    continue
+                }
+                if parser::called_functions(&def.body).contains(&name) ||
+                   parser::free_variables(&def.body).contains(&name) {
+                    found[i] = true;
+                    used.push(def.prototype.name.clone());
+                }
+            }
+        }
+        self.definitions.iter().zip(found).filter(|&(_, found)| found).map(|(def, _)| def).collect()
+    }
+
+    /// Compiles `func`, and compiles every definition that uses it again,
+    /// so they don't keep calling the body `func` replaces. Nothing is
+    /// added to the engine until all of them compiled.
+    fn define(&mut self, func: parser::Function) -> Result<(), CompileError> {
+        let mut compiled = vec![(func.clone(), self.compile_definition(&func)?)];
+        for def in self.dependents(&func.prototype.name) {
+            // The definition might not fit the new prototype any more
+            parser::validate(def, &self.prototypes)?;
+            compiled.push((def.clone(), self.compile_definition(def)?));
+        }
+        for param in parser::unused_params(&func) {
+            self.warnings.push(format!("{} never uses its parameter {}", func.prototype.name, param));
+        }
+        for (def, module) in compiled {
+            // A redefinition replaces the module holding the old body
+            let old = self.definitions.iter().position(|old| old.prototype.name == def.prototype.name);
+            if let Some(i) = old {
+                if def.prototype.name == func.prototype.name {
+                    self.warnings.push(format!("redefining {}", def.prototype.name));
+                }
+                self.engine.remove_module(&self.modules[i]);
+                self.modules.remove(i);
+                self.definitions.remove(i);
+            }
+            self.engine.add_module(&module);
+            self.modules.push(module);
+            self.definitions.push(def);
+        }
+        Ok(())
+    }
+
+    /// Drops every definition and extern, as if the session had just started.
+    ///
+    /// The modules are taken out of the engine before they are dropped, so
+    /// the engine doesn't keep pointing at freed code.
+    pub fn reset(&mut self) {
+        for module in &self.modules {
+            self.engine.remove_module(module);
         }
-        if input == "exit\n" {
-            break;
+        self.modules.clear();
+        self.definitions.clear();
+        self.prototypes.clear();
+    }
+
+    /// Returns the warnings about everything evaluated since the last call,
+    /// like parameters that are never used, and forgets them.
+    pub fn take_warnings(&mut self) -> Vec<String> {
+        mem::replace(&mut self.warnings, Vec::new())
+    }
+
+    /// Runs a REPL command like `.dump`, returning the text to show the user.
+    pub fn command(&mut self, command: &str) -> String {
+        match command {
+            ".dump" => {
+                self.modules.iter()
+                    .map(|module| codegen::emit_ir(module))
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            },
+            ".help" => String::from(HELP),
+            ".reset" => {
+                self.reset();
+                String::from("Forgot all definitions")
+            },
+            _ => format!("Unknown command {}, type .help for a list of commands", command),
+        }
+    }
+
+    /// Evaluates one line of input, returning the value of top-level expressions.
+    ///
+    /// Nothing is added to the session if compiling the input fails.
+    pub fn eval(&mut self, input: &str) -> Result<Option<f64>, CompileError> {
+        let builder = Builder::new(self.context);
+        let mut parser = parser::Parser::from_source(input);
+        // Make operators defined on earlier lines usable on this one
+        for proto in self.prototypes.values() {
+            if let Some(prec) = proto.precedence {
+                parser.set_precedence(proto.operator_name(), prec);
+            } else if proto.is_unary_op() {
+                parser.add_unary_operator(proto.operator_name().chars().next().unwrap());
+            }
         }
-        let mut parser = parser::Parser::from_source(&input);
         match parser.current {
-            Some(Token::Define) => {
-                let func = match parser.parse_definition() {
-                    Ok(func) => func,
-                    Err(e) => {
-                        println!("Error parsing definition: {}", e);
-                        continue;
-                    }
-                };
-                codegen::generate_function(&func, &builder, &module, &context).unwrap();
+            Token::Define => {
+                let func = parser.parse_definition()?;
+                parser::validate(&func, &self.prototypes)?;
+                // Modules declare the new prototype from here on, which
+                // is put back if the definition doesn't compile
+                let name = func.prototype.name.clone();
+                let old = self.prototypes.insert(name.clone(), func.prototype.clone());
+                if let Err(err) = self.define(func) {
+                    match old {
+                        Some(old) => self.prototypes.insert(name, old),
+                        None => self.prototypes.remove(&name),
+                    };
+                    return Err(err)
+                }
+                Ok(None)
             },
-            Some(Token::Extern) => {
-                let proto = match parser.parse_extern() {
-                    Ok(proto) => proto,
-                    Err(e) => {
-                        println!("Error parsing extern: {}", e);
-                        continue;
-                    }
-                };
-                codegen::generate_prototype(&proto, &module, &context);
+            Token::Extern => {
+                let proto = parser.parse_extern()?;
+                self.prototypes.insert(proto.name.clone(), proto);
+                Ok(None)
             },
             // Top level expression
             _ => {
-                let expr = parser.parse_top_level_expr().unwrap();
-                let new_module = module.clone();
-                let func = codegen::generate_function(&expr, &builder, &new_module, &context).unwrap();
-                engine.add_module(&new_module);
-                let res = engine.run_function(&func, &[]);
-                println!("{}", f64::from_generic(&res, &context));
-                engine.remove_module(&new_module);
+                let expr = parser.parse_top_level_expr()?;
+                parser::validate(&expr, &self.prototypes)?;
+                // The expression's module is only needed while it runs, so it
+                // is removed from the engine again afterwards.
+                let module = self.new_module("__top_level_expr");
+                let func = codegen::generate_function(&expr, &builder, &module, self.context)?;
+                codegen::optimize_with(&module, &self.passes);
+                self.engine.add_module(&module);
+                STEPS_LEFT.with(|steps| steps.set(self.step_limit));
+                OUT_OF_STEPS.with(|out| out.set(false));
+                let res = self.engine.run_function(&func, &[]);
+                let value = f64::from_generic(&res, self.context);
+                self.engine.remove_module(&module);
+                match (self.step_limit, OUT_OF_STEPS.with(|out| out.get())) {
+                    (Some(limit), true) => Err(CompileError::OutOfSteps(limit)),
+                    _ => Ok(Some(value)),
+                }
+            }
+        }
+    }
+}
+
+/// What to do with the code read from stdin.
+#[derive(Debug, Clone, PartialEq)]
+pub enum OutputMode {
+    /// Run it, interactively when used with `run`.
+    Jit,
+    /// Print the LLVM IR.
+    Ir,
+    /// Write LLVM bitcode to the file at the path.
+    Bitcode(PathBuf),
+}
+
+pub fn run(opt_level: usize, mode: OutputMode) {
+    if mode == OutputMode::Jit {
+        let context = Context::new();
+        let module = Module::new("my jit", &context);
+        let engine = JitEngine::new(&module, JitOptions {
+            opt_level: opt_level,
+        }).unwrap();
+        let mut session = Session::new(&context, &engine, opt_level);
+        session.set_step_limit(Some(DEFAULT_STEP_LIMIT));
+        let stdin = stdin();
+        repl(&mut session, stdin.lock(), stdout());
+        return
+    }
+    // The other modes need the whole program, so there's no REPL
+    let mut source = String::new();
+    stdin().read_to_string(&mut source).unwrap();
+    if let Err(e) = emit(&source, opt_level, &mode, stdout()) {
+        eprintln!("Error: {}", e.render(&source));
+        process::exit(1);
+    }
+}
+
+/// Compiles all of `source` at once and handles the result according to `mode`.
+///
+/// `Jit` writes the value of every top-level expression to `output`, `Ir`
+/// writes the IR for the whole program, and `Bitcode` writes nothing.
+pub fn emit<W: Write>(source: &str, opt_level: usize, mode: &OutputMode, mut output: W) -> Result<(), CompileError> {
+    if *mode == OutputMode::Jit {
+        for value in driver::run_program(source, opt_level)? {
+            writeln!(output, "{}", value).unwrap();
+        }
+        return Ok(())
+    }
+    let context = Context::new();
+    let module = Module::new("program", &context);
+    driver::compile_program(source, &module, &context)?;
+    codegen::optimize(&module, opt_level);
+    match *mode {
+        OutputMode::Ir => write!(output, "{}", codegen::emit_ir(&module)).unwrap(),
+        OutputMode::Bitcode(ref path) => {
+            module.write_bitcode(&path.to_string_lossy())
+                .map_err(|e| CompileError::Bitcode(format!("{}: {}", path.display(), e)))?;
+        },
+        OutputMode::Jit => unreachable!(),
+    }
+    Ok(())
+}
+
+/// Formats a result for the REPL, with `precision` decimal places if it's set.
+fn format_value(value: f64, precision: Option<usize>) -> String {
+    match precision {
+        Some(precision) => format!("{:.*}", precision, value),
+        None => format!("{}", value),
+    }
+}
+
+/// Adds a line of input to `buffer`, and returns whether it's ready to parse.
+/// A line ending in a backslash isn't: the backslash is dropped and the next
+/// line is joined straight onto it.
+fn add_line(buffer: &mut String, line: &str) -> bool {
+    let content = line.trim_end_matches(|c| c == '\n' || c == '\r');
+    if content.ends_with('\\') {
+        buffer.push_str(&content[..content.len() - 1]);
+        false
+    } else {
+        buffer.push_str(line);
+        true
+    }
+}
+
+/// Reads and evaluates input until it runs out or the user types `exit`.
+///
+/// An item can span several lines. As long as what has been typed so far
+/// ends before the item is complete, more lines are read with a `... ` prompt.
+/// Ending a line with `\` asks for another line too.
+fn repl<R: BufRead, W: Write>(session: &mut Session, mut input: R, mut output: W) {
+    let mut buffer = String::new();
+    let mut precision = None;
+    loop {
+        let prompt = if buffer.is_empty() { "> " } else { "... " };
+        write!(output, "{}", prompt).unwrap();
+        output.flush().unwrap();
+        let mut line = String::new();
+        match input.read_line(&mut line) {
+            Ok(0) | Err(_) => break,
+            Ok(_) => (),
+        }
+        if buffer.is_empty() {
+            // Trimming copes with trailing spaces and Windows line endings
+            let command = line.trim();
+            if command.is_empty() {
+                continue;
+            }
+            if command == "exit" {
+                break;
+            }
+            // Precision only affects how results are shown, so the session doesn't need it
+            if command.starts_with(".precision") {
+                let places = command[".precision".len()..].trim();
+                let message = if places.is_empty() {
+                    precision = None;
+                    String::from("Showing results in full")
+                } else {
+                    match places.parse::<usize>() {
+                        Ok(places) => {
+                            precision = Some(places);
+                            format!("Showing results with {} decimal places", places)
+                        },
+                        Err(_) => format!("Expected a number of decimal places, found {}", places),
+                    }
+                };
+                writeln!(output, "{}", message).unwrap();
+                continue;
+            }
+            if command.starts_with('.') {
+                writeln!(output, "{}", session.command(command)).unwrap();
+                continue;
             }
         }
+        if !add_line(&mut buffer, &line) {
+            continue;
+        }
+        let result = session.eval(&buffer);
+        for warning in session.take_warnings() {
+            writeln!(output, "Warning: {}", warning).unwrap();
+        }
+        match result {
+            Err(CompileError::UnexpectedEof(_)) => continue,
+            Ok(Some(value)) => writeln!(output, "{}", format_value(value, precision)).unwrap(),
+            Ok(None) => (),
+            Err(e) => writeln!(output, "Error: {}", e.render(&buffer)).unwrap(),
+        }
+        buffer.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lexer;
+    #[test]
+    fn test_definitions_persist() {
+        let context = Context::new();
+        let module = Module::new("test", &context);
+        let engine = JitEngine::new(&module, JitOptions {opt_level: 0}).unwrap();
+        let mut session = Session::new(&context, &engine, 0);
+        assert_eq!(session.eval("def double(x) x+x"), Ok(None));
+        assert_eq!(session.eval("double(21)"), Ok(Some(42.0)));
+        assert_eq!(session.eval("def quadruple(x) double(double(x))"), Ok(None));
+        assert_eq!(session.eval("quadruple(2) + double(1)"), Ok(Some(10.0)));
+    }
+    #[test]
+    fn test_commands() {
+        let context = Context::new();
+        let module = Module::new("test", &context);
+        let engine = JitEngine::new(&module, JitOptions {opt_level: 0}).unwrap();
+        let mut session = Session::new(&context, &engine, 0);
+        session.eval("def double(x) x+x").unwrap();
+        let ir = session.command(".dump");
+        assert!(ir.contains("@double"));
+        assert!(ir.contains("fadd"));
+        assert!(session.command(".help").contains(".dump"));
+        assert_eq!(session.command(".frobnicate"),
+                   "Unknown command .frobnicate, type .help for a list of commands");
+    }
+    #[test]
+    fn test_errors_dont_end_the_session() {
+        let context = Context::new();
+        let module = Module::new("test", &context);
+        let engine = JitEngine::new(&module, JitOptions {opt_level: 0}).unwrap();
+        let mut session = Session::new(&context, &engine, 0);
+        assert!(session.eval("1 +").is_err());
+        assert!(session.eval("def broken(x) y").is_err());
+        assert_eq!(session.eval("broken(1)"),
+                   Err(CompileError::UnknownFunction(String::from("broken"))));
+        assert_eq!(session.eval("1 + 1"), Ok(Some(2.0)));
+        session.eval("def two(a b) a").unwrap();
+        assert_eq!(session.eval("two(1)"), Err(CompileError::ArityMismatch {
+            name: String::from("two"),
+            expected: 2,
+            found: 1,
+        }));
+        assert_eq!(session.eval("def three(a b c) four(a)"),
+                   Err(CompileError::UnknownFunction(String::from("four"))));
+        // Nothing was compiled for the failed definition
+        assert_eq!(session.modules.len(), 1);
+    }
+    #[test]
+    fn test_operators_persist() {
+        let context = Context::new();
+        let module = Module::new("test", &context);
+        let engine = JitEngine::new(&module, JitOptions {opt_level: 0}).unwrap();
+        let mut session = Session::new(&context, &engine, 0);
+        session.eval("def binary| 5 (a b) if a then 1 else if b then 1 else 0").unwrap();
+        assert_eq!(session.eval("0 | 1 < 2"), Ok(Some(1.0)));
+        assert_eq!(session.eval("0 | 0"), Ok(Some(0.0)));
+    }
+    #[test]
+    fn test_unary_operators() {
+        let context = Context::new();
+        let module = Module::new("test", &context);
+        let engine = JitEngine::new(&module, JitOptions {opt_level: 0}).unwrap();
+        let mut session = Session::new(&context, &engine, 0);
+        session.eval("def unary~(v) if v then 0 else 1").unwrap();
+        assert_eq!(session.eval("~0"), Ok(Some(1.0)));
+        assert_eq!(session.eval("~~0 + ~3"), Ok(Some(0.0)));
+    }
+    #[test]
+    fn test_logical_not() {
+        let context = Context::new();
+        let module = Module::new("test", &context);
+        let engine = JitEngine::new(&module, JitOptions {opt_level: 0}).unwrap();
+        let mut session = Session::new(&context, &engine, 0);
+        assert_eq!(session.eval("!0"), Ok(Some(1.0)));
+        assert_eq!(session.eval("!5"), Ok(Some(0.0)));
+        assert_eq!(session.eval("!!5 && !(1 > 2)"), Ok(Some(1.0)));
+        assert_eq!(session.eval("!0i"), Ok(Some(1.0)));
+    }
+    #[test]
+    fn test_nan_comparisons() {
+        let context = Context::new();
+        let module = Module::new("test", &context);
+        let engine = JitEngine::new(&module, JitOptions {opt_level: 0}).unwrap();
+        let mut session = Session::new(&context, &engine, 0);
+        session.eval("def nan() 0/0").unwrap();
+        // Only != holds when NaN is involved
+        for &(op, expected) in &[("<", 0.0), (">", 0.0), ("<=", 0.0), (">=", 0.0), ("==", 0.0), ("!=", 1.0)] {
+            assert_eq!(session.eval(&format!("var x = nan() in x {} x", op)), Ok(Some(expected)), "{}", op);
+            assert_eq!(session.eval(&format!("nan() {} 1", op)), Ok(Some(expected)), "{}", op);
+        }
+        assert_eq!(session.eval("!nan()"), Ok(Some(0.0)));
+        assert_eq!(session.eval("1 != 1"), Ok(Some(0.0)));
+        assert_eq!(session.eval("1i != 2i"), Ok(Some(1.0)));
+    }
+    #[test]
+    fn test_nan_is_true() {
+        let context = Context::new();
+        let module = Module::new("test", &context);
+        let engine = JitEngine::new(&module, JitOptions {opt_level: 0}).unwrap();
+        let mut session = Session::new(&context, &engine, 0);
+        session.eval("def nan() 0/0").unwrap();
+        assert_eq!(session.eval("nan() ? 1 : 2"), Ok(Some(1.0)));
+        assert_eq!(session.eval("if nan() then 1 else 2"), Ok(Some(1.0)));
+        assert_eq!(session.eval("nan() && nan()"), Ok(Some(1.0)));
+        assert_eq!(session.eval("0 || nan()"), Ok(Some(1.0)));
+        assert_eq!(session.eval("assert(nan()) != 0"), Ok(Some(1.0)));
+        // Both loops go around again while the condition is NaN
+        assert_eq!(session.eval("var n = 0 in (for i = 0, n < 3 ? nan() : 0 in n = n + 1) : n"), Ok(Some(3.0)));
+        assert_eq!(session.eval("var n = 0 in (while n < 3 ? nan() : 0 in n = n + 1) : n"), Ok(Some(3.0)));
+    }
+    #[test]
+    fn test_nan_max_min() {
+        let context = Context::new();
+        let module = Module::new("test", &context);
+        let engine = JitEngine::new(&module, JitOptions {opt_level: 0}).unwrap();
+        let mut session = Session::new(&context, &engine, 0);
+        session.eval("def nan() 0/0").unwrap();
+        // The second argument wins when either is NaN
+        for name in &["max", "min"] {
+            assert_eq!(session.eval(&format!("{}(nan(), 1)", name)), Ok(Some(1.0)), "{}", name);
+            let value = session.eval(&format!("{}(1, nan())", name)).unwrap().unwrap();
+            assert!(value.is_nan(), "{}", name);
+        }
+    }
+    #[test]
+    fn test_optimized_session() {
+        let context = Context::new();
+        let module = Module::new("test", &context);
+        let engine = JitEngine::new(&module, JitOptions {opt_level: 2}).unwrap();
+        let mut session = Session::new(&context, &engine, 2);
+        session.eval("def foo(x) (1+2)+x").unwrap();
+        assert_eq!(session.eval("foo(4)"), Ok(Some(7.0)));
+        assert!(!session.command(".dump").contains("alloca"));
+    }
+    #[test]
+    fn test_set_passes() {
+        let context = Context::new();
+        let module = Module::new("test", &context);
+        let engine = JitEngine::new(&module, JitOptions {opt_level: 2}).unwrap();
+        let mut session = Session::new(&context, &engine, 2);
+        session.set_passes(codegen::PassConfig::None);
+        session.eval("def foo(x) (1+2)+x").unwrap();
+        assert_eq!(session.eval("foo(4)"), Ok(Some(7.0)));
+        assert!(session.command(".dump").contains("alloca"));
+    }
+    #[test]
+    fn test_redefinition() {
+        let context = Context::new();
+        let module = Module::new("test", &context);
+        let engine = JitEngine::new(&module, JitOptions {opt_level: 0}).unwrap();
+        let mut session = Session::new(&context, &engine, 0);
+        session.eval("def f(x) x+1").unwrap();
+        assert_eq!(session.eval("f(1)"), Ok(Some(2.0)));
+        session.eval("def f(x) x+2").unwrap();
+        assert_eq!(session.eval("f(1)"), Ok(Some(3.0)));
+        assert_eq!(session.take_warnings(), vec![String::from("redefining f")]);
+        // Old bodies with loops in them can be replaced too
+        session.eval("def f(x) var s = 0 in (for i = 0, i < x in s = s + i); s").unwrap();
+        assert_eq!(session.eval("f(4)"), Ok(Some(6.0)));
+        session.eval("def f(x) x * 2").unwrap();
+        assert_eq!(session.eval("f(4)"), Ok(Some(8.0)));
+    }
+    #[test]
+    fn test_redefinition_with_other_params() {
+        let context = Context::new();
+        let module = Module::new("test", &context);
+        let engine = JitEngine::new(&module, JitOptions {opt_level: 0}).unwrap();
+        let mut session = Session::new(&context, &engine, 0);
+        session.eval("def f(x) x").unwrap();
+        session.eval("def f(x y) x + y").unwrap();
+        assert_eq!(session.eval("f(1, 2)"), Ok(Some(3.0)));
+        assert_eq!(session.eval("f(1)"), Err(CompileError::ArityMismatch {
+            name: String::from("f"),
+            expected: 2,
+            found: 1,
+        }));
+    }
+    #[test]
+    fn test_redefinition_recompiles_callers() {
+        let context = Context::new();
+        let module = Module::new("test", &context);
+        let engine = JitEngine::new(&module, JitOptions {opt_level: 0}).unwrap();
+        let mut session = Session::new(&context, &engine, 0);
+        session.eval("def f(x) x + 1").unwrap();
+        session.eval("def g(x) f(x) * 10").unwrap();
+        session.eval("def h(x) g(x) + 1").unwrap();
+        assert_eq!(session.eval("h(1)"), Ok(Some(21.0)));
+        session.eval("def f(x) x + 2").unwrap();
+        assert_eq!(session.eval("g(1)"), Ok(Some(30.0)));
+        assert_eq!(session.eval("h(1)"), Ok(Some(31.0)));
+        // g can't call a two argument f, so f stays as it was
+        assert_eq!(session.eval("def f(x y) x + y"), Err(CompileError::ArityMismatch {
+            name: String::from("f"),
+            expected: 2,
+            found: 1,
+        }));
+        assert_eq!(session.eval("f(1)"), Ok(Some(3.0)));
+        assert_eq!(session.eval("h(1)"), Ok(Some(31.0)));
+    }
+    #[test]
+    fn test_warnings() {
+        let context = Context::new();
+        let module = Module::new("test", &context);
+        let engine = JitEngine::new(&module, JitOptions {opt_level: 0}).unwrap();
+        let mut session = Session::new(&context, &engine, 0);
+        session.eval("def foo(a b c) a").unwrap();
+        assert_eq!(session.take_warnings(), vec![String::from("foo never uses its parameter b"),
+                                                 String::from("foo never uses its parameter c")]);
+        assert_eq!(session.take_warnings(), Vec::<String>::new());
+        // Nothing is reported for input that doesn't compile
+        assert!(session.eval("def bar(a b) nosuch(a)").is_err());
+        assert_eq!(session.take_warnings(), Vec::<String>::new());
+        let mut output = Vec::new();
+        repl(&mut session, "def baz(x) 1\n".as_bytes(), &mut output);
+        assert_eq!(String::from_utf8(output).unwrap(), "> Warning: baz never uses its parameter x\n> ");
+    }
+    #[test]
+    fn test_extern_functions() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        static PRINTED: AtomicUsize = AtomicUsize::new(0);
+        // Stands in for the real putchard, so the test doesn't print anything
+        extern "C" fn putchard(c: f64) -> f64 {
+            PRINTED.store(c as usize, Ordering::SeqCst);
+            0.0
+        }
+        let context = Context::new();
+        let module = Module::new("test", &context);
+        let engine = JitEngine::new(&module, JitOptions {opt_level: 0}).unwrap();
+        let mut session = Session::new(&context, &engine, 0);
+        unsafe {
+            session.register_symbol("putchard", putchard as *const ());
+        }
+        session.eval("extern putchard(c)").unwrap();
+        assert_eq!(session.eval("putchard(72)"), Ok(Some(0.0)));
+        assert_eq!(PRINTED.load(Ordering::SeqCst), 72);
+        session.eval("extern cos(x)").unwrap();
+        assert_eq!(session.eval("cos(0)"), Ok(Some(1.0)));
+    }
+    #[test]
+    fn test_assert() {
+        use std::env;
+        use std::process::{Command, Stdio};
+        let context = Context::new();
+        let module = Module::new("test", &context);
+        let engine = JitEngine::new(&module, JitOptions {opt_level: 0}).unwrap();
+        let mut session = Session::new(&context, &engine, 0);
+        assert_eq!(session.eval("assert(1 < 2)"), Ok(Some(1.0)));
+        assert_eq!(session.eval("assert(3) + 1"), Ok(Some(4.0)));
+        // A failed assert takes the whole process down, so it has to happen in
+        // a copy of this test running in another process
+        if env::var("KALEIDOSCOPE_FAILED_ASSERT").is_ok() {
+            session.eval("assert(2 < 1)").unwrap();
+            return
+        }
+        let status = Command::new(env::current_exe().unwrap())
+            .args(&["jit::tests::test_assert", "--exact", "--test-threads=1"])
+            .env("KALEIDOSCOPE_FAILED_ASSERT", "1")
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .unwrap();
+        assert!(!status.success());
+    }
+    #[test]
+    fn test_register_symbol() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        extern "C" fn count(by: f64) -> f64 {
+            (COUNTER.fetch_add(by as usize, Ordering::SeqCst) + by as usize) as f64
+        }
+        let context = Context::new();
+        let module = Module::new("test", &context);
+        let engine = JitEngine::new(&module, JitOptions {opt_level: 0}).unwrap();
+        let mut session = Session::new(&context, &engine, 0);
+        unsafe {
+            session.register_symbol("count", count as *const ());
+        }
+        session.eval("extern count(by)").unwrap();
+        assert_eq!(session.eval("count(2) + count(3)"), Ok(Some(7.0)));
+        assert_eq!(COUNTER.load(Ordering::SeqCst), 5);
+        // Functions defined later can call it too
+        session.eval("def twice(by) count(by) : count(by)").unwrap();
+        assert_eq!(session.eval("twice(1)"), Ok(Some(7.0)));
+    }
+    #[test]
+    fn test_function_pointers() {
+        let context = Context::new();
+        let module = Module::new("test", &context);
+        let builder = Builder::new(&context);
+        for source in &["def sq(x) x*x", "def answer() 42", "def add(a b) a+b"] {
+            let func = parser::Parser::from_source(source).parse_definition().unwrap();
+            codegen::generate_function(&func, &builder, &module, &context).unwrap();
+        }
+        let engine = JitEngine::new(&module, JitOptions {opt_level: 0}).unwrap();
+        let sq = get_function_address(&engine, &module, "sq").unwrap();
+        assert_eq!(sq(7.0), 49.0);
+        assert_eq!(sq(1.5), 2.25);
+        assert_eq!(get_function_address0(&engine, &module, "answer").unwrap()(), 42.0);
+        assert_eq!(get_function_address2(&engine, &module, "add").unwrap()(1.0, 2.0), 3.0);
+        // The wrong arity or a missing function gives None
+        assert!(get_function_address(&engine, &module, "add").is_none());
+        assert!(get_function_address(&engine, &module, "missing").is_none());
+    }
+    #[test]
+    fn test_multi_line_input() {
+        let context = Context::new();
+        let module = Module::new("test", &context);
+        let engine = JitEngine::new(&module, JitOptions {opt_level: 0}).unwrap();
+        let mut session = Session::new(&context, &engine, 0);
+        let mut output = Vec::new();
+        repl(&mut session, "def foo(x)\n  x + 1\n(foo(2)\n)\n1 + )\n".as_bytes(), &mut output);
+        assert_eq!(String::from_utf8(output).unwrap(),
+                   "> ... > ... 3\n> Error: Unknown token ')' \
+                    when expecting an expression at line 1, column 5\n1 + )\n    ^\n> ");
+        assert_eq!(session.prototypes.len(), 1);
+    }
+    #[test]
+    fn test_precision() {
+        assert_eq!(format_value(1.0 / 3.0, None), "0.3333333333333333");
+        assert_eq!(format_value(1.0 / 3.0, Some(2)), "0.33");
+        assert_eq!(format_value(2.0 / 3.0, Some(0)), "1");
+        assert_eq!(format_value(42.0, Some(0)), "42");
+        assert_eq!(format_value(-1.5, Some(3)), "-1.500");
+        let context = Context::new();
+        let module = Module::new("test", &context);
+        let engine = JitEngine::new(&module, JitOptions {opt_level: 0}).unwrap();
+        let mut session = Session::new(&context, &engine, 0);
+        let mut output = Vec::new();
+        repl(&mut session, ".precision 2\n1/3\n.precision x\n.precision\n1/4\n".as_bytes(), &mut output);
+        assert_eq!(String::from_utf8(output).unwrap(),
+                   "> Showing results with 2 decimal places\n> 0.33\n\
+                    > Expected a number of decimal places, found x\n\
+                    > Showing results in full\n> 0.25\n> ");
+    }
+    #[test]
+    fn test_line_continuation() {
+        let mut buffer = String::new();
+        assert!(!add_line(&mut buffer, "1 + \\\n"));
+        assert!(add_line(&mut buffer, "2\n"));
+        assert_eq!(buffer, "1 + 2\n");
+        let mut buffer = String::new();
+        assert!(!add_line(&mut buffer, "def f(x) \\\r\n"));
+        assert!(add_line(&mut buffer, "x"));
+        assert_eq!(buffer, "def f(x) x");
+        let context = Context::new();
+        let module = Module::new("test", &context);
+        let engine = JitEngine::new(&module, JitOptions {opt_level: 0}).unwrap();
+        let mut session = Session::new(&context, &engine, 0);
+        let mut output = Vec::new();
+        repl(&mut session, "1 + \\\n2\n".as_bytes(), &mut output);
+        assert_eq!(String::from_utf8(output).unwrap(), "> ... 3\n> ");
+    }
+    #[test]
+    fn test_exit() {
+        let context = Context::new();
+        let module = Module::new("test", &context);
+        let engine = JitEngine::new(&module, JitOptions {opt_level: 0}).unwrap();
+        let mut session = Session::new(&context, &engine, 0);
+        for input in &["exit\n1\n", "exit\r\n1\n", "  exit  \n1\n", "\r\n \nexit"] {
+            let mut output = Vec::new();
+            repl(&mut session, input.as_bytes(), &mut output);
+            assert!(!String::from_utf8(output).unwrap().contains('1'), "{:?} didn't quit", input);
+        }
+    }
+    #[test]
+    fn test_zero_argument_functions() {
+        let context = Context::new();
+        let module = Module::new("test", &context);
+        let engine = JitEngine::new(&module, JitOptions {opt_level: 0}).unwrap();
+        let mut session = Session::new(&context, &engine, 0);
+        assert_eq!(session.eval("def answer() 42"), Ok(None));
+        assert_eq!(session.eval("answer()"), Ok(Some(42.0)));
+        // Called from a function in another module
+        assert_eq!(session.eval("def twice() answer() + answer()"), Ok(None));
+        assert_eq!(session.eval("twice() + 1"), Ok(Some(85.0)));
+        assert_eq!(session.eval("answer(1)"), Err(CompileError::ArityMismatch {
+            name: String::from("answer"),
+            expected: 0,
+            found: 1,
+        }));
+    }
+    #[test]
+    fn test_step_limit() {
+        let context = Context::new();
+        let module = Module::new("test", &context);
+        let engine = JitEngine::new(&module, JitOptions {opt_level: 0}).unwrap();
+        let mut session = Session::new(&context, &engine, 0);
+        session.set_step_limit(Some(10000));
+        session.eval("def forever(x) forever(x) + 1").unwrap();
+        assert_eq!(session.eval("forever(1)"), Err(CompileError::OutOfSteps(10000)));
+        assert_eq!(session.eval("while 1 in 0"), Err(CompileError::OutOfSteps(10000)));
+        assert_eq!(session.eval("for i = 0, 1 in forever(i)"), Err(CompileError::OutOfSteps(10000)));
+        // Code that finishes in time isn't affected
+        session.eval("def fib(x) if x < 2 then x else fib(x-1) + fib(x-2)").unwrap();
+        assert_eq!(session.eval("fib(10)"), Ok(Some(55.0)));
+        assert_eq!(session.eval("var n = 0 in (for i = 0, i < 100 in n = n + 1); n"), Ok(Some(100.0)));
+        // The limit starts over for every expression
+        assert_eq!(session.eval("fib(15)"), Ok(Some(610.0)));
+    }
+    #[test]
+    fn test_removing_the_step_limit() {
+        let context = Context::new();
+        let module = Module::new("test", &context);
+        let engine = JitEngine::new(&module, JitOptions {opt_level: 0}).unwrap();
+        let mut session = Session::new(&context, &engine, 0);
+        session.set_step_limit(Some(100));
+        // Compiled with a guard, which keeps counting steps without a limit
+        session.eval("def fib(x) if x < 2 then x else fib(x-1) + fib(x-2)").unwrap();
+        assert_eq!(session.eval("fib(15)"), Err(CompileError::OutOfSteps(100)));
+        session.set_step_limit(None);
+        assert_eq!(session.eval("fib(15)"), Ok(Some(610.0)));
+        session.set_step_limit(Some(100));
+        assert_eq!(session.eval("fib(15)"), Err(CompileError::OutOfSteps(100)));
+        assert_eq!(session.eval("fib(5)"), Ok(Some(5.0)));
+    }
+    #[test]
+    fn test_blocks() {
+        let context = Context::new();
+        let module = Module::new("test", &context);
+        let engine = JitEngine::new(&module, JitOptions {opt_level: 0}).unwrap();
+        let mut session = Session::new(&context, &engine, 0);
+        assert_eq!(session.eval("{1; 2; 3}"), Ok(Some(3.0)));
+        session.eval("def f(x) if x > 0 then { x = x * 2; x + 1 } else { 0 }").unwrap();
+        assert_eq!(session.eval("f(3)"), Ok(Some(7.0)));
+        assert_eq!(session.eval("f(-3)"), Ok(Some(0.0)));
+    }
+    #[test]
+    fn test_functions_as_values() {
+        let context = Context::new();
+        let module = Module::new("test", &context);
+        let engine = JitEngine::new(&module, JitOptions {opt_level: 0}).unwrap();
+        let mut session = Session::new(&context, &engine, 0);
+        session.eval("def apply(f x) f(x)").unwrap();
+        session.eval("def double(x) x * 2").unwrap();
+        assert_eq!(session.eval("apply(double, 21)"), Ok(Some(42.0)));
+        session.eval("extern cos(x)").unwrap();
+        assert_eq!(session.eval("apply(cos, 0)"), Ok(Some(1.0)));
+        session.eval("def fold(f a b c) f(f(a, b), c)").unwrap();
+        session.eval("def add(a b) a + b").unwrap();
+        assert_eq!(session.eval("fold(add, 1, 2, 3)"), Ok(Some(6.0)));
+        // A function can also be stored in a variable first
+        assert_eq!(session.eval("var g = double in g(4) + apply(g, 1)"), Ok(Some(10.0)));
+    }
+    #[test]
+    fn test_recursion() {
+        let context = Context::new();
+        let module = Module::new("test", &context);
+        let engine = JitEngine::new(&module, JitOptions {opt_level: 0}).unwrap();
+        let mut session = Session::new(&context, &engine, 0);
+        session.eval("def fib(n) if n < 2 then n else fib(n-1) + fib(n-2)").unwrap();
+        assert_eq!(session.eval("fib(10)"), Ok(Some(55.0)));
+        assert_eq!(session.eval("fib(1) + fib(0)"), Ok(Some(1.0)));
+        session.eval("def fact(n) if n <= 1 then 1 else n * fact(n - 1)").unwrap();
+        assert_eq!(session.eval("fact(5)"), Ok(Some(120.0)));
+    }
+    #[test]
+    fn test_tail_recursion() {
+        let context = Context::new();
+        let module = Module::new("test", &context);
+        let engine = JitEngine::new(&module, JitOptions {opt_level: 1}).unwrap();
+        let mut session = Session::new(&context, &engine, 1);
+        session.eval("def sum(n total) if n < 1 then total else sum(n - 1, total + n)").unwrap();
+        // Far too deep for the stack if every call got its own frame
+        assert_eq!(session.eval("sum(10000000, 0)"), Ok(Some(50000005000000.0)));
+    }
+    #[test]
+    fn test_reset() {
+        let context = Context::new();
+        let module = Module::new("test", &context);
+        let engine = JitEngine::new(&module, JitOptions {opt_level: 0}).unwrap();
+        let mut session = Session::new(&context, &engine, 0);
+        session.eval("def f(x) x + 1").unwrap();
+        session.eval("def binary| 5 (a b) a").unwrap();
+        assert_eq!(session.command(".reset"), "Forgot all definitions");
+        assert_eq!(session.eval("f(1)"), Err(CompileError::UnknownFunction(String::from("f"))));
+        assert_eq!(session.command(".dump"), "");
+        // The operator is gone too, so the | is left over
+        assert!(session.eval("1 | 2").is_err());
+        // Everything can be defined again from scratch
+        session.eval("def f(x) x + 2").unwrap();
+        assert_eq!(session.eval("f(1)"), Ok(Some(3.0)));
+    }
+    #[test]
+    fn test_jit_eval() {
+        assert_eq!(jit_eval("x * y + 1", &[("x", 3.0), ("y", 4.0)]), Ok(13.0));
+        assert_eq!(jit_eval("if x then 1 else 2", &[("x", 0.0)]), Ok(2.0));
+        assert_eq!(jit_eval("4 / 2", &[]), Ok(2.0));
+        assert_eq!(jit_eval("x + z", &[("x", 1.0)]), Err(CompileError::UnknownVariable(String::from("z"))));
+        assert_eq!(jit_eval("x +", &[("x", 1.0)]), Err(CompileError::UnexpectedEof(lexer::Span { line: 1, col: 4 })));
+    }
+    #[test]
+    fn test_emit_ir() {
+        let source = "def double(x) x+x\ndouble(2)";
+        let mut output = Vec::new();
+        emit(source, 0, &OutputMode::Ir, &mut output).unwrap();
+        let ir = String::from_utf8(output).unwrap();
+        assert!(ir.contains("define double @double(double"));
+        assert!(ir.contains("@__top_level_expr0"));
+        let mut output = Vec::new();
+        emit(source, 0, &OutputMode::Jit, &mut output).unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), "4\n");
+        let mut output = Vec::new();
+        assert_eq!(emit("def f(x) y", 0, &OutputMode::Ir, &mut output),
+                   Err(CompileError::UnknownVariable(String::from("y"))));
+        assert!(output.is_empty());
+    }
+    #[test]
+    fn test_emit_bitcode() {
+        use std::env;
+        use std::fs::File;
+        let path = env::temp_dir().join("kaleidoscope_emit_test.bc");
+        let mut output = Vec::new();
+        emit("def double(x) x+x", 0, &OutputMode::Bitcode(path.clone()), &mut output).unwrap();
+        assert!(output.is_empty());
+        let mut bytes = Vec::new();
+        File::open(&path).unwrap().read_to_end(&mut bytes).unwrap();
+        assert_eq!(&bytes[..4], b"BC\xc0\xde");
     }
 }
@@ -1,5 +1,6 @@
 //! This module contains the lexer for Kaleidoscope.
 
+use std::fmt;
 // The lexer will be implemented as an iterator, so we need to use the Iterator trait.
 use std::iter::Iterator;
 use std::iter::Peekable;
@@ -9,11 +10,22 @@ use std::str::Chars;
 /// All the different tokens that the lexer can return.
 ///
 // Using Rust enums instead of integers is much safer and more readable.
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub enum Token {
     // Commands
     Define,
     Extern,
+    // Operator definitions
+    Binary,
+    Unary,
+    // Control flow
+    If,
+    Then,
+    Else,
+    For,
+    While,
+    In,
+    Var,
     /// An Identifier contains the identifier as a String.
     /// This is much safer and easier to manage than using global variables.
     Identifier(String),
@@ -21,8 +33,136 @@ pub enum Token {
     /// We store the number in the variant istead of in a global variable
     /// for the same reasons as Identifier.
     Number(f64),
+    /// Integer literals are written with an `i` suffix, like `42i`, and are 64 bit signed ints.
+    Integer(i64),
+    /// A double quoted string literal, with its escapes already replaced.
+    Str(String),
+    /// Operators made of more than one character, like `<=`, `==` or `&&`.
+    /// Single character operators are still returned as UnknownChar.
+    Op(String),
+    /// A single `=`, which assigns. Comparing with `==` is an Op instead.
+    Assign,
     /// UnknownChar corresponds to returning a positive integer from gettok.
     UnknownChar(char),
+    /// Input that can't be turned into a token. The lexer keeps going after
+    /// returning one of these, so it's up to the parser to report it.
+    Error(LexError),
+    /// The text of a `#` comment after the `#`. These are only returned when
+    /// `keep_comments` is set, which the parser doesn't expect.
+    Comment(String),
+    /// The end of the input. The lexer returns exactly one of these, then None.
+    Eof,
+}
+
+/// Describes the token the way an error message would, like `'+'` or `number 1`.
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Token::Define => write!(f, "'def'"),
+            Token::Extern => write!(f, "'extern'"),
+            Token::Binary => write!(f, "'binary'"),
+            Token::Unary => write!(f, "'unary'"),
+            Token::If => write!(f, "'if'"),
+            Token::Then => write!(f, "'then'"),
+            Token::Else => write!(f, "'else'"),
+            Token::For => write!(f, "'for'"),
+            Token::While => write!(f, "'while'"),
+            Token::In => write!(f, "'in'"),
+            Token::Var => write!(f, "'var'"),
+            Token::Identifier(ref name) => write!(f, "identifier {}", name),
+            Token::Number(n) => write!(f, "number {}", n),
+            Token::Integer(n) => write!(f, "integer {}", n),
+            Token::Str(ref string) => write!(f, "string {:?}", string),
+            Token::Op(ref op) => write!(f, "'{}'", op),
+            Token::Assign => write!(f, "'='"),
+            Token::UnknownChar(c) => write!(f, "'{}'", c),
+            Token::Error(ref err) => write!(f, "{}", err),
+            Token::Comment(_) => write!(f, "comment"),
+            Token::Eof => write!(f, "end of input"),
+        }
+    }
+}
+
+/// Everything that can go wrong while lexing.
+#[derive(Debug, PartialEq, Clone)]
+pub enum LexError {
+    /// A number literal that isn't a valid float, like `1.2.3`.
+    MalformedNumber(String),
+    /// A block comment that is still open at the end of the input.
+    UnterminatedComment,
+    /// A string literal that is still open at the end of the input.
+    UnterminatedString,
+    /// A backslash in a string literal followed by something other than `n`, `t`, `\\` or `"`,
+    /// or in a character literal followed by something other than `n`, `t`, `\\` or `'`.
+    InvalidEscape(char),
+    /// A character literal that is still open at the end of the line.
+    UnterminatedChar,
+    /// A character literal that doesn't hold exactly one character, like `''` or `'ab'`.
+    InvalidChar(String),
+    /// A number literal too big to fit in a double, like `1e400`.
+    NumberOverflow(String),
+}
+
+impl fmt::Display for LexError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LexError::MalformedNumber(ref num) => write!(f, "Malformed number {}", num),
+            LexError::UnterminatedComment => write!(f, "Unterminated block comment"),
+            LexError::UnterminatedString => write!(f, "Unterminated string literal"),
+            LexError::InvalidEscape(c) => write!(f, "Invalid escape sequence \\{}", c),
+            LexError::UnterminatedChar => write!(f, "Unterminated character literal"),
+            LexError::InvalidChar(ref literal) =>
+                write!(f, "Character literal '{}' must hold exactly one character", literal),
+            LexError::NumberOverflow(ref num) => write!(f, "Number {} is too big to fit in a double", num),
+        }
+    }
+}
+
+/// A position in the source code. Lines and columns both start at 1.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Span {
+    pub line: u32,
+    pub col: u32,
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}, column {}", self.line, self.col)
+    }
+}
+
+/// Renders the line of `source` that `span` is on, with a caret under its column.
+///
+/// ```text
+/// def foo(x) x +
+///              ^
+/// ```
+pub fn snippet(source: &str, span: Span) -> String {
+    let line = source.lines().nth(span.line as usize - 1).unwrap_or("");
+    // Tabs are copied into the padding so the caret lines up however wide they are
+    let padding: String = line.chars()
+        .take(span.col as usize - 1)
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect();
+    format!("{}\n{}^", line, padding)
+}
+
+/// A token along with the position of its first character.
+#[derive(Debug, PartialEq, Clone)]
+pub struct SpannedToken {
+    pub token: Token,
+    pub span: Span,
+    /// The position just past its last character.
+    pub end: Span,
+}
+
+/// Settings for a `Lexer`. The defaults are what the parser expects.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct LexerOptions {
+    /// Return comments as `Token::Comment` instead of skipping them.
+    pub keep_comments: bool,
+    /// Let number literals like `1e400` become infinity instead of an error.
+    pub allow_infinite_numbers: bool,
 }
 
 /// The lexer is implemented as a struct that holds its state instead of a
@@ -31,87 +171,348 @@ pub enum Token {
 pub struct Lexer<'a> {
     chars: Peekable<Chars<'a>>,
     source: &'a str,
+    // The position of the next char that will be consumed
+    line: u32,
+    col: u32,
+    offset: usize,
+    // The position of the first char of the token being lexed
+    start: Span,
+    // The position just past the last token returned
+    end: Span,
+    // Whether Eof has been returned yet
+    finished: bool,
+    options: LexerOptions,
 }
 
 impl<'a> Lexer<'a> {
     /// The constructor for Lexer.
     pub fn new(source: &'a str) -> Lexer<'a> {
+        Lexer::with_options(source, LexerOptions::default())
+    }
+    pub fn with_options(source: &'a str, options: LexerOptions) -> Lexer<'a> {
         Lexer {
             chars: source.chars().peekable(),
             source: source,
+            line: 1,
+            col: 1,
+            offset: 0,
+            start: Span { line: 1, col: 1 },
+            end: Span { line: 1, col: 1 },
+            finished: false,
+            options: options,
         }
     }
-}
-
-impl<'a> Iterator for Lexer<'a> {
-    // We will be iterating over Tokens
-    type Item = Token;
-
-    fn next(&mut self) -> Option<Token> {
-        let mut next = self.chars.next();
+    /// The position of the next char that will be consumed.
+    pub fn location(&self) -> Span {
+        Span {
+            line: self.line,
+            col: self.col,
+        }
+    }
+    /// The byte offset into the source of the next char that will be consumed.
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+    /// All of the source being lexed, including what has already been consumed.
+    pub fn source(&self) -> &'a str {
+        self.source
+    }
+    /// The position just past the last char of the last token returned.
+    ///
+    /// Unlike `location` this doesn't include any whitespace or comments after the token.
+    pub fn end(&self) -> Span {
+        self.end
+    }
+    /// Consumes the next char, keeping the line and column up to date.
+    fn bump(&mut self) -> Option<char> {
+        let next = self.chars.next();
+        if let Some(c) = next {
+            self.offset += c.len_utf8();
+        }
+        match next {
+            Some('\n') => {
+                self.line += 1;
+                self.col = 1;
+            },
+            Some(_) => self.col += 1,
+            None => (),
+        }
+        next
+    }
+    /// Lexes a number literal whose first char has already been consumed.
+    ///
+    /// A number is digits with at most one decimal point, optionally followed
+    /// by an exponent like `e10` or `E-3`. Digits followed by an `i` are an integer.
+    /// Underscores can be used to separate digits, as in `1_000_000`.
+    fn lex_number(&mut self, first: char) -> Token {
+        let mut num = String::new();
+        num.push(first);
+        let mut seen_point = first == '.';
+        let mut seen_exponent = false;
+        let mut malformed = false;
+        loop {
+            // We create a new block so that x will be out of scope when
+            // self.bump() is called.
+            // This avoids a multiple mutable reference error
+            {
+                let x = self.chars.peek();
+                match x {
+                    Some(c) if c.is_digit(10) => num.push(*c),
+                    // We keep eating after a second decimal point so that
+                    // the whole literal ends up in the error.
+                    Some(&'.') => {
+                        malformed = malformed || seen_point || num.ends_with('_');
+                        seen_point = true;
+                        num.push('.');
+                    },
+                    // Separators have to come right after a digit
+                    Some(&'_') => {
+                        malformed = malformed || !num.ends_with(|c: char| c.is_digit(10));
+                        num.push('_');
+                    },
+                    _ => break,
+                }
+            };
+            self.bump();
+        }
+        // ...and right before one
+        malformed = malformed || num.ends_with('_');
+        if let Some(&e) = self.chars.peek() {
+            if e == 'e' || e == 'E' {
+                seen_exponent = true;
+                num.push(e);
+                self.bump();
+                if let Some(&sign) = self.chars.peek() {
+                    if sign == '+' || sign == '-' {
+                        num.push(sign);
+                        self.bump();
+                    }
+                }
+                let mut exponent_digits = 0;
+                while let Some(&d) = self.chars.peek() {
+                    if !d.is_digit(10) {
+                        break;
+                    }
+                    num.push(d);
+                    exponent_digits += 1;
+                    self.bump();
+                }
+                malformed = malformed || exponent_digits == 0;
+            }
+        }
+        if self.chars.peek() == Some(&'i') {
+            self.bump();
+            return match num.replace('_', "").parse() {
+                Ok(n) if !malformed && !seen_point && !seen_exponent => Token::Integer(n),
+                _ => {
+                    num.push('i');
+                    Token::Error(LexError::MalformedNumber(num))
+                }
+            }
+        }
+        match num.replace('_', "").parse::<f64>() {
+            Ok(n) if !malformed && !n.is_finite() && !self.options.allow_infinite_numbers =>
+                Token::Error(LexError::NumberOverflow(num)),
+            Ok(n) if !malformed => Token::Number(n),
+            _ => Token::Error(LexError::MalformedNumber(num)),
+        }
+    }
+    /// Lexes a hexadecimal or binary literal like `0x1F` or `0b1010`, whose
+    /// `0x` or `0b` prefix has already been consumed.
+    ///
+    /// These are numbers like any other, so they can be separated with
+    /// underscores and end with an `i` to make an integer.
+    fn lex_radix_number(&mut self, prefix: char, radix: u32) -> Token {
+        let mut num = String::from("0");
+        num.push(prefix);
+        // Eat anything that looks like part of the literal, so that `0xG`
+        // is one malformed number instead of a number and an identifier.
+        while let Some(&c) = self.chars.peek() {
+            if !c.is_alphanumeric() && c != '_' {
+                break;
+            }
+            num.push(c);
+            self.bump();
+        }
+        let integer = num.ends_with('i');
+        let digits = if integer { &num[2..num.len() - 1] } else { &num[2..] };
+        let valid = !digits.is_empty() && !digits.starts_with('_') && !digits.ends_with('_') &&
+            !digits.contains("__") && digits.chars().all(|c| c == '_' || c.is_digit(radix));
+        match i64::from_str_radix(&digits.replace('_', ""), radix) {
+            Ok(n) if valid && integer => Token::Integer(n),
+            Ok(n) if valid => Token::Number(n as f64),
+            _ => Token::Error(LexError::MalformedNumber(num.clone())),
+        }
+    }
+    /// Lexes a string literal whose opening quote has already been consumed.
+    fn lex_string(&mut self) -> Token {
+        let mut string = String::new();
+        // An invalid escape is only reported once the closing quote has been
+        // found, so the rest of the string doesn't get lexed as code.
+        let mut invalid_escape = None;
+        loop {
+            match self.bump() {
+                Some('"') => break,
+                Some('\\') => match self.bump() {
+                    Some('n') => string.push('\n'),
+                    Some('t') => string.push('\t'),
+                    Some('\\') => string.push('\\'),
+                    Some('"') => string.push('"'),
+                    Some(c) => invalid_escape = invalid_escape.or(Some(c)),
+                    None => return Token::Error(LexError::UnterminatedString),
+                },
+                Some(c) => string.push(c),
+                None => return Token::Error(LexError::UnterminatedString),
+            }
+        }
+        match invalid_escape {
+            Some(c) => Token::Error(LexError::InvalidEscape(c)),
+            None => Token::Str(string),
+        }
+    }
+    /// Lexes a character literal like `'A'` after the opening quote. Since
+    /// everything is a double, it becomes the number of the character.
+    fn lex_char(&mut self) -> Token {
+        let mut chars = Vec::new();
+        let mut literal = String::new();
+        // Like in strings, an invalid escape is reported at the closing quote
+        let mut invalid_escape = None;
+        loop {
+            match self.bump() {
+                Some('\'') => break,
+                Some('\\') => {
+                    let c = match self.bump() {
+                        Some('n') => '\n',
+                        Some('t') => '\t',
+                        Some('\\') => '\\',
+                        Some('\'') => '\'',
+                        Some(c) if c != '\n' => {
+                            invalid_escape = invalid_escape.or(Some(c));
+                            c
+                        },
+                        _ => return Token::Error(LexError::UnterminatedChar),
+                    };
+                    literal.push('\\');
+                    literal.push(c);
+                    chars.push(c);
+                },
+                // Unlike strings, character literals can't span lines
+                Some('\n') | None => return Token::Error(LexError::UnterminatedChar),
+                Some(c) => {
+                    literal.push(c);
+                    chars.push(c);
+                },
+            }
+        }
+        if let Some(c) = invalid_escape {
+            return Token::Error(LexError::InvalidEscape(c))
+        }
+        if chars.len() != 1 {
+            return Token::Error(LexError::InvalidChar(literal))
+        }
+        Token::Number(chars[0] as u32 as f64)
+    }
+    fn next_token(&mut self) -> Option<Token> {
+        self.start = self.location();
+        let mut next = self.bump();
         while let Some(c) = next {
             if !c.is_whitespace() {
                 break;
             }
-            next = self.chars.next();
+            self.start = self.location();
+            next = self.bump();
         }
         if let Some(c) = next {
-            if c.is_alphabetic() {
+            if c.is_alphabetic() || c == '_' {
                 let mut identifier = String::new();
                 identifier.push(c);
                 loop {
                     // We create a new block so that x will be out of scope when
-                    // self.chars.next() is called.
+                    // self.bump() is called.
                     // This avoids a multiple mutable reference error
                     {
                         let x = self.chars.peek();
                         match x {
-                            Some(c) if c.is_alphanumeric() => identifier.push(*c),
+                            Some(c) if c.is_alphanumeric() || *c == '_' => identifier.push(*c),
                             _ => break,
                         }
                     };
-                    self.chars.next();
+                    self.bump();
                 }
-                if identifier == "def" {
-                    Some(Token::Define)
-                } else if identifier == "extern" {
-                    Some(Token::Extern)
-                } else {
-                    Some(Token::Identifier(identifier))
+                match identifier.as_str() {
+                    "def" => Some(Token::Define),
+                    "extern" => Some(Token::Extern),
+                    "binary" => Some(Token::Binary),
+                    "unary" => Some(Token::Unary),
+                    "if" => Some(Token::If),
+                    "then" => Some(Token::Then),
+                    "else" => Some(Token::Else),
+                    "for" => Some(Token::For),
+                    "while" => Some(Token::While),
+                    "in" => Some(Token::In),
+                    "var" => Some(Token::Var),
+                    _ => Some(Token::Identifier(identifier)),
                 }
+            } else if c == '0' && (self.chars.peek() == Some(&'x') || self.chars.peek() == Some(&'b')) {
+                let prefix = self.bump().unwrap();
+                Some(self.lex_radix_number(prefix, if prefix == 'x' { 16 } else { 2 }))
+            } else if c == '.' && self.source[self.offset..].starts_with("..") {
+                // The `...` that marks a prototype as taking any number of extra arguments
+                self.bump();
+                self.bump();
+                Some(Token::Op(String::from("...")))
             } else if c.is_digit(10) || c == '.' {
-                let mut num = String::new();
-                num.push(c);
+                Some(self.lex_number(c))
+            } else if c == '"' {
+                Some(self.lex_string())
+            } else if c == '\'' {
+                Some(self.lex_char())
+            } else if c == '#' {
+                let mut comment = String::new();
                 loop {
                     // We create a new block so that x will be out of scope when
-                    // self.chars.next() is called.
+                    // self.bump() is called.
                     // This avoids a multiple mutable reference error
                     {
                         let x = self.chars.peek();
                         match x {
-                            Some(c) if c.is_digit(10) || *c == '.' => num.push(*c),
+                            Some(c) if *c != '\r' && *c != '\n' => comment.push(*c),
                             _ => break,
                         }
                     };
-                    self.chars.next();
+                    self.bump();
                 }
-                Some(Token::Number(num.parse().expect("Could not parse number!")))
-            } else if c == '#' {
+                if self.options.keep_comments {
+                    Some(Token::Comment(comment))
+                } else {
+                    self.next_token()
+                }
+            } else if c == '/' && self.chars.peek() == Some(&'*') {
+                // Block comments don't nest, so the first */ always ends the comment.
+                self.bump();
+                let mut last = None;
                 loop {
-                    // We create a new block so that x will be out of scope when
-                    // self.chars.next() is called.
-                    // This avoids a multiple mutable reference error
-                    {
-                        let x = self.chars.peek();
-                        match x {
-                            // Just eat the chars
-                            Some(c) if *c != '\r' && *c != '\n' => {},
-                            _ => break,
-                        }
-                    };
-                    self.chars.next();
+                    match self.bump() {
+                        Some('/') if last == Some('*') => break,
+                        Some(c) => last = Some(c),
+                        None => return Some(Token::Error(LexError::UnterminatedComment)),
+                    }
                 }
-                self.next()
+                self.next_token()
+            } else if (c == '<' || c == '>' || c == '=' || c == '!') && self.chars.peek() == Some(&'=') {
+                self.bump();
+                let mut op = String::new();
+                op.push(c);
+                op.push('=');
+                Some(Token::Op(op))
+            } else if c == '=' {
+                Some(Token::Assign)
+            } else if (c == '&' || c == '|') && self.chars.peek() == Some(&c) {
+                self.bump();
+                let mut op = String::new();
+                op.push(c);
+                op.push(c);
+                Some(Token::Op(op))
             } else {
                 Some(Token::UnknownChar(c))
             }
@@ -121,6 +522,47 @@ impl<'a> Iterator for Lexer<'a> {
     }
 }
 
+impl<'a> Iterator for Lexer<'a> {
+    // We will be iterating over Tokens along with where they start
+    type Item = SpannedToken;
+
+    fn next(&mut self) -> Option<SpannedToken> {
+        match self.next_token() {
+            Some(token) => {
+                self.end = self.location();
+                Some(SpannedToken {
+                    token: token,
+                    span: self.start,
+                    end: self.end,
+                })
+            },
+            None if !self.finished => {
+                self.finished = true;
+                Some(SpannedToken {
+                    token: Token::Eof,
+                    span: self.end,
+                    end: self.end,
+                })
+            },
+            None => None,
+        }
+    }
+}
+
+/// Lexes all of `source` at once. Any errors are left in the output as `Token::Error`.
+/// The final `Token::Eof` isn't included.
+pub fn tokenize(source: &str) -> Vec<Token> {
+    Lexer::new(source).map(|t| t.token).filter(|token| *token != Token::Eof).collect()
+}
+
+/// Like `tokenize`, but returns the first lexing error instead of the tokens if there is one.
+pub fn tokenize_checked(source: &str) -> Result<Vec<Token>, LexError> {
+    Lexer::new(source).map(|t| t.token).filter(|token| *token != Token::Eof).map(|token| match token {
+        Token::Error(err) => Err(err),
+        token => Ok(token),
+    }).collect()
+}
+
 // Some tests for the lexer
 #[cfg(test)]
 mod tests {
@@ -128,22 +570,22 @@ mod tests {
     #[test]
     fn test_simple_tokens_and_value() {
         let mut lexer = Lexer::new("1 + 1 - foo");
-        assert_eq!(lexer.next().unwrap(), Token::Number(1.0));
-        assert_eq!(lexer.next().unwrap(), Token::UnknownChar('+'));
-        assert_eq!(lexer.next().unwrap(), Token::Number(1.0));
-        assert_eq!(lexer.next().unwrap(), Token::UnknownChar('-'));
-        assert_eq!(lexer.next().unwrap(), Token::Identifier(String::from("foo")));
-        assert_eq!(lexer.next(), None);
+        assert_eq!(lexer.next().unwrap().token, Token::Number(1.0));
+        assert_eq!(lexer.next().unwrap().token, Token::UnknownChar('+'));
+        assert_eq!(lexer.next().unwrap().token, Token::Number(1.0));
+        assert_eq!(lexer.next().unwrap().token, Token::UnknownChar('-'));
+        assert_eq!(lexer.next().unwrap().token, Token::Identifier(String::from("foo")));
+        assert_eq!(lexer.next().map(|t| t.token), Some(Token::Eof));
     }
     #[test]
     fn test_simple_tokens_and_value_no_whitespace() {
         let mut lexer = Lexer::new("1+1-foo");
-        assert_eq!(lexer.next().unwrap(), Token::Number(1.0));
-        assert_eq!(lexer.next().unwrap(), Token::UnknownChar('+'));
-        assert_eq!(lexer.next().unwrap(), Token::Number(1.0));
-        assert_eq!(lexer.next().unwrap(), Token::UnknownChar('-'));
-        assert_eq!(lexer.next().unwrap(), Token::Identifier(String::from("foo")));
-        assert_eq!(lexer.next(), None);
+        assert_eq!(lexer.next().unwrap().token, Token::Number(1.0));
+        assert_eq!(lexer.next().unwrap().token, Token::UnknownChar('+'));
+        assert_eq!(lexer.next().unwrap().token, Token::Number(1.0));
+        assert_eq!(lexer.next().unwrap().token, Token::UnknownChar('-'));
+        assert_eq!(lexer.next().unwrap().token, Token::Identifier(String::from("foo")));
+        assert_eq!(lexer.next().map(|t| t.token), Some(Token::Eof));
     }
     #[test]
     fn test_comments() {
@@ -151,9 +593,294 @@ mod tests {
         1 + 2 # <- is code
         # this is not";
         let mut lexer = Lexer::new(code);
-        assert_eq!(lexer.next(), Some(Token::Number(1.0)));
-        assert_eq!(lexer.next(), Some(Token::UnknownChar('+')));
-        assert_eq!(lexer.next(), Some(Token::Number(2.0)));
+        assert_eq!(lexer.next().map(|t| t.token), Some(Token::Number(1.0)));
+        assert_eq!(lexer.next().map(|t| t.token), Some(Token::UnknownChar('+')));
+        assert_eq!(lexer.next().map(|t| t.token), Some(Token::Number(2.0)));
+        assert_eq!(lexer.next().map(|t| t.token), Some(Token::Eof));
+    }
+    #[test]
+    fn test_comment_at_end_of_input() {
+        let mut lexer = Lexer::new("1 # trailing");
+        assert_eq!(lexer.next().map(|t| t.token), Some(Token::Number(1.0)));
+        assert_eq!(lexer.next().map(|t| t.token), Some(Token::Eof));
         assert_eq!(lexer.next(), None);
+        assert_eq!(tokenize("1 # trailing"), vec![Token::Number(1.0)]);
+        assert_eq!(tokenize("#"), vec![]);
+        assert_eq!(tokenize("1 #\r\n2"), vec![Token::Number(1.0), Token::Number(2.0)]);
+    }
+    #[test]
+    fn test_if_then_else_keywords() {
+        let mut lexer = Lexer::new("if x then 1 else 2");
+        assert_eq!(lexer.next().map(|t| t.token), Some(Token::If));
+        assert_eq!(lexer.next().map(|t| t.token), Some(Token::Identifier(String::from("x"))));
+        assert_eq!(lexer.next().map(|t| t.token), Some(Token::Then));
+        assert_eq!(lexer.next().map(|t| t.token), Some(Token::Number(1.0)));
+        assert_eq!(lexer.next().map(|t| t.token), Some(Token::Else));
+        assert_eq!(lexer.next().map(|t| t.token), Some(Token::Number(2.0)));
+        assert_eq!(lexer.next().map(|t| t.token), Some(Token::Eof));
+    }
+    #[test]
+    fn test_keyword_prefixed_identifiers() {
+        let mut lexer = Lexer::new("iffy thenable elsewhere");
+        assert_eq!(lexer.next().map(|t| t.token), Some(Token::Identifier(String::from("iffy"))));
+        assert_eq!(lexer.next().map(|t| t.token), Some(Token::Identifier(String::from("thenable"))));
+        assert_eq!(lexer.next().map(|t| t.token), Some(Token::Identifier(String::from("elsewhere"))));
+        assert_eq!(lexer.next().map(|t| t.token), Some(Token::Eof));
+    }
+    #[test]
+    fn test_for_in_keywords() {
+        let mut lexer = Lexer::new("for i in inner");
+        assert_eq!(lexer.next().map(|t| t.token), Some(Token::For));
+        assert_eq!(lexer.next().map(|t| t.token), Some(Token::Identifier(String::from("i"))));
+        assert_eq!(lexer.next().map(|t| t.token), Some(Token::In));
+        assert_eq!(lexer.next().map(|t| t.token), Some(Token::Identifier(String::from("inner"))));
+        assert_eq!(lexer.next().map(|t| t.token), Some(Token::Eof));
+    }
+    #[test]
+    fn test_multi_char_operators() {
+        let mut lexer = Lexer::new("a <= b >= c == d != e < f = g");
+        assert_eq!(lexer.next().map(|t| t.token), Some(Token::Identifier(String::from("a"))));
+        assert_eq!(lexer.next().map(|t| t.token), Some(Token::Op(String::from("<="))));
+        assert_eq!(lexer.next().map(|t| t.token), Some(Token::Identifier(String::from("b"))));
+        assert_eq!(lexer.next().map(|t| t.token), Some(Token::Op(String::from(">="))));
+        assert_eq!(lexer.next().map(|t| t.token), Some(Token::Identifier(String::from("c"))));
+        assert_eq!(lexer.next().map(|t| t.token), Some(Token::Op(String::from("=="))));
+        assert_eq!(lexer.next().map(|t| t.token), Some(Token::Identifier(String::from("d"))));
+        assert_eq!(lexer.next().map(|t| t.token), Some(Token::Op(String::from("!="))));
+        assert_eq!(lexer.next().map(|t| t.token), Some(Token::Identifier(String::from("e"))));
+        assert_eq!(lexer.next().map(|t| t.token), Some(Token::UnknownChar('<')));
+        assert_eq!(lexer.next().map(|t| t.token), Some(Token::Identifier(String::from("f"))));
+        assert_eq!(lexer.next().map(|t| t.token), Some(Token::Assign));
+        assert_eq!(lexer.next().map(|t| t.token), Some(Token::Identifier(String::from("g"))));
+        assert_eq!(lexer.next().map(|t| t.token), Some(Token::Eof));
+    }
+    #[test]
+    fn test_token_spans() {
+        let mut lexer = Lexer::new("1 +\n 2");
+        assert_eq!(lexer.next().unwrap().span, Span { line: 1, col: 1 });
+        let plus = lexer.next().unwrap();
+        assert_eq!(plus.token, Token::UnknownChar('+'));
+        assert_eq!(plus.span, Span { line: 1, col: 3 });
+        assert_eq!(plus.end, Span { line: 1, col: 4 });
+        assert_eq!(lexer.next().unwrap().span, Span { line: 2, col: 2 });
+        let mut lexer = Lexer::new("# comment\nfoo");
+        let foo = lexer.next().unwrap();
+        assert_eq!(foo.token, Token::Identifier(String::from("foo")));
+        assert_eq!(foo.span, Span { line: 2, col: 1 });
+        assert_eq!(foo.end, Span { line: 2, col: 4 });
+    }
+    #[test]
+    fn test_malformed_numbers() {
+        let mut lexer = Lexer::new("1.2.3 + 4");
+        assert_eq!(lexer.next().map(|t| t.token),
+                   Some(Token::Error(LexError::MalformedNumber(String::from("1.2.3")))));
+        assert_eq!(lexer.next().map(|t| t.token), Some(Token::UnknownChar('+')));
+        assert_eq!(lexer.next().map(|t| t.token), Some(Token::Number(4.0)));
+        let mut lexer = Lexer::new(".");
+        assert_eq!(lexer.next().map(|t| t.token),
+                   Some(Token::Error(LexError::MalformedNumber(String::from(".")))));
+        let mut lexer = Lexer::new("1. .5");
+        assert_eq!(lexer.next().map(|t| t.token), Some(Token::Number(1.0)));
+        assert_eq!(lexer.next().map(|t| t.token), Some(Token::Number(0.5)));
+        assert_eq!(lexer.next().map(|t| t.token), Some(Token::Eof));
+    }
+    #[test]
+    fn test_scientific_notation() {
+        let mut lexer = Lexer::new("1e3 2.5e-3 6.022E23 1e+2");
+        assert_eq!(lexer.next().map(|t| t.token), Some(Token::Number(1000.0)));
+        assert_eq!(lexer.next().map(|t| t.token), Some(Token::Number(0.0025)));
+        assert_eq!(lexer.next().map(|t| t.token), Some(Token::Number(6.022e23)));
+        assert_eq!(lexer.next().map(|t| t.token), Some(Token::Number(100.0)));
+        assert_eq!(lexer.next().map(|t| t.token), Some(Token::Eof));
+        let mut lexer = Lexer::new("2e + 1");
+        assert_eq!(lexer.next().map(|t| t.token),
+                   Some(Token::Error(LexError::MalformedNumber(String::from("2e")))));
+        assert_eq!(lexer.next().map(|t| t.token), Some(Token::UnknownChar('+')));
+        let mut lexer = Lexer::new("2e-");
+        assert_eq!(lexer.next().map(|t| t.token),
+                   Some(Token::Error(LexError::MalformedNumber(String::from("2e-")))));
+    }
+    #[test]
+    fn test_block_comments() {
+        let mut lexer = Lexer::new("1 /* comment */ + 2");
+        assert_eq!(lexer.next().map(|t| t.token), Some(Token::Number(1.0)));
+        assert_eq!(lexer.next().map(|t| t.token), Some(Token::UnknownChar('+')));
+        assert_eq!(lexer.next().map(|t| t.token), Some(Token::Number(2.0)));
+        assert_eq!(lexer.next().map(|t| t.token), Some(Token::Eof));
+        let mut lexer = Lexer::new("/* multi\nline **/ foo / 2");
+        let foo = lexer.next().unwrap();
+        assert_eq!(foo.token, Token::Identifier(String::from("foo")));
+        assert_eq!(foo.span, Span { line: 2, col: 10 });
+        assert_eq!(lexer.next().map(|t| t.token), Some(Token::UnknownChar('/')));
+        assert_eq!(lexer.next().map(|t| t.token), Some(Token::Number(2.0)));
+    }
+    #[test]
+    fn test_nested_and_unterminated_block_comments() {
+        // Comments don't nest: the first */ closes the comment.
+        let mut lexer = Lexer::new("/* a /* b */ 1 */");
+        assert_eq!(lexer.next().map(|t| t.token), Some(Token::Number(1.0)));
+        assert_eq!(lexer.next().map(|t| t.token), Some(Token::UnknownChar('*')));
+        assert_eq!(lexer.next().map(|t| t.token), Some(Token::UnknownChar('/')));
+        let mut lexer = Lexer::new("1 /* never closed *");
+        assert_eq!(lexer.next().map(|t| t.token), Some(Token::Number(1.0)));
+        assert_eq!(lexer.next().map(|t| t.token), Some(Token::Error(LexError::UnterminatedComment)));
+        assert_eq!(lexer.next().map(|t| t.token), Some(Token::Eof));
+    }
+    #[test]
+    fn test_tokenize() {
+        let got = tokenize("def foo(a) a * 2 # double it");
+        let expected = [Token::Define,
+                        Token::Identifier(String::from("foo")),
+                        Token::UnknownChar('('),
+                        Token::Identifier(String::from("a")),
+                        Token::UnknownChar(')'),
+                        Token::Identifier(String::from("a")),
+                        Token::UnknownChar('*'),
+                        Token::Number(2.0)];
+        assert_eq!(got, expected);
+        assert_eq!(tokenize_checked("def foo(a) a * 2 # double it"), Ok(expected.to_vec()));
+        assert_eq!(tokenize_checked("1 + 1.2.3"), Err(LexError::MalformedNumber(String::from("1.2.3"))));
+    }
+    #[test]
+    fn test_integer_literals() {
+        assert_eq!(tokenize("42i + 1"), vec![Token::Integer(42), Token::UnknownChar('+'), Token::Number(1.0)]);
+        assert_eq!(tokenize("1.5i"), vec![Token::Error(LexError::MalformedNumber(String::from("1.5i")))]);
+        assert_eq!(tokenize("1e3i"), vec![Token::Error(LexError::MalformedNumber(String::from("1e3i")))]);
+        assert_eq!(tokenize("99999999999999999999i"),
+                   vec![Token::Error(LexError::MalformedNumber(String::from("99999999999999999999i")))]);
+    }
+    #[test]
+    fn test_underscores_in_identifiers() {
+        assert_eq!(tokenize("my_var _hidden"),
+                   vec![Token::Identifier(String::from("my_var")), Token::Identifier(String::from("_hidden"))]);
+        // Identifiers still can't start with a digit
+        assert_eq!(tokenize("1_000"), vec![Token::Number(1000.0)]);
+    }
+    #[test]
+    fn test_numeric_separators() {
+        assert_eq!(tokenize("1_000_000 3.141_592 1_0e2 2_5i"),
+                   vec![Token::Number(1000000.0), Token::Number(3.141592), Token::Number(1000.0),
+                        Token::Integer(25)]);
+        for bad in &["1_", "1__0", "1_.0", "1._0", "1_e3", "1_i"] {
+            assert_eq!(tokenize(bad), vec![Token::Error(LexError::MalformedNumber(String::from(*bad)))]);
+        }
+    }
+    #[test]
+    fn test_hex_and_binary_literals() {
+        assert_eq!(tokenize("0xFF 0x1f 0b101 0b1111_0000 0x10i"),
+                   vec![Token::Number(255.0), Token::Number(31.0), Token::Number(5.0),
+                        Token::Number(240.0), Token::Integer(16)]);
+        for bad in &["0x", "0b", "0xG", "0b102", "0x_1", "0x1_"] {
+            assert_eq!(tokenize(bad), vec![Token::Error(LexError::MalformedNumber(String::from(*bad)))]);
+        }
+        // Only a leading zero starts a prefix
+        assert_eq!(tokenize("10b1"), vec![Token::Number(10.0), Token::Identifier(String::from("b1"))]);
+    }
+    #[test]
+    fn test_string_literals() {
+        assert_eq!(tokenize(r#""hi" "a\nb\tc\\d\"e""#),
+                   vec![Token::Str(String::from("hi")), Token::Str(String::from("a\nb\tc\\d\"e"))]);
+        assert_eq!(tokenize("\"# not a comment\""), vec![Token::Str(String::from("# not a comment"))]);
+        assert_eq!(tokenize(r#""\q" 1"#), vec![Token::Error(LexError::InvalidEscape('q')), Token::Number(1.0)]);
+        assert_eq!(tokenize(r#"1 "never closed"#), vec![Token::Number(1.0), Token::Error(LexError::UnterminatedString)]);
+        assert_eq!(tokenize(r#""ends in \"#), vec![Token::Error(LexError::UnterminatedString)]);
+    }
+    #[test]
+    fn test_char_literals() {
+        assert_eq!(tokenize("'A'"), vec![Token::Number(65.0)]);
+        assert_eq!(tokenize(r"'\n' '\t' '\\' '\'' ' ' '#'"),
+                   vec![Token::Number(10.0), Token::Number(9.0), Token::Number(92.0),
+                        Token::Number(39.0), Token::Number(32.0), Token::Number(35.0)]);
+        assert_eq!(tokenize("putchard('A')"),
+                   vec![Token::Identifier(String::from("putchard")), Token::UnknownChar('('),
+                        Token::Number(65.0), Token::UnknownChar(')')]);
+        assert_eq!(tokenize("'' 1"), vec![Token::Error(LexError::InvalidChar(String::new())), Token::Number(1.0)]);
+        assert_eq!(tokenize("'ab'"), vec![Token::Error(LexError::InvalidChar(String::from("ab")))]);
+        assert_eq!(tokenize(r"'\q' 1"), vec![Token::Error(LexError::InvalidEscape('q')), Token::Number(1.0)]);
+        assert_eq!(tokenize("'A"), vec![Token::Error(LexError::UnterminatedChar)]);
+        assert_eq!(tokenize("'A\n1"), vec![Token::Error(LexError::UnterminatedChar), Token::Number(1.0)]);
+        assert_eq!(LexError::InvalidChar(String::from("ab")).to_string(),
+                   "Character literal 'ab' must hold exactly one character");
+    }
+    #[test]
+    fn test_single_eof() {
+        let mut lexer = Lexer::new("1 + 2  ");
+        assert_eq!(lexer.by_ref().filter(|t| t.token == Token::Eof).count(), 1);
+        assert_eq!(lexer.next(), None);
+        let mut lexer = Lexer::new("x\n");
+        lexer.next();
+        // Eof is just past the last token, not after the trailing whitespace
+        let eof = Span { line: 1, col: 2 };
+        assert_eq!(lexer.next(), Some(SpannedToken { token: Token::Eof, span: eof, end: eof }));
+        assert_eq!(lexer.next(), None);
+        let mut lexer = Lexer::new("");
+        assert_eq!(lexer.next().map(|t| t.token), Some(Token::Eof));
+        assert_eq!(lexer.next(), None);
+    }
+    #[test]
+    fn test_keep_comments() {
+        let options = LexerOptions { keep_comments: true, ..LexerOptions::default() };
+        let tokens: Vec<Token> = Lexer::with_options("# hi\n1 # one", options).map(|t| t.token).collect();
+        assert_eq!(tokens, vec![Token::Comment(String::from(" hi")), Token::Number(1.0),
+                                Token::Comment(String::from(" one")), Token::Eof]);
+        assert_eq!(tokenize("# hi\n1"), vec![Token::Number(1.0)]);
+    }
+    #[test]
+    fn test_ellipsis() {
+        assert_eq!(tokenize("(fmt ...)"), vec![Token::UnknownChar('('), Token::Identifier(String::from("fmt")),
+                                               Token::Op(String::from("...")), Token::UnknownChar(')')]);
+        assert_eq!(tokenize(".5 ..."), vec![Token::Number(0.5), Token::Op(String::from("..."))]);
+    }
+    #[test]
+    fn test_number_overflow() {
+        assert_eq!(tokenize("1e308"), vec![Token::Number(1e308)]);
+        assert_eq!(tokenize("1e400"), vec![Token::Error(LexError::NumberOverflow(String::from("1e400")))]);
+        let options = LexerOptions { allow_infinite_numbers: true, ..LexerOptions::default() };
+        let tokens: Vec<Token> = Lexer::with_options("1e400", options).map(|t| t.token).collect();
+        assert_eq!(tokens, vec![Token::Number(f64::INFINITY), Token::Eof]);
+    }
+    #[test]
+    fn test_logical_operators() {
+        assert_eq!(tokenize("a && b || c | d & e"),
+                   vec![Token::Identifier(String::from("a")), Token::Op(String::from("&&")),
+                        Token::Identifier(String::from("b")), Token::Op(String::from("||")),
+                        Token::Identifier(String::from("c")), Token::UnknownChar('|'),
+                        Token::Identifier(String::from("d")), Token::UnknownChar('&'),
+                        Token::Identifier(String::from("e"))]);
+    }
+    #[test]
+    fn test_token_display() {
+        let cases = [(Token::Define, "'def'"), (Token::Extern, "'extern'"),
+                     (Token::Binary, "'binary'"), (Token::Unary, "'unary'"),
+                     (Token::If, "'if'"), (Token::Then, "'then'"), (Token::Else, "'else'"),
+                     (Token::For, "'for'"), (Token::While, "'while'"), (Token::In, "'in'"),
+                     (Token::Var, "'var'"),
+                     (Token::Identifier(String::from("foo")), "identifier foo"),
+                     (Token::Number(1.0), "number 1"), (Token::Number(2.5), "number 2.5"),
+                     (Token::Integer(42), "integer 42"),
+                     (Token::Str(String::from("a\nb")), "string \"a\\nb\""),
+                     (Token::Op(String::from("<=")), "'<='"), (Token::UnknownChar('+'), "'+'"),
+                     (Token::Assign, "'='"),
+                     (Token::Error(LexError::UnterminatedComment), "Unterminated block comment"),
+                     (Token::Comment(String::from(" hi")), "comment"),
+                     (Token::Eof, "end of input")];
+        for &(ref token, expected) in &cases {
+            assert_eq!(token.to_string(), expected);
+        }
+    }
+    #[test]
+    fn test_offset_and_source() {
+        let mut lexer = Lexer::new("é + 1");
+        lexer.next();
+        assert_eq!(lexer.offset(), 2);
+        assert_eq!(&lexer.source()[lexer.offset()..], " + 1");
+    }
+    #[test]
+    fn test_snippet() {
+        assert_eq!(snippet("def f(x)\n  x +", Span { line: 2, col: 6 }), "  x +\n     ^");
+        assert_eq!(snippet("\tx )", Span { line: 1, col: 4 }), "\tx )\n\t  ^");
+        // Just past the end of the input
+        assert_eq!(snippet("1 +", Span { line: 1, col: 4 }), "1 +\n   ^");
+        assert_eq!(snippet("", Span { line: 1, col: 1 }), "\n^");
     }
 }
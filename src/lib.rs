@@ -2,6 +2,9 @@
 extern crate llvm;
 // The lexer module was written in chapter 1.
 pub mod lexer;
+pub mod error;
 pub mod parser;
 pub mod codegen;
 pub mod jit;
+pub mod aot;
+pub mod driver;
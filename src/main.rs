@@ -1,5 +1,38 @@
 extern crate kaleidoscope;
-use kaleidoscope::jit;
+use std::env;
+use std::path::{Path, PathBuf};
+use std::process;
+use kaleidoscope::{driver, jit};
+use kaleidoscope::jit::OutputMode;
 fn main() {
-    jit::run(0);
+    // With a file argument we run that file, otherwise we start the REPL,
+    // or compile stdin without running it when asked to --emit something
+    let args: Vec<String> = env::args().skip(1).collect();
+    match args.get(0).map(|arg| arg.as_str()) {
+        Some("--emit") => {
+            let mode = match args.get(1).map(|arg| arg.as_str()) {
+                Some("ir") => OutputMode::Ir,
+                Some("bitcode") => {
+                    let path = args.get(2).map_or("out.bc", |path| path.as_str());
+                    OutputMode::Bitcode(PathBuf::from(path))
+                },
+                _ => {
+                    println!("Usage: kaleidoscope --emit ir|bitcode [path]");
+                    process::exit(1);
+                },
+            };
+            jit::run(0, mode);
+        },
+        Some(path) => {
+            let source = driver::read_source(Path::new(&path)).unwrap_or_else(|e| {
+                eprintln!("Error: {}", e);
+                process::exit(1);
+            });
+            if let Err(e) = driver::compile_source(&source, 0) {
+                eprintln!("Error: {}", e.render(&source));
+                process::exit(1);
+            }
+        },
+        None => jit::run(0, OutputMode::Jit),
+    }
 }
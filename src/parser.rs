@@ -1,33 +1,166 @@
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+use std::mem;
+use std::vec;
 use lexer;
+use error::CompileError;
+
+/// An expression, along with where it was in the source.
+#[derive(Debug, Clone)]
+pub struct Expr {
+    pub kind: ExprKind,
+    /// The range the expression was parsed from. A parenthesized expression's
+    /// range includes the parentheses. Nodes that were built by hand or
+    /// rewritten afterwards, for example by `fold_constants`, may not have one.
+    pub span: Option<SourceRange>,
+}
+
+/// Two expressions are equal if they have the same kind, wherever they were
+/// in the source, so a parsed tree equals the same tree built by hand.
+impl PartialEq for Expr {
+    fn eq(&self, other: &Expr) -> bool {
+        self.kind == other.kind
+    }
+}
 
 /// Instead of creating a base class and multiple child classes,
 /// we will use an enum to hold the different variants. This is much more Rusty
 #[derive(Debug, PartialEq, Clone)]
-pub enum Expr {
+pub enum ExprKind {
     Number(f64),
+    Integer(i64),
+    /// A string literal, which evaluates to a pointer to its characters.
+    Str(String),
     Variable(String),
     Binary {
-        op: char,
+        op: String,
         lhs: Box<Expr>,
         rhs: Box<Expr>,
     },
     Call {
         name: String,
         args: Vec<Box<Expr>>,
+    },
+    /// A prefix operator like `-x`
+    Unary {
+        op: char,
+        operand: Box<Expr>,
+    },
+    IfElse {
+        pred: Box<Expr>,
+        if_clause: Box<Expr>,
+        else_clause: Box<Expr>,
+    },
+    /// `var a = 1, b in body`. Variables without an initializer start out as 0.0.
+    VarIn {
+        vars: Vec<(String, Option<Box<Expr>>)>,
+        body: Box<Expr>,
+    },
+    /// `a; b; c`, which evaluates each expression in turn and gives the value of the last one.
+    Sequence(Vec<Box<Expr>>),
+    /// `for var = start, cond, step in body`. The step is optional and defaults to 1.0.
+    For {
+        var: String,
+        start: Box<Expr>,
+        cond: Box<Expr>,
+        step: Option<Box<Expr>>,
+        body: Box<Expr>,
+    },
+    /// `while cond in body`, which runs body for as long as cond is true.
+    While {
+        cond: Box<Expr>,
+        body: Box<Expr>,
+    },
+}
+
+/// Shorthands for building expressions by hand, like
+/// `Expr::binary("+", Expr::number(1.0), Expr::variable("x"))`.
+impl Expr {
+    /// An expression without a source range.
+    pub fn new(kind: ExprKind) -> Box<Expr> {
+        Box::new(Expr {
+            kind: kind,
+            span: None,
+        })
+    }
+    pub fn number(n: f64) -> Box<Expr> {
+        Expr::new(ExprKind::Number(n))
+    }
+    pub fn variable(name: &str) -> Box<Expr> {
+        Expr::new(ExprKind::Variable(String::from(name)))
+    }
+    pub fn binary(op: &str, lhs: Box<Expr>, rhs: Box<Expr>) -> Box<Expr> {
+        Expr::new(ExprKind::Binary {
+            op: String::from(op),
+            lhs: lhs,
+            rhs: rhs,
+        })
+    }
+    pub fn call(name: &str, args: Vec<Box<Expr>>) -> Box<Expr> {
+        Expr::new(ExprKind::Call {
+            name: String::from(name),
+            args: args,
+        })
     }
 }
 
 // These structs hold the prototype and function ast nodes
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Debug, Clone)]
 pub struct Prototype {
     pub name: String,
     pub args: Vec<String>,
+    /// True if this defines a user-defined unary or binary operator.
+    pub is_operator: bool,
+    /// The precedence of a user-defined binary operator, or None for everything else.
+    pub precedence: Option<u32>,
+    /// Ask LLVM to inline every call to this function when optimizing.
+    /// There's no syntax for this, it is up to whoever compiles the function.
+    pub always_inline: bool,
+    /// True if the prototype ends with `...`, so calls can pass any number of
+    /// arguments after the named ones, like C's `printf`.
+    pub is_vararg: bool,
+    /// Where the prototype was in the source, from its name up to the
+    /// closing parenthesis. Like an `Expr`'s range, it is left out when
+    /// comparing prototypes.
+    pub span: Option<SourceRange>,
+}
+impl PartialEq for Prototype {
+    fn eq(&self, other: &Prototype) -> bool {
+        self.name == other.name && self.args == other.args && self.is_operator == other.is_operator &&
+        self.precedence == other.precedence && self.always_inline == other.always_inline &&
+        self.is_vararg == other.is_vararg
+    }
 }
 impl Prototype {
     pub fn new(name: String, args: Vec<String>) -> Prototype {
         Prototype {
             name: name,
             args: args,
+            is_operator: false,
+            precedence: None,
+            always_inline: false,
+            is_vararg: false,
+            span: None,
+        }
+    }
+    /// How many arguments calls have to pass. Varargs functions can be
+    /// passed more than this, but not fewer.
+    pub fn arity(&self) -> usize {
+        self.args.len()
+    }
+    pub fn is_unary_op(&self) -> bool {
+        self.is_operator && self.arity() == 1
+    }
+    pub fn is_binary_op(&self) -> bool {
+        self.is_operator && self.arity() == 2
+    }
+    /// The operator an operator prototype defines, e.g. `|` for `binary|`.
+    pub fn operator_name(&self) -> &str {
+        if self.name.starts_with("unary") {
+            &self.name["unary".len()..]
+        } else {
+            &self.name["binary".len()..]
         }
     }
 }
@@ -45,170 +178,1437 @@ impl Function {
     }
 }
 
+/// A single top level item in a program.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Item {
+    Definition(Function),
+    Extern(Prototype),
+    TopLevel(Function),
+}
+
+// Where the parser gets its tokens from
+#[derive(Debug)]
+enum TokenSource<'a> {
+    Lexer(lexer::Lexer<'a>),
+    Buffer(vec::IntoIter<lexer::SpannedToken>),
+}
+impl<'a> Iterator for TokenSource<'a> {
+    type Item = lexer::SpannedToken;
+    fn next(&mut self) -> Option<lexer::SpannedToken> {
+        match *self {
+            TokenSource::Lexer(ref mut lexer) => lexer.next(),
+            TokenSource::Buffer(ref mut tokens) => tokens.next(),
+        }
+    }
+}
+
+/// The part of the source a node was parsed from, from its first character
+/// up to just past its last one.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct SourceRange {
+    pub start: lexer::Span,
+    pub end: lexer::Span,
+}
+
 // The Parser struct contains the lexer and has functions for parsing the token stream.
 #[derive(Debug)]
 pub struct Parser<'a> {
-    lexer: lexer::Lexer<'a>,
-    pub current: Option<lexer::Token>,
+    tokens: TokenSource<'a>,
+    pub current: lexer::Token,
+    /// Where the current token starts. For Eof that is just past the last token.
+    pub span: lexer::Span,
+    // Where the current token and the one before it end
+    end: lexer::Span,
+    last_end: lexer::Span,
+    // The token after current, which has already been taken from the lexer
+    peeked: Option<lexer::SpannedToken>,
+    // Binary operators and their precedences. User-defined operators get added here.
+    precedence: HashMap<String, u32>,
+    // User-defined unary operators
+    unary_ops: HashSet<char>,
+    // Whether a definition without a body is allowed, and returns 0.0
+    allow_empty_bodies: bool,
 }
 impl<'a> Parser<'a> {
     pub fn from_source(source: &'a str) -> Parser<'a> {
         Parser::from_lexer(lexer::Lexer::new(source))
     }
-    pub fn from_lexer(mut lex: lexer::Lexer<'a>) -> Parser<'a> {
-        let current = lex.next();
-        Parser {
-            lexer: lex,
-            current: current,
+    pub fn from_lexer(lex: lexer::Lexer<'a>) -> Parser<'a> {
+        Parser::from_token_source(TokenSource::Lexer(lex))
+    }
+    fn from_token_source(mut tokens: TokenSource<'a>) -> Parser<'a> {
+        let peeked = tokens.next();
+        let mut parser = Parser {
+            tokens: tokens,
+            current: lexer::Token::Eof,
+            span: lexer::Span { line: 1, col: 1 },
+            end: lexer::Span { line: 1, col: 1 },
+            last_end: lexer::Span { line: 1, col: 1 },
+            peeked: peeked,
+            precedence: default_precedence(),
+            unary_ops: HashSet::new(),
+            allow_empty_bodies: false,
+        };
+        parser.get_next_token();
+        parser
+    }
+    /// Parses tokens that didn't come straight from a `Lexer`, for example
+    /// because they were generated or rewritten.
+    ///
+    /// The tokens don't have positions in any source, so errors report the
+    /// nth token as being at line 1, column n.
+    pub fn from_tokens(mut tokens: Vec<lexer::Token>) -> Parser<'static> {
+        if tokens.last() != Some(&lexer::Token::Eof) {
+            tokens.push(lexer::Token::Eof);
+        }
+        let tokens: Vec<lexer::SpannedToken> = tokens.into_iter().enumerate().map(|(i, token)| {
+            lexer::SpannedToken {
+                token: token,
+                span: lexer::Span { line: 1, col: i as u32 + 1 },
+                end: lexer::Span { line: 1, col: i as u32 + 2 },
+            }
+        }).collect();
+        Parser::from_token_source(TokenSource::Buffer(tokens.into_iter()))
+    }
+    /// Builds an error saying that `expected` was wanted instead of the current token.
+    /// If the current token is a lexing error, that error is reported instead,
+    /// and running out of input is reported as `UnexpectedEof`.
+    fn unexpected(&self, expected: &str) -> CompileError {
+        match self.current {
+            lexer::Token::Error(ref err) => return CompileError::Lex(err.clone(), self.span),
+            lexer::Token::Eof => return CompileError::UnexpectedEof(self.span),
+            _ => (),
+        }
+        CompileError::UnexpectedToken {
+            expected: String::from(expected),
+            found: self.current.clone(),
+            span: self.span,
         }
     }
+    /// Returns the token after the current one without consuming anything.
+    pub fn peek_token(&self) -> Option<&lexer::Token> {
+        self.peeked.as_ref().map(|tok| &tok.token)
+    }
     fn get_next_token(&mut self) {
 
-        let tok = self.lexer.next();
-        self.current = tok;
+        // Once the lexer runs out, current stays at Eof
+        if let Some(tok) = self.peeked.take() {
+            self.current = tok.token;
+            self.span = tok.span;
+            self.last_end = self.end;
+            self.end = tok.end;
+            self.peeked = self.tokens.next();
+        }
+    }
+    /// Records that `expr` went from `start` up to the end of the last token.
+    fn spanned(&self, mut expr: Box<Expr>, start: lexer::Span) -> Box<Expr> {
+        expr.span = Some(self.range_from(start));
+        expr
+    }
+    fn range_from(&self, start: lexer::Span) -> SourceRange {
+        SourceRange {
+            start: start,
+            end: self.last_end,
+        }
+    }
+    fn start_of(&self, expr: &Expr) -> lexer::Span {
+        expr.span.map_or(self.span, |range| range.start)
     }
-    fn parse_number(&mut self) -> Result<Box<Expr>, String> {
+    fn parse_number(&mut self) -> Result<Box<Expr>, CompileError> {
+        let start = self.span;
         match self.current {
-            Some(lexer::Token::Number(n)) => {
+            lexer::Token::Number(n) => {
+                self.get_next_token();
+                Ok(self.spanned(Expr::new(ExprKind::Number(n)), start))
+            },
+            lexer::Token::Integer(n) => {
                 self.get_next_token();
-                Ok(Box::new(Expr::Number(n)))
+                Ok(self.spanned(Expr::new(ExprKind::Integer(n)), start))
             },
-            ref x => Err(format!("Expected number, found {:?}", x))
+            _ => Err(self.unexpected("number"))
         }
     }
-    fn parse_paren_expr(&mut self) -> Result<Box<Expr>, String> {
+    fn parse_paren_expr(&mut self) -> Result<Box<Expr>, CompileError> {
 
+        let start = self.span;
         self.get_next_token();
         let v = self.parse_expression()?;
         match self.current {
-            Some(lexer::Token::UnknownChar(')')) => Ok(v),
-            ref x => Err(format!("Expected ), found {:?}", x))
+            lexer::Token::UnknownChar(')') => {
+                self.get_next_token();
+                Ok(self.spanned(v, start))
+            },
+            _ => Err(self.unexpected(")"))
         }
     }
-    fn parse_identifier_expr(&mut self) -> Result<Box<Expr>, String> {
+    /// Parses a block like `{ a; b }`, which is just a sequence in braces.
+    fn parse_block_expr(&mut self) -> Result<Box<Expr>, CompileError> {
+        let start = self.span;
+        self.get_next_token(); // eat {
+        let v = self.parse_expression()?;
+        match self.current {
+            lexer::Token::UnknownChar('}') => {
+                self.get_next_token();
+                Ok(self.spanned(v, start))
+            },
+            _ => Err(self.unexpected("}"))
+        }
+    }
+    fn parse_identifier_expr(&mut self) -> Result<Box<Expr>, CompileError> {
 
-        let id = if let Some(lexer::Token::Identifier(ref s)) = self.current {
+        let start = self.span;
+        let id = if let lexer::Token::Identifier(ref s) = self.current {
             s.clone()
         } else {
-            return Err(format!("Expected identifier, found {:?}", self.current))
+            return Err(self.unexpected("identifier"))
         };
         self.get_next_token();
-        if Some(lexer::Token::UnknownChar('(')) == self.current {
+        if lexer::Token::UnknownChar('(') == self.current {
             self.get_next_token();
             let mut args = Vec::new();
-            loop {
-                args.push(self.parse_expression()?);
-                if Some(lexer::Token::UnknownChar(')')) == self.current {
-                    break;
-                }
-                if Some(lexer::Token::UnknownChar(',')) != self.current {
-                    return Err(format!("Expected \",\", found {:?}", self.current))
+            // A call without arguments, like foo(), goes straight to the )
+            if lexer::Token::UnknownChar(')') != self.current {
+                loop {
+                    args.push(self.parse_expression()?);
+                    if lexer::Token::UnknownChar(')') == self.current {
+                        break;
+                    }
+                    // Anything else, including running out of input, ends the call badly
+                    if lexer::Token::UnknownChar(',') != self.current {
+                        return Err(self.unexpected("\",\" or \")\""))
+                    }
+                    self.get_next_token();
                 }
-                self.get_next_token();
             }
             self.get_next_token();
-            Ok(Box::new(Expr::Call {
+            Ok(self.spanned(Expr::new(ExprKind::Call {
                 name: id,
                 args: args,
-            }))
+            }), start))
         } else {
-            Ok(Box::new(Expr::Variable(id.clone())))
+            Ok(self.spanned(Expr::new(ExprKind::Variable(id.clone())), start))
+        }
+    }
+    fn parse_if_expr(&mut self) -> Result<Box<Expr>, CompileError> {
+        let start = self.span;
+        self.get_next_token(); // Eat "if"
+        let pred = self.parse_expression()?;
+        if self.current != lexer::Token::Then {
+            return Err(self.unexpected("then"))
+        }
+        self.get_next_token();
+        let if_clause = self.parse_expression()?;
+        if self.current != lexer::Token::Else {
+            return Err(self.unexpected("else"))
+        }
+        self.get_next_token();
+        let else_clause = self.parse_expression()?;
+        Ok(self.spanned(Expr::new(ExprKind::IfElse {
+            pred: pred,
+            if_clause: if_clause,
+            else_clause: else_clause,
+        }), start))
+    }
+    fn parse_for_expr(&mut self) -> Result<Box<Expr>, CompileError> {
+        let start = self.span;
+        self.get_next_token(); // Eat "for"
+        let var = match self.current {
+            lexer::Token::Identifier(ref name) => name.clone(),
+            _ => return Err(self.unexpected("identifier after for"))
+        };
+        self.get_next_token();
+        if self.current != lexer::Token::Assign {
+            return Err(self.unexpected("= after for"))
+        }
+        self.get_next_token();
+        let start_value = self.parse_expression()?;
+        if self.current != lexer::Token::UnknownChar(',') {
+            return Err(self.unexpected("\",\" after for start value"))
+        }
+        self.get_next_token();
+        let cond = self.parse_expression()?;
+        let step = if self.current == lexer::Token::UnknownChar(',') {
+            self.get_next_token();
+            Some(self.parse_expression()?)
+        } else {
+            None
+        };
+        if self.current != lexer::Token::In {
+            return Err(self.unexpected("in after for"))
+        }
+        self.get_next_token();
+        let body = self.parse_expression()?;
+        Ok(self.spanned(Expr::new(ExprKind::For {
+            var: var,
+            start: start_value,
+            cond: cond,
+            step: step,
+            body: body,
+        }), start))
+    }
+    fn parse_while_expr(&mut self) -> Result<Box<Expr>, CompileError> {
+        let start = self.span;
+        self.get_next_token(); // Eat "while"
+        let cond = self.parse_expression()?;
+        if self.current != lexer::Token::In {
+            return Err(self.unexpected("in after while"))
+        }
+        self.get_next_token();
+        let body = self.parse_expression()?;
+        Ok(self.spanned(Expr::new(ExprKind::While {
+            cond: cond,
+            body: body,
+        }), start))
+    }
+    fn parse_var_expr(&mut self) -> Result<Box<Expr>, CompileError> {
+        let start = self.span;
+        self.get_next_token(); // Eat "var"
+        let mut vars = Vec::new();
+        loop {
+            let name = match self.current {
+                lexer::Token::Identifier(ref name) => name.clone(),
+                _ => return Err(self.unexpected("identifier after var"))
+            };
+            self.get_next_token();
+            let init = if self.current == lexer::Token::Assign {
+                self.get_next_token();
+                Some(self.parse_expression()?)
+            } else {
+                None
+            };
+            vars.push((name, init));
+            if self.current != lexer::Token::UnknownChar(',') {
+                break;
+            }
+            self.get_next_token();
+        }
+        if self.current != lexer::Token::In {
+            return Err(self.unexpected("in after var"))
         }
+        self.get_next_token();
+        let body = self.parse_expression()?;
+        Ok(self.spanned(Expr::new(ExprKind::VarIn {
+            vars: vars,
+            body: body,
+        }), start))
     }
-    fn parse_primary(&mut self) -> Result<Box<Expr>, String> {
+    fn parse_primary(&mut self) -> Result<Box<Expr>, CompileError> {
 
         match self.current {
-            Some(lexer::Token::Identifier(_)) => self.parse_identifier_expr(),
-            Some(lexer::Token::Number(_)) => self.parse_number(),
-            Some(lexer::Token::UnknownChar('(')) => self.parse_paren_expr(),
-            _ => Err(format!("Unknown token {:?} when expecting an expression", self.current))
+            lexer::Token::Identifier(_) => self.parse_identifier_expr(),
+            lexer::Token::If => self.parse_if_expr(),
+            lexer::Token::For => self.parse_for_expr(),
+            lexer::Token::While => self.parse_while_expr(),
+            lexer::Token::Var => self.parse_var_expr(),
+            lexer::Token::Number(_) | lexer::Token::Integer(_) => self.parse_number(),
+            lexer::Token::Str(ref string) => {
+                let string = string.clone();
+                let start = self.span;
+                self.get_next_token();
+                Ok(self.spanned(Expr::new(ExprKind::Str(string)), start))
+            },
+            lexer::Token::UnknownChar('(') => self.parse_paren_expr(),
+            lexer::Token::UnknownChar('{') => self.parse_block_expr(),
+            lexer::Token::Error(ref err) => Err(CompileError::Lex(err.clone(), self.span)),
+            lexer::Token::Eof => Err(CompileError::UnexpectedEof(self.span)),
+            _ => Err(CompileError::ExpectedExpression(self.current.clone(), self.span))
         }
     }
-    fn parse_expression(&mut self) -> Result<Box<Expr>, String> {
+    fn parse_unary(&mut self) -> Result<Box<Expr>, CompileError> {
+        let op = match self.current {
+            lexer::Token::UnknownChar(c) if self.unary_ops.contains(&c) || c == '-' || c == '!' => c,
+            _ => return self.parse_primary(),
+        };
+        let start = self.span;
+        self.get_next_token();
+        let operand = self.parse_unary()?;
+        // User-defined operators are just calls to the function implementing them,
+        // and they take priority over the builtin ones.
+        let expr = if self.unary_ops.contains(&op) {
+            ExprKind::Call {
+                name: format!("unary{}", op),
+                args: vec![operand],
+            }
+        } else {
+            // Negative literals are just numbers
+            match (op, &operand.kind) {
+                ('-', &ExprKind::Number(n)) => ExprKind::Number(-n),
+                ('-', &ExprKind::Integer(n)) => ExprKind::Integer(-n),
+                _ => ExprKind::Unary {
+                    op: op,
+                    operand: operand,
+                },
+            }
+        };
+        Ok(self.spanned(Expr::new(expr), start))
+    }
+    /// Parses an expression, stopping at the first token that can't continue it.
+    ///
+    /// ```
+    /// use kaleidoscope::parser::{Expr, Parser};
+    ///
+    /// let mut parser = Parser::from_source("a + b");
+    /// let expr = parser.parse_expression().unwrap();
+    /// assert_eq!(expr, Expr::binary("+", Expr::variable("a"), Expr::variable("b")));
+    /// ```
+    pub fn parse_expression(&mut self) -> Result<Box<Expr>, CompileError> {
 
-        let lhs = self.parse_primary()?;
+        let first = self.parse_binary_expr()?;
+        if self.current != lexer::Token::UnknownChar(';') {
+            return Ok(first)
+        }
+        let start = self.start_of(&first);
+        // Every ; has to be followed by another expression, so empty
+        // statements like `a;;` and trailing semicolons are errors.
+        let mut exprs = vec![first];
+        while self.current == lexer::Token::UnknownChar(';') {
+            self.get_next_token();
+            exprs.push(self.parse_binary_expr()?);
+        }
+        Ok(self.spanned(Expr::new(ExprKind::Sequence(exprs)), start))
+    }
+    fn parse_binary_expr(&mut self) -> Result<Box<Expr>, CompileError> {
+        let lhs = self.parse_unary()?;
         self.parse_bin_op_rhs(0, lhs)
     }
-    fn parse_bin_op_rhs(&mut self, prec: u32, mut lhs: Box<Expr>) -> Result<Box<Expr>, String> {
+    fn parse_bin_op_rhs(&mut self, prec: u32, mut lhs: Box<Expr>) -> Result<Box<Expr>, CompileError> {
         loop {
             let op = match self.current {
-                Some(lexer::Token::UnknownChar(c)) => c,
+                lexer::Token::UnknownChar(c) => c.to_string(),
+                lexer::Token::Op(ref op) => op.clone(),
+                lexer::Token::Assign => String::from("="),
                 _ => return Ok(lhs),
             };
-            let tok_prec = match token_precedence(op) {
+            let tok_prec = match self.token_precedence(&op) {
                 Some(n) if n < prec => return Ok(lhs),
                 None => return Ok(lhs),
                 Some(n) => n,
             };
             self.get_next_token();
-            let mut rhs = self.parse_primary()?;
+            if op == "?" {
+                lhs = self.parse_ternary_rhs(tok_prec, lhs)?;
+                continue;
+            }
+            let mut rhs = self.parse_unary()?;
             let next_prec = match self.current {
-                Some(lexer::Token::UnknownChar(c)) => token_precedence(c),
+                lexer::Token::UnknownChar(c) => self.token_precedence(&c.to_string()),
+                lexer::Token::Op(ref op) => self.token_precedence(op),
+                lexer::Token::Assign => self.token_precedence("="),
                 _ => None,
             };
             match next_prec {
                 Some(n) if tok_prec < n => rhs = self.parse_bin_op_rhs(tok_prec + 1, rhs)?,
+                // A right associative operator takes the next one at the same
+                // precedence into its right hand side, so `a ^ b ^ c` is `a ^ (b ^ c)`
+                Some(n) if tok_prec == n && is_right_associative(&op) =>
+                    rhs = self.parse_bin_op_rhs(tok_prec, rhs)?,
                 //None => rhs = self.parse_bin_op_rhs(tok_prec + 1, rhs)?,
                 _ => (),
             };
-            lhs = Box::new(Expr::Binary {
+            let start = self.start_of(&lhs);
+            lhs = self.spanned(Expr::new(ExprKind::Binary {
                 op: op,
                 lhs: lhs,
                 rhs: rhs,
-            });
+            }), start);
         }
     }
-    pub fn parse_prototype(&mut self) -> Result<Prototype, String> {
+    /// Parses the rest of `pred ? a : b` after the `?`, turning it into an if/else.
+    fn parse_ternary_rhs(&mut self, prec: u32, pred: Box<Expr>) -> Result<Box<Expr>, CompileError> {
+        // The middle stops before any `:`, which would otherwise be taken as sequencing
+        let if_clause = self.parse_unary()?;
+        let if_clause = self.parse_bin_op_rhs(SEQUENCE_PRECEDENCE + 1, if_clause)?;
+        if self.current != lexer::Token::UnknownChar(':') {
+            return Err(self.unexpected(": after ?"))
+        }
+        self.get_next_token();
+        // The else clause is right associative, so `a ? b : c ? d : e` nests to the right
+        let mut else_clause = self.parse_unary()?;
+        let next_prec = match self.current {
+            lexer::Token::UnknownChar(c) => self.token_precedence(&c.to_string()),
+            lexer::Token::Op(ref op) => self.token_precedence(op),
+            lexer::Token::Assign => self.token_precedence("="),
+            _ => None,
+        };
+        match next_prec {
+            Some(n) if n >= prec => else_clause = self.parse_bin_op_rhs(prec, else_clause)?,
+            _ => (),
+        };
+        let start = self.start_of(&pred);
+        Ok(self.spanned(Expr::new(ExprKind::IfElse {
+            pred: pred,
+            if_clause: if_clause,
+            else_clause: else_clause,
+        }), start))
+    }
+    /// Sets the precedence of the binary operator `op`, adding it if it is new.
+    /// Higher precedences bind tighter.
+    pub fn set_precedence(&mut self, op: &str, prec: u32) {
+        self.precedence.insert(op.to_string(), prec);
+    }
+    /// Lets definitions like `def noop()` leave out their body, which then
+    /// defaults to 0.0. By default a body is required.
+    pub fn allow_empty_bodies(&mut self, allow: bool) {
+        self.allow_empty_bodies = allow;
+    }
+    /// Makes `op` usable as a prefix operator calling the function `unary<op>`.
+    pub fn add_unary_operator(&mut self, op: char) {
+        self.unary_ops.insert(op);
+    }
+    fn token_precedence(&self, op: &str) -> Option<u32> {
+        self.precedence.get(op).cloned()
+    }
+    pub fn parse_prototype(&mut self) -> Result<Prototype, CompileError> {
+        let start = self.span;
+        let mut precedence = None;
+        let is_binary = self.current == lexer::Token::Binary;
+        let is_unary = self.current == lexer::Token::Unary;
         let name = match self.current {
-            Some(lexer::Token::Identifier(ref name)) => name.clone(),
-            ref x => return Err(format!("Expected identifier in prototype, found {:?}", x))
+            lexer::Token::Identifier(ref name) => name.clone(),
+            lexer::Token::Binary => {
+                self.get_next_token();
+                let op = match self.current {
+                    lexer::Token::UnknownChar(c) => c.to_string(),
+                    lexer::Token::Op(ref op) => op.clone(),
+                    _ => return Err(self.unexpected("operator after binary"))
+                };
+                format!("binary{}", op)
+            },
+            lexer::Token::Unary => {
+                self.get_next_token();
+                match self.current {
+                    lexer::Token::UnknownChar(c) => format!("unary{}", c),
+                    _ => return Err(self.unexpected("operator after unary"))
+                }
+            },
+            _ => return Err(self.unexpected("identifier in prototype"))
         };
         self.get_next_token();
-        if self.current != Some(lexer::Token::UnknownChar('(')) {
-            return Err(format!("Expected ( in prototype, found {:?}", self.current))
+        if is_binary && self.current != lexer::Token::UnknownChar('(') {
+            // The precedence is optional
+            precedence = match self.current {
+                lexer::Token::Number(n) if n >= 1.0 && n <= 100.0 => Some(n as u32),
+                _ => return Err(self.unexpected("precedence between 1 and 100")),
+            };
+            self.get_next_token();
+        } else if is_binary {
+            precedence = Some(DEFAULT_BINARY_PRECEDENCE);
+        }
+        if self.current != lexer::Token::UnknownChar('(') {
+            return Err(self.unexpected("( in prototype"))
         }
         let mut arg_names = Vec::new();
+        let mut is_vararg = false;
         loop {
             self.get_next_token();
             match self.current {
-                Some(lexer::Token::Identifier(ref arg_name)) => {
+                // Operators always take exactly one or two arguments
+                lexer::Token::Op(ref op) if op == "..." && !is_binary && !is_unary => {
+                    is_vararg = true;
+                    self.get_next_token();
+                    break
+                },
+                lexer::Token::Identifier(ref arg_name) => {
+                    if arg_names.contains(arg_name) {
+                        return Err(CompileError::DuplicateParam {
+                            name: name,
+                            param: arg_name.clone(),
+                            span: self.span,
+                        })
+                    }
                     arg_names.push(arg_name.clone());
                 },
                 _ => break,
             }
         }
-        if self.current != Some(lexer::Token::UnknownChar(')')) {
-            return Err(format!("Expected ) in prototype, found {:?}", self.current))
+        if self.current != lexer::Token::UnknownChar(')') {
+            return Err(self.unexpected(") in prototype"))
         }
         self.get_next_token();
-        Ok(Prototype::new(name, arg_names))
+        if let Some(prec) = precedence {
+            if arg_names.len() != 2 {
+                return Err(CompileError::ArityMismatch {
+                    name: name,
+                    expected: 2,
+                    found: arg_names.len(),
+                })
+            }
+        }
+        if is_unary && arg_names.len() != 1 {
+            return Err(CompileError::ArityMismatch {
+                name: name,
+                expected: 1,
+                found: arg_names.len(),
+            })
+        }
+        let mut proto = Prototype::new(name, arg_names);
+        proto.is_operator = is_binary || is_unary;
+        proto.precedence = precedence;
+        proto.is_vararg = is_vararg;
+        proto.span = Some(self.range_from(start));
+        // Register the operator so the rest of the input can use it
+        if let Some(prec) = precedence {
+            self.set_precedence(proto.operator_name(), prec);
+        } else if is_unary {
+            let op = proto.operator_name().chars().next().unwrap();
+            self.add_unary_operator(op);
+        }
+        Ok(proto)
     }
-    pub fn parse_definition(&mut self) -> Result<Function, String> {
+    pub fn parse_definition(&mut self) -> Result<Function, CompileError> {
         self.get_next_token(); // Eat "def"
         let proto = self.parse_prototype()?;
-        let body = self.parse_expression()?;
+        let body = match self.current {
+            lexer::Token::Eof | lexer::Token::Define | lexer::Token::Extern if self.allow_empty_bodies =>
+                Expr::new(ExprKind::Number(0.0)),
+            _ => self.parse_expression()?,
+        };
         Ok(Function::new(proto, body))
     }
-    pub fn parse_extern(&mut self) -> Result<Prototype, String> {
+    pub fn parse_extern(&mut self) -> Result<Prototype, CompileError> {
         self.get_next_token(); // eat "extern"
         self.parse_prototype()
     }
-    pub fn parse_top_level_expr(&mut self) -> Result<Function, String> {
+    /// Parses whichever kind of item starts at the current token.
+    pub fn parse_item(&mut self) -> Result<Item, CompileError> {
+        match self.current {
+            lexer::Token::Define => Ok(Item::Definition(self.parse_definition()?)),
+            lexer::Token::Extern => Ok(Item::Extern(self.parse_extern()?)),
+            _ => Ok(Item::TopLevel(self.parse_anonymous_function()?)),
+        }
+    }
+    /// Parses items until the end of the input.
+    pub fn parse_program(&mut self) -> Result<Vec<Item>, CompileError> {
+        let mut items = Vec::new();
+        while !self.is_at_end() {
+            items.push(self.parse_item()?);
+        }
+        Ok(items)
+    }
+    /// Parses items one at a time as they are asked for, instead of all at
+    /// once like `parse_program`. The iterator ends at the end of the input,
+    /// or right after yielding an error.
+    pub fn items<'p>(&'p mut self) -> Items<'p, 'a> {
+        Items {
+            parser: self,
+            failed: false,
+        }
+    }
+    /// Like `parse_program`, but keeps going after a syntax error so that
+    /// every error in the input is reported, not just the first.
+    ///
+    /// After an error, tokens are skipped up to the next `def`, `extern` or
+    /// line, and parsing starts over from there.
+    pub fn parse_program_recovering(&mut self) -> (Vec<Item>, Vec<CompileError>) {
+        let mut items = Vec::new();
+        let mut errors = Vec::new();
+        while !self.is_at_end() {
+            let start = self.span;
+            match self.parse_item() {
+                Ok(item) => items.push(item),
+                Err(err) => {
+                    errors.push(err);
+                    let line = self.span.line;
+                    // Always make progress, even if the error was at the start of an item
+                    if self.span == start {
+                        self.get_next_token();
+                    }
+                    while !self.is_at_end() && self.span.line == line &&
+                          self.current != lexer::Token::Define && self.current != lexer::Token::Extern {
+                        self.get_next_token();
+                    }
+                },
+            }
+        }
+        (items, errors)
+    }
+    /// Parses an expression that makes up the whole rest of the input.
+    ///
+    /// Anything left over after the expression is an error, so typos like
+    /// `1 + 1 2` don't silently drop part of the input.
+    pub fn parse_top_level_expr(&mut self) -> Result<Function, CompileError> {
+        let func = self.parse_anonymous_function()?;
+        if !self.is_at_end() {
+            return Err(self.unexpected("end of input"))
+        }
+        Ok(func)
+    }
+    /// Wraps the next expression in a function without arguments.
+    fn parse_anonymous_function(&mut self) -> Result<Function, CompileError> {
         let expr = self.parse_expression()?;
         let proto = Prototype::new(String::from("__top_level_expr"), Vec::new());
         Ok(Function::new(proto, expr))
     }
+    /// True once every token has been consumed.
+    pub fn is_at_end(&self) -> bool {
+        self.current == lexer::Token::Eof
+    }
+}
+
+/// The iterator returned by `Parser::items`.
+#[derive(Debug)]
+pub struct Items<'p, 'a: 'p> {
+    parser: &'p mut Parser<'a>,
+    failed: bool,
+}
+impl<'p, 'a> Iterator for Items<'p, 'a> {
+    type Item = Result<Item, CompileError>;
+    fn next(&mut self) -> Option<Result<Item, CompileError>> {
+        if self.failed || self.parser.is_at_end() {
+            return None
+        }
+        let item = self.parser.parse_item();
+        self.failed = item.is_err();
+        Some(item)
+    }
+}
+
+/// Wraps an expression so it can be used as a `HashMap` key, for example to
+/// find identical subtrees.
+///
+/// Numbers are compared by their bits, so unlike with `==` on `Expr`, `NaN`
+/// equals itself and `0.0` doesn't equal `-0.0`.
+#[derive(Debug, Clone, Copy)]
+pub struct ExprKey<'a>(pub &'a Expr);
+
+impl<'a> PartialEq for ExprKey<'a> {
+    fn eq(&self, other: &ExprKey<'a>) -> bool {
+        same_expr(self.0, other.0)
+    }
+}
+impl<'a> Eq for ExprKey<'a> {}
+impl<'a> Hash for ExprKey<'a> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        hash_expr(self.0, state)
+    }
+}
+
+fn same_expr(a: &Expr, b: &Expr) -> bool {
+    let same_all = |a: &[Box<Expr>], b: &[Box<Expr>]| {
+        a.len() == b.len() && a.iter().zip(b).all(|(a, b)| same_expr(a, b))
+    };
+    let same_option = |a: &Option<Box<Expr>>, b: &Option<Box<Expr>>| match (a, b) {
+        (&Some(ref a), &Some(ref b)) => same_expr(a, b),
+        (&None, &None) => true,
+        _ => false,
+    };
+    match (&a.kind, &b.kind) {
+        (&ExprKind::Number(a), &ExprKind::Number(b)) => a.to_bits() == b.to_bits(),
+        (&ExprKind::Integer(a), &ExprKind::Integer(b)) => a == b,
+        (&ExprKind::Str(ref a), &ExprKind::Str(ref b)) |
+        (&ExprKind::Variable(ref a), &ExprKind::Variable(ref b)) => a == b,
+        (&ExprKind::Binary {op: ref op_a, lhs: ref lhs_a, rhs: ref rhs_a},
+         &ExprKind::Binary {op: ref op_b, lhs: ref lhs_b, rhs: ref rhs_b}) =>
+            op_a == op_b && same_expr(lhs_a, lhs_b) && same_expr(rhs_a, rhs_b),
+        (&ExprKind::Unary {op: op_a, operand: ref operand_a},
+         &ExprKind::Unary {op: op_b, operand: ref operand_b}) =>
+            op_a == op_b && same_expr(operand_a, operand_b),
+        (&ExprKind::Call {name: ref name_a, args: ref args_a},
+         &ExprKind::Call {name: ref name_b, args: ref args_b}) =>
+            name_a == name_b && same_all(args_a, args_b),
+        (&ExprKind::IfElse {pred: ref pred_a, if_clause: ref if_a, else_clause: ref else_a},
+         &ExprKind::IfElse {pred: ref pred_b, if_clause: ref if_b, else_clause: ref else_b}) =>
+            same_expr(pred_a, pred_b) && same_expr(if_a, if_b) && same_expr(else_a, else_b),
+        (&ExprKind::For {var: ref var_a, start: ref start_a, cond: ref cond_a, step: ref step_a, body: ref body_a},
+         &ExprKind::For {var: ref var_b, start: ref start_b, cond: ref cond_b, step: ref step_b, body: ref body_b}) =>
+            var_a == var_b && same_expr(start_a, start_b) && same_expr(cond_a, cond_b) &&
+            same_option(step_a, step_b) && same_expr(body_a, body_b),
+        (&ExprKind::While {cond: ref cond_a, body: ref body_a},
+         &ExprKind::While {cond: ref cond_b, body: ref body_b}) =>
+            same_expr(cond_a, cond_b) && same_expr(body_a, body_b),
+        (&ExprKind::VarIn {vars: ref vars_a, body: ref body_a},
+         &ExprKind::VarIn {vars: ref vars_b, body: ref body_b}) =>
+            vars_a.len() == vars_b.len() &&
+            vars_a.iter().zip(vars_b).all(|(a, b)| a.0 == b.0 && same_option(&a.1, &b.1)) &&
+            same_expr(body_a, body_b),
+        (&ExprKind::Sequence(ref a), &ExprKind::Sequence(ref b)) => same_all(a, b),
+        _ => false,
+    }
+}
+
+fn hash_expr<H: Hasher>(expr: &Expr, state: &mut H) {
+    mem::discriminant(&expr.kind).hash(state);
+    match expr.kind {
+        ExprKind::Number(n) => n.to_bits().hash(state),
+        ExprKind::Integer(n) => n.hash(state),
+        ExprKind::Str(ref string) => string.hash(state),
+        ExprKind::Variable(ref name) => name.hash(state),
+        ExprKind::Binary {ref op, ref lhs, ref rhs} => {
+            op.hash(state);
+            hash_expr(lhs, state);
+            hash_expr(rhs, state);
+        },
+        ExprKind::Unary {op, ref operand} => {
+            op.hash(state);
+            hash_expr(operand, state);
+        },
+        ExprKind::Call {ref name, ref args} => {
+            name.hash(state);
+            for arg in args {
+                hash_expr(arg, state);
+            }
+        },
+        ExprKind::IfElse {ref pred, ref if_clause, ref else_clause} => {
+            hash_expr(pred, state);
+            hash_expr(if_clause, state);
+            hash_expr(else_clause, state);
+        },
+        ExprKind::For {ref var, ref start, ref cond, ref step, ref body} => {
+            var.hash(state);
+            hash_expr(start, state);
+            hash_expr(cond, state);
+            if let Some(ref step) = *step {
+                hash_expr(step, state);
+            }
+            hash_expr(body, state);
+        },
+        ExprKind::While {ref cond, ref body} => {
+            hash_expr(cond, state);
+            hash_expr(body, state);
+        },
+        ExprKind::VarIn {ref vars, ref body} => {
+            for &(ref name, ref init) in vars {
+                name.hash(state);
+                if let Some(ref init) = *init {
+                    hash_expr(init, state);
+                }
+            }
+            hash_expr(body, state);
+        },
+        ExprKind::Sequence(ref exprs) => {
+            for expr in exprs {
+                hash_expr(expr, state);
+            }
+        },
+    }
+}
+
+/// Parses `source`, which has to be a single expression and nothing else.
+pub fn parse_expression_str(source: &str) -> Result<Box<Expr>, CompileError> {
+    let mut parser = Parser::from_source(source);
+    let expr = parser.parse_expression()?;
+    if !parser.is_at_end() {
+        return Err(parser.unexpected("end of input"))
+    }
+    Ok(expr)
+}
+
+/// Renders an expression as source code with every compound expression in parentheses,
+/// so `1 + 2 * 3` becomes `(1 + (2 * 3))`.
+pub fn pretty(expr: &Expr) -> String {
+    match expr.kind {
+        ExprKind::Number(n) => n.to_string(),
+        ExprKind::Integer(n) => format!("{}i", n),
+        ExprKind::Str(ref string) => format!("{:?}", string),
+        ExprKind::Variable(ref name) => name.clone(),
+        ExprKind::Binary {ref op, ref lhs, ref rhs} =>
+            format!("({} {} {})", pretty(lhs), op, pretty(rhs)),
+        ExprKind::Unary {op, ref operand} => format!("({}{})", op, pretty(operand)),
+        ExprKind::Call {ref name, ref args} => {
+            let args: Vec<String> = args.iter().map(|arg| pretty(arg)).collect();
+            format!("{}({})", name, args.join(", "))
+        },
+        ExprKind::IfElse {ref pred, ref if_clause, ref else_clause} =>
+            format!("(if {} then {} else {})", pretty(pred), pretty(if_clause), pretty(else_clause)),
+        ExprKind::For {ref var, ref start, ref cond, ref step, ref body} => {
+            let step = match *step {
+                Some(ref step) => format!(", {}", pretty(step)),
+                None => String::new(),
+            };
+            format!("(for {} = {}, {}{} in {})", var, pretty(start), pretty(cond), step, pretty(body))
+        },
+        ExprKind::While {ref cond, ref body} => format!("(while {} in {})", pretty(cond), pretty(body)),
+        ExprKind::VarIn {ref vars, ref body} => {
+            let vars: Vec<String> = vars.iter().map(|&(ref name, ref init)| match *init {
+                Some(ref init) => format!("{} = {}", name, pretty(init)),
+                None => name.clone(),
+            }).collect();
+            format!("(var {} in {})", vars.join(", "), pretty(body))
+        },
+        ExprKind::Sequence(ref exprs) => {
+            let exprs: Vec<String> = exprs.iter().map(|expr| pretty(expr)).collect();
+            format!("({})", exprs.join("; "))
+        },
+    }
+}
+
+/// Folds arithmetic on constants, so `(2 + 3) * x` becomes `5 * x`.
+///
+/// Only `+`, `-`, `*` and `/` on floats are folded. Division by zero is left for codegen.
+pub fn fold_constants(expr: Box<Expr>) -> Box<Expr> {
+    let fold_all = |exprs: Vec<Box<Expr>>| exprs.into_iter().map(fold_constants).collect();
+    let Expr {kind, span} = *expr;
+    // A folded constant keeps the range of the arithmetic it replaced
+    let kind = match kind {
+        ExprKind::Binary {op, lhs, rhs} => {
+            let lhs = fold_constants(lhs);
+            let rhs = fold_constants(rhs);
+            match (&lhs.kind, op.as_str(), &rhs.kind) {
+                (&ExprKind::Number(l), "+", &ExprKind::Number(r)) => ExprKind::Number(l + r),
+                (&ExprKind::Number(l), "-", &ExprKind::Number(r)) => ExprKind::Number(l - r),
+                (&ExprKind::Number(l), "*", &ExprKind::Number(r)) => ExprKind::Number(l * r),
+                (&ExprKind::Number(l), "/", &ExprKind::Number(r)) if r != 0.0 => ExprKind::Number(l / r),
+                _ => ExprKind::Binary {
+                    op: op,
+                    lhs: lhs,
+                    rhs: rhs,
+                },
+            }
+        },
+        ExprKind::Unary {op, operand} => ExprKind::Unary {
+            op: op,
+            operand: fold_constants(operand),
+        },
+        ExprKind::Call {name, args} => ExprKind::Call {
+            name: name,
+            args: fold_all(args),
+        },
+        ExprKind::IfElse {pred, if_clause, else_clause} => ExprKind::IfElse {
+            pred: fold_constants(pred),
+            if_clause: fold_constants(if_clause),
+            else_clause: fold_constants(else_clause),
+        },
+        ExprKind::For {var, start, cond, step, body} => ExprKind::For {
+            var: var,
+            start: fold_constants(start),
+            cond: fold_constants(cond),
+            step: step.map(fold_constants),
+            body: fold_constants(body),
+        },
+        ExprKind::While {cond, body} => ExprKind::While {
+            cond: fold_constants(cond),
+            body: fold_constants(body),
+        },
+        ExprKind::VarIn {vars, body} => ExprKind::VarIn {
+            vars: vars.into_iter().map(|(name, init)| (name, init.map(fold_constants))).collect(),
+            body: fold_constants(body),
+        },
+        ExprKind::Sequence(exprs) => ExprKind::Sequence(fold_all(exprs)),
+        other => other,
+    };
+    Box::new(Expr {
+        kind: kind,
+        span: span,
+    })
+}
+
+/// Rewrites `a > b` to `b < a` and `a >= b` to `b <= a` everywhere in `expr`,
+/// so later passes only have to deal with `<` and `<=`.
+///
+/// Swapping the operands also swaps the order they are evaluated in, so
+/// comparisons where either side calls a function or assigns are left alone.
+pub fn normalize_comparisons(mut expr: Box<Expr>) -> Box<Expr> {
+    normalize_comparisons_in_place(&mut expr);
+    expr
+}
+
+fn normalize_comparisons_in_place(expr: &mut Expr) {
+    for child in children_mut(expr) {
+        normalize_comparisons_in_place(child);
+    }
+    if let ExprKind::Binary {ref mut op, ref mut lhs, ref mut rhs} = expr.kind {
+        let flipped = match op.as_str() {
+            ">" => "<",
+            ">=" => "<=",
+            _ => return,
+        };
+        if is_pure(lhs) && is_pure(rhs) {
+            *op = String::from(flipped);
+            mem::swap(lhs, rhs);
+        }
+    }
+}
+
+/// True if evaluating `expr` can't call anything or change any variable.
+fn is_pure(expr: &Expr) -> bool {
+    struct Pure;
+    impl Visitor for Pure {
+        fn visit_call(&mut self, _name: &str, _args: &[Box<Expr>]) -> bool {
+            false
+        }
+        fn visit_binary(&mut self, op: &str, _lhs: &Expr, _rhs: &Expr) -> bool {
+            op != "=" && BUILTIN_BINARY_OPS.contains(&op)
+        }
+    }
+    walk_expr(&mut Pure, expr)
+}
+
+/// Binds arithmetic that appears more than once in `fun`'s body to variables,
+/// so `(a*b) + (a*b)` becomes `var cse0 = a*b in cse0 + cse0`.
+///
+/// Only arithmetic on arguments that the body never changes is shared, since
+/// anything else could give a different value the second time. Returns how
+/// many subexpressions were bound.
+pub fn eliminate_common_subexpressions(fun: &mut Function) -> usize {
+    #[derive(Default)]
+    struct Names {
+        used: HashSet<String>,
+        changed: HashSet<String>,
+    }
+    impl Visitor for Names {
+        fn visit_variable(&mut self, name: &str) -> bool {
+            self.used.insert(String::from(name));
+            true
+        }
+        fn visit_binary(&mut self, op: &str, lhs: &Expr, _rhs: &Expr) -> bool {
+            if let (&ExprKind::Variable(ref name), "=") = (&lhs.kind, op) {
+                self.changed.insert(name.clone());
+            }
+            true
+        }
+        fn visit_for(&mut self, var: &str, _start: &Expr, _cond: &Expr,
+                     _step: Option<&Expr>, _body: &Expr) -> bool {
+            self.used.insert(String::from(var));
+            self.changed.insert(String::from(var));
+            true
+        }
+        fn visit_var_in(&mut self, vars: &[(String, Option<Box<Expr>>)], _body: &Expr) -> bool {
+            for &(ref name, _) in vars {
+                self.used.insert(name.clone());
+                self.changed.insert(name.clone());
+            }
+            true
+        }
+    }
+    let mut names = Names::default();
+    walk_expr(&mut names, &fun.body);
+    let allowed: HashSet<&str> = fun.prototype.args.iter()
+        .map(|arg| arg.as_str())
+        .filter(|arg| !names.changed.contains(*arg))
+        .collect();
+    let mut bindings: Vec<(String, Box<Expr>)> = Vec::new();
+    loop {
+        let target = {
+            let mut counts = HashMap::new();
+            let mut order = Vec::new();
+            count_shareable(&fun.body, &allowed, &mut counts, &mut order);
+            for &(_, ref init) in &bindings {
+                count_shareable(init, &allowed, &mut counts, &mut order);
+            }
+            // Share the biggest repeated subexpression first, since that
+            // also shares everything inside it
+            order.into_iter()
+                .filter(|key| counts[key] > 1)
+                .min_by_key(|key| Reverse(expr_size(key.0)))
+                .map(|key| key.0.clone())
+        };
+        let target = match target {
+            Some(target) => target,
+            None => break,
+        };
+        let mut name = format!("cse{}", bindings.len());
+        while names.used.contains(&name) {
+            name.push('_');
+        }
+        names.used.insert(name.clone());
+        replace_expr(&mut fun.body, &target, &name);
+        for &mut (_, ref mut init) in &mut bindings {
+            replace_expr(init, &target, &name);
+        }
+        bindings.push((name, Box::new(target)));
+    }
+    let count = bindings.len();
+    if count > 0 {
+        // Later bindings are smaller and may be used by earlier ones, but
+        // never the other way around, so they go first
+        let body = mem::replace(&mut fun.body, Expr::new(ExprKind::Number(0.0)));
+        fun.body = Expr::new(ExprKind::VarIn {
+            vars: bindings.into_iter().rev().map(|(name, init)| (name, Some(init))).collect(),
+            body: body,
+        });
+    }
+    count
+}
+
+/// True if `expr` is made of builtin arithmetic on numbers and `allowed` variables.
+fn is_shareable(expr: &Expr, allowed: &HashSet<&str>) -> bool {
+    match expr.kind {
+        ExprKind::Number(_) => true,
+        ExprKind::Variable(ref name) => allowed.contains(name.as_str()),
+        ExprKind::Binary {ref op, ref lhs, ref rhs} => match op.as_str() {
+            "+" | "-" | "*" | "/" | "%" | "^" | "<" | ">" | "<=" | ">=" | "==" | "!=" =>
+                is_shareable(lhs, allowed) && is_shareable(rhs, allowed),
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+/// Counts how often each shareable binary expression in `expr` appears.
+/// `order` gets every expression in the order they were first seen.
+fn count_shareable<'a>(expr: &'a Expr,
+                       allowed: &HashSet<&str>,
+                       counts: &mut HashMap<ExprKey<'a>, usize>,
+                       order: &mut Vec<ExprKey<'a>>) {
+    if let ExprKind::Binary {..} = expr.kind {
+        if is_shareable(expr, allowed) {
+            let count = counts.entry(ExprKey(expr)).or_insert(0);
+            if *count == 0 {
+                order.push(ExprKey(expr));
+            }
+            *count += 1;
+        }
+    }
+    for child in children(expr) {
+        count_shareable(child, allowed, counts, order);
+    }
+}
+
+/// Replaces every occurrence of `target` in `expr` with the variable `name`.
+fn replace_expr(expr: &mut Expr, target: &Expr, name: &str) {
+    if ExprKey(expr) == ExprKey(target) {
+        expr.kind = ExprKind::Variable(String::from(name));
+        return
+    }
+    for child in children_mut(expr) {
+        replace_expr(child, target, name);
+    }
+}
+
+/// The number of nodes in `expr`.
+fn expr_size(expr: &Expr) -> usize {
+    1 + children(expr).into_iter().map(expr_size).sum::<usize>()
+}
+
+fn children(expr: &Expr) -> Vec<&Expr> {
+    match expr.kind {
+        ExprKind::Number(_) | ExprKind::Integer(_) | ExprKind::Str(_) | ExprKind::Variable(_) => vec![],
+        ExprKind::Binary {ref lhs, ref rhs, ..} => vec![lhs, rhs],
+        ExprKind::Unary {ref operand, ..} => vec![operand],
+        ExprKind::Call {ref args, ..} => args.iter().map(|arg| &**arg).collect(),
+        ExprKind::IfElse {ref pred, ref if_clause, ref else_clause} => vec![pred, if_clause, else_clause],
+        ExprKind::For {ref start, ref cond, ref step, ref body, ..} => {
+            let mut children: Vec<&Expr> = vec![start, cond];
+            children.extend(step.as_ref().map(|step| &**step));
+            children.push(body);
+            children
+        },
+        ExprKind::While {ref cond, ref body} => vec![cond, body],
+        ExprKind::VarIn {ref vars, ref body} => {
+            let mut children: Vec<&Expr> = vars.iter()
+                .filter_map(|&(_, ref init)| init.as_ref().map(|init| &**init))
+                .collect();
+            children.push(body);
+            children
+        },
+        ExprKind::Sequence(ref exprs) => exprs.iter().map(|expr| &**expr).collect(),
+    }
+}
+
+fn children_mut(expr: &mut Expr) -> Vec<&mut Expr> {
+    match expr.kind {
+        ExprKind::Number(_) | ExprKind::Integer(_) | ExprKind::Str(_) | ExprKind::Variable(_) => vec![],
+        ExprKind::Binary {ref mut lhs, ref mut rhs, ..} => vec![lhs, rhs],
+        ExprKind::Unary {ref mut operand, ..} => vec![operand],
+        ExprKind::Call {ref mut args, ..} => args.iter_mut().map(|arg| &mut **arg).collect(),
+        ExprKind::IfElse {ref mut pred, ref mut if_clause, ref mut else_clause} =>
+            vec![pred, if_clause, else_clause],
+        ExprKind::For {ref mut start, ref mut cond, ref mut step, ref mut body, ..} => {
+            let mut children: Vec<&mut Expr> = vec![start, cond];
+            children.extend(step.as_mut().map(|step| &mut **step));
+            children.push(body);
+            children
+        },
+        ExprKind::While {ref mut cond, ref mut body} => vec![cond, body],
+        ExprKind::VarIn {ref mut vars, ref mut body} => {
+            let mut children: Vec<&mut Expr> = vars.iter_mut()
+                .filter_map(|&mut (_, ref mut init)| init.as_mut().map(|init| &mut **init))
+                .collect();
+            children.push(body);
+            children
+        },
+        ExprKind::Sequence(ref mut exprs) => exprs.iter_mut().map(|expr| &mut **expr).collect(),
+    }
+}
+
+/// Hooks for walking an expression tree with `walk_expr`.
+///
+/// Each method is called on a node before its children are walked. They all
+/// default to doing nothing, and returning `false` from any of them stops the
+/// whole walk.
+pub trait Visitor {
+    fn visit_number(&mut self, _n: f64) -> bool { true }
+    fn visit_integer(&mut self, _n: i64) -> bool { true }
+    fn visit_str(&mut self, _string: &str) -> bool { true }
+    fn visit_variable(&mut self, _name: &str) -> bool { true }
+    fn visit_binary(&mut self, _op: &str, _lhs: &Expr, _rhs: &Expr) -> bool { true }
+    fn visit_unary(&mut self, _op: char, _operand: &Expr) -> bool { true }
+    fn visit_call(&mut self, _name: &str, _args: &[Box<Expr>]) -> bool { true }
+    fn visit_if(&mut self, _pred: &Expr, _if_clause: &Expr, _else_clause: &Expr) -> bool { true }
+    fn visit_for(&mut self, _var: &str, _start: &Expr, _cond: &Expr,
+                 _step: Option<&Expr>, _body: &Expr) -> bool { true }
+    fn visit_while(&mut self, _cond: &Expr, _body: &Expr) -> bool { true }
+    fn visit_var_in(&mut self, _vars: &[(String, Option<Box<Expr>>)], _body: &Expr) -> bool { true }
+    fn visit_sequence(&mut self, _exprs: &[Box<Expr>]) -> bool { true }
+}
+
+/// Walks `expr` depth first, calling `visitor` on every node.
+///
+/// Returns `false` if the visitor stopped the walk early.
+pub fn walk_expr<V: Visitor>(visitor: &mut V, expr: &Expr) -> bool {
+    match expr.kind {
+        ExprKind::Number(n) => visitor.visit_number(n),
+        ExprKind::Integer(n) => visitor.visit_integer(n),
+        ExprKind::Str(ref string) => visitor.visit_str(string),
+        ExprKind::Variable(ref name) => visitor.visit_variable(name),
+        ExprKind::Binary {ref op, ref lhs, ref rhs} =>
+            visitor.visit_binary(op, lhs, rhs) && walk_expr(visitor, lhs) && walk_expr(visitor, rhs),
+        ExprKind::Unary {op, ref operand} =>
+            visitor.visit_unary(op, operand) && walk_expr(visitor, operand),
+        ExprKind::Call {ref name, ref args} =>
+            visitor.visit_call(name, args) && args.iter().all(|arg| walk_expr(visitor, arg)),
+        ExprKind::IfElse {ref pred, ref if_clause, ref else_clause} =>
+            visitor.visit_if(pred, if_clause, else_clause) && walk_expr(visitor, pred) &&
+            walk_expr(visitor, if_clause) && walk_expr(visitor, else_clause),
+        ExprKind::For {ref var, ref start, ref cond, ref step, ref body} =>
+            visitor.visit_for(var, start, cond, step.as_ref().map(|step| &**step), body) &&
+            walk_expr(visitor, start) && walk_expr(visitor, cond) &&
+            step.as_ref().map_or(true, |step| walk_expr(visitor, step)) && walk_expr(visitor, body),
+        ExprKind::While {ref cond, ref body} =>
+            visitor.visit_while(cond, body) && walk_expr(visitor, cond) && walk_expr(visitor, body),
+        ExprKind::VarIn {ref vars, ref body} =>
+            visitor.visit_var_in(vars, body) &&
+            vars.iter().all(|&(_, ref init)| init.as_ref().map_or(true, |init| walk_expr(visitor, init))) &&
+            walk_expr(visitor, body),
+        ExprKind::Sequence(ref exprs) =>
+            visitor.visit_sequence(exprs) && exprs.iter().all(|expr| walk_expr(visitor, expr)),
+    }
 }
 
-fn token_precedence(tok: char) -> Option<u32> {
-    match tok {
-        '+' | '-' => Some(20),
-        '<' => Some(10),
-        '*' => Some(40),
+/// The names of all the variables `expr` uses that aren't bound by a `for` or
+/// `var` inside it.
+pub fn free_variables(expr: &Expr) -> HashSet<String> {
+    let mut free = HashSet::new();
+    collect_free_variables(expr, &mut Vec::new(), &mut free);
+    free
+}
+
+fn collect_free_variables<'a>(expr: &'a Expr, bound: &mut Vec<&'a str>, free: &mut HashSet<String>) {
+    match expr.kind {
+        ExprKind::Number(_) | ExprKind::Integer(_) | ExprKind::Str(_) => (),
+        ExprKind::Variable(ref name) => {
+            if !bound.contains(&name.as_str()) {
+                free.insert(name.clone());
+            }
+        },
+        ExprKind::Binary {ref lhs, ref rhs, ..} => {
+            collect_free_variables(lhs, bound, free);
+            collect_free_variables(rhs, bound, free);
+        },
+        ExprKind::Unary {ref operand, ..} => collect_free_variables(operand, bound, free),
+        ExprKind::Call {ref args, ..} => {
+            for arg in args {
+                collect_free_variables(arg, bound, free);
+            }
+        },
+        ExprKind::IfElse {ref pred, ref if_clause, ref else_clause} => {
+            collect_free_variables(pred, bound, free);
+            collect_free_variables(if_clause, bound, free);
+            collect_free_variables(else_clause, bound, free);
+        },
+        ExprKind::For {ref var, ref start, ref cond, ref step, ref body} => {
+            // The start is evaluated before the loop variable exists
+            collect_free_variables(start, bound, free);
+            bound.push(var);
+            collect_free_variables(cond, bound, free);
+            if let Some(ref step) = *step {
+                collect_free_variables(step, bound, free);
+            }
+            collect_free_variables(body, bound, free);
+            bound.pop();
+        },
+        ExprKind::While {ref cond, ref body} => {
+            collect_free_variables(cond, bound, free);
+            collect_free_variables(body, bound, free);
+        },
+        ExprKind::VarIn {ref vars, ref body} => {
+            // Each initializer can see the variables before it
+            let outer = bound.len();
+            for &(ref name, ref init) in vars {
+                if let Some(ref init) = *init {
+                    collect_free_variables(init, bound, free);
+                }
+                bound.push(name);
+            }
+            collect_free_variables(body, bound, free);
+            bound.truncate(outer);
+        },
+        ExprKind::Sequence(ref exprs) => {
+            for expr in exprs {
+                collect_free_variables(expr, bound, free);
+            }
+        },
+    }
+}
+
+/// The names of all the functions `expr` calls.
+pub fn called_functions(expr: &Expr) -> HashSet<String> {
+    struct Calls(HashSet<String>);
+    impl Visitor for Calls {
+        fn visit_call(&mut self, name: &str, _args: &[Box<Expr>]) -> bool {
+            self.0.insert(String::from(name));
+            true
+        }
+    }
+    let mut calls = Calls(HashSet::new());
+    walk_expr(&mut calls, expr);
+    calls.0
+}
+
+/// The parameters of `fun` that its body never uses, in the order they're declared.
+/// Calling a parameter, which calls the function it holds, counts as using it.
+pub fn unused_params(fun: &Function) -> Vec<String> {
+    let used = free_variables(&fun.body);
+    let called = called_functions(&fun.body);
+    fun.prototype.args.iter()
+        .filter(|arg| !used.contains(*arg) && !called.contains(*arg))
+        .cloned()
+        .collect()
+}
+
+/// Checks that everything `function` refers to exists before generating any code for it.
+///
+/// `known` maps the name of every function that can be called to its
+/// prototype. `function` itself can always be called, so it can recurse,
+/// and so can builtins like `sqrt` and `max`. Known functions can also be
+/// used as values, and calling a variable calls the function it holds,
+/// which can't be checked.
+pub fn validate(function: &Function, known: &HashMap<String, Prototype>) -> Result<(), CompileError> {
+    struct Locals(HashSet<String>);
+    impl Visitor for Locals {
+        fn visit_for(&mut self, var: &str, _start: &Expr, _cond: &Expr,
+                     _step: Option<&Expr>, _body: &Expr) -> bool {
+            self.0.insert(String::from(var));
+            true
+        }
+        fn visit_var_in(&mut self, vars: &[(String, Option<Box<Expr>>)], _body: &Expr) -> bool {
+            self.0.extend(vars.iter().map(|&(ref name, _)| name.clone()));
+            true
+        }
+    }
+    struct CallChecker<'a> {
+        known: &'a HashMap<String, Prototype>,
+        this: &'a Prototype,
+        locals: HashSet<String>,
+        error: Option<CompileError>,
+    }
+    impl<'a> CallChecker<'a> {
+        fn check(&mut self, name: &str, found: usize, missing: CompileError) -> bool {
+            let (expected, is_vararg) = if name == self.this.name {
+                (self.this.arity(), self.this.is_vararg)
+            } else {
+                match self.known.get(name) {
+                    Some(proto) => (proto.arity(), proto.is_vararg),
+                    None => match builtin_arity(name) {
+                        Some(expected) => (expected, false),
+                        None => {
+                            self.error = Some(missing);
+                            return false
+                        },
+                    },
+                }
+            };
+            // Varargs functions take extra arguments, but still need the named ones
+            if found != expected && !(is_vararg && found > expected) {
+                self.error = Some(CompileError::ArityMismatch {
+                    name: String::from(name),
+                    expected: expected,
+                    found: found,
+                });
+                return false
+            }
+            true
+        }
+    }
+    impl<'a> Visitor for CallChecker<'a> {
+        fn visit_call(&mut self, name: &str, args: &[Box<Expr>]) -> bool {
+            if self.locals.contains(name) {
+                return true
+            }
+            self.check(name, args.len(), CompileError::UnknownFunction(String::from(name)))
+        }
+        fn visit_binary(&mut self, op: &str, _lhs: &Expr, _rhs: &Expr) -> bool {
+            if BUILTIN_BINARY_OPS.contains(&op) {
+                return true
+            }
+            self.check(&format!("binary{}", op), 2, CompileError::InvalidOperator(String::from(op)))
+        }
+    }
+    let mut locals = Locals(function.prototype.args.iter().cloned().collect());
+    walk_expr(&mut locals, &function.body);
+    let mut checker = CallChecker {
+        known: known,
+        this: &function.prototype,
+        locals: locals.0,
+        error: None,
+    };
+    walk_expr(&mut checker, &function.body);
+    if let Some(err) = checker.error {
+        return Err(err)
+    }
+    let mut unknown: Vec<String> = free_variables(&function.body).into_iter()
+        .filter(|name| !function.prototype.args.contains(name))
+        .filter(|name| !known.contains_key(name) && *name != function.prototype.name)
+        .collect();
+    // Report the same variable every time
+    unknown.sort();
+    match unknown.into_iter().next() {
+        Some(name) => Err(CompileError::UnknownVariable(name)),
+        None => Ok(()),
+    }
+}
+
+/// The binary operators codegen implements itself. Any other operator is a
+/// call to a user-defined function.
+const BUILTIN_BINARY_OPS: &'static [&'static str] = &[
+    ":", "=", "&&", "||", "+", "-", "*", "/", "%", "^", "<", ">", "<=", ">=", "==", "!=",
+];
+
+/// The number of arguments a builtin function like `sqrt` or `max` takes,
+/// or None if there's no builtin called `name`. Codegen implements these
+/// itself, unless the user defines a function with the same name.
+pub fn builtin_arity(name: &str) -> Option<usize> {
+    match name {
+        "max" | "min" => Some(2),
+        "assert" | "sin" | "cos" | "sqrt" => Some(1),
         _ => None,
     }
 }
 
+/// The precedence given to user-defined binary operators that don't specify one.
+const DEFAULT_BINARY_PRECEDENCE: u32 = 30;
+
+/// The precedence of `:`, which is lower than any other operator.
+const SEQUENCE_PRECEDENCE: u32 = 1;
+
+/// The precedences of the builtin binary operators.
+fn default_precedence() -> HashMap<String, u32> {
+    let mut precedence = HashMap::new();
+    // `a : b` evaluates a, then gives b
+    precedence.insert(String::from(":"), SEQUENCE_PRECEDENCE);
+    precedence.insert(String::from("="), 2);
+    // Not really a binary operator, `a ? b : c` is parsed as an if/else
+    precedence.insert(String::from("?"), 3);
+    precedence.insert(String::from("||"), 5);
+    precedence.insert(String::from("&&"), 6);
+    for op in &["<", ">", "<=", ">=", "==", "!="] {
+        precedence.insert(op.to_string(), 10);
+    }
+    precedence.insert(String::from("+"), 20);
+    precedence.insert(String::from("-"), 20);
+    precedence.insert(String::from("*"), 40);
+    precedence.insert(String::from("/"), 40);
+    precedence.insert(String::from("%"), 40);
+    precedence.insert(String::from("^"), 60);
+    precedence
+}
+
+/// Binary operators that group to the right instead of the left.
+/// Assignment does too, so `a = b = 1` sets both.
+fn is_right_associative(op: &str) -> bool {
+    op == "^" || op == "="
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -216,46 +1616,46 @@ mod tests {
     fn test_number_parsing() {
         let mut parser = Parser::from_source("1");
         let ast = parser.parse_expression().unwrap();
-        assert_eq!(ast, Box::new(Expr::Number(1.0)));
+        assert_eq!(ast, Expr::new(ExprKind::Number(1.0)));
         let mut parser = Parser::from_source("1234567890");
         let ast = parser.parse_expression().unwrap();
-        assert_eq!(ast, Box::new(Expr::Number(1234567890.0)));
+        assert_eq!(ast, Expr::new(ExprKind::Number(1234567890.0)));
         let mut parser = Parser::from_source("3.14159");
         let ast = parser.parse_expression().unwrap();
-        assert_eq!(ast, Box::new(Expr::Number(3.14159)));
+        assert_eq!(ast, Expr::new(ExprKind::Number(3.14159)));
         let mut parser = Parser::from_source("1.");
         let ast = parser.parse_expression().unwrap();
-        assert_eq!(ast, Box::new(Expr::Number(1.0)));
+        assert_eq!(ast, Expr::new(ExprKind::Number(1.0)));
         let mut parser = Parser::from_source(".1");
         let ast = parser.parse_expression().unwrap();
-        assert_eq!(ast, Box::new(Expr::Number(0.1)));
+        assert_eq!(ast, Expr::new(ExprKind::Number(0.1)));
     }
     #[test]
     fn test_basic_expression_parsing() {
         let mut parser = Parser::from_source("1 + 1");
         let ast = parser.parse_expression().unwrap();
-        assert_eq!(ast, Box::new(Expr::Binary {
-            op: '+',
-            lhs: Box::new(Expr::Number(1.0)),
-            rhs: Box::new(Expr::Number(1.0)),
+        assert_eq!(ast, Expr::new(ExprKind::Binary {
+            op: String::from("+"),
+            lhs: Expr::new(ExprKind::Number(1.0)),
+            rhs: Expr::new(ExprKind::Number(1.0)),
         }))
     }
     #[test]
     fn test_complicated_expression_parsing() {
         let mut parser = Parser::from_source("1 + 2 * 3 - 2");
         let got = parser.parse_expression().unwrap();
-        let expected = Box::new(Expr::Binary {
-            op: '-',
-            lhs: Box::new(Expr::Binary {
-                op: '+',
-                lhs: Box::new(Expr::Number(1.0)),
-                rhs: Box::new(Expr::Binary {
-                    op: '*',
-                    lhs: Box::new(Expr::Number(2.0)),
-                    rhs: Box::new(Expr::Number(3.0)),
+        let expected = Expr::new(ExprKind::Binary {
+            op: String::from("-"),
+            lhs: Expr::new(ExprKind::Binary {
+                op: String::from("+"),
+                lhs: Expr::new(ExprKind::Number(1.0)),
+                rhs: Expr::new(ExprKind::Binary {
+                    op: String::from("*"),
+                    lhs: Expr::new(ExprKind::Number(2.0)),
+                    rhs: Expr::new(ExprKind::Number(3.0)),
                 }),
             }),
-            rhs: Box::new(Expr::Number(2.0)),
+            rhs: Expr::new(ExprKind::Number(2.0)),
         });
         assert_eq!(got, expected)
     }
@@ -275,14 +1675,25 @@ mod tests {
         assert_eq!(got, expected);
     }
     #[test]
+    fn test_prototype_arity() {
+        let arity = |source: &str| Parser::from_source(source).parse_extern().unwrap().arity();
+        assert_eq!(arity("extern answer()"), 0);
+        assert_eq!(arity("extern sin(x)"), 1);
+        assert_eq!(arity("extern bar(a b c)"), 3);
+        // Only the named parameters count
+        assert_eq!(arity("extern printf(fmt ...)"), 1);
+        assert_eq!(arity("extern unary!(x)"), 1);
+        assert_eq!(arity("extern binary| 5 (a b)"), 2);
+    }
+    #[test]
     fn test_function_definition_parsing() {
         let mut parser = Parser::from_source("def foo() 1 + 1");
         let got = parser.parse_definition().unwrap();
         let expected = Function::new(Prototype::new(String::from("foo"), vec![]),
-                                     Box::new(Expr::Binary {
-                                         op: '+',
-                                         lhs: Box::new(Expr::Number(1.0)),
-                                         rhs: Box::new(Expr::Number(1.0)),
+                                     Expr::new(ExprKind::Binary {
+                                         op: String::from("+"),
+                                         lhs: Expr::new(ExprKind::Number(1.0)),
+                                         rhs: Expr::new(ExprKind::Number(1.0)),
                                      })
         );
         assert_eq!(got, expected);
@@ -293,5 +1704,878 @@ mod tests {
         let got = parser.parse_extern().unwrap();
         let expected = Prototype::new(String::from("sin"), vec![String::from("a")]);
         assert_eq!(got, expected);
+        let mut parser = Parser::from_source("extern printf(fmt ...)");
+        let got = parser.parse_extern().unwrap();
+        let mut expected = Prototype::new(String::from("printf"), vec![String::from("fmt")]);
+        expected.is_vararg = true;
+        assert_eq!(got, expected);
+        assert!(Parser::from_source("extern f(...)").parse_extern().unwrap().is_vararg);
+        // The ... has to come last, and operators can't have one
+        let mut parser = Parser::from_source("extern f(a ... b)");
+        assert_eq!(parser.parse_extern().err().map(|e| e.to_string()),
+                   Some(String::from("Expected ) in prototype, found identifier b at line 1, column 16")));
+        assert!(Parser::from_source("extern binary| (a ...)").parse_extern().is_err());
+    }
+    #[test]
+    fn test_if_else_parsing() {
+        let mut parser = Parser::from_source("if 1 then 2 else 3");
+        let got = parser.parse_expression().unwrap();
+        let expected = Expr::new(ExprKind::IfElse {
+            pred: Expr::new(ExprKind::Number(1.0)),
+            if_clause: Expr::new(ExprKind::Number(2.0)),
+            else_clause: Expr::new(ExprKind::Number(3.0)),
+        });
+        assert_eq!(got, expected);
+    }
+    #[test]
+    fn test_if_else_missing_clauses() {
+        let mut parser = Parser::from_source("if 1 2 else 3");
+        assert!(parser.parse_expression().is_err());
+        let mut parser = Parser::from_source("if 1 then 2");
+        assert!(parser.parse_expression().is_err());
+    }
+    #[test]
+    fn test_for_parsing() {
+        let mut parser = Parser::from_source("for i = 1, i < n, 2 in foo(i)");
+        let got = parser.parse_expression().unwrap();
+        let expected = Expr::new(ExprKind::For {
+            var: String::from("i"),
+            start: Expr::new(ExprKind::Number(1.0)),
+            cond: Expr::new(ExprKind::Binary {
+                op: String::from("<"),
+                lhs: Expr::new(ExprKind::Variable(String::from("i"))),
+                rhs: Expr::new(ExprKind::Variable(String::from("n"))),
+            }),
+            step: Some(Expr::new(ExprKind::Number(2.0))),
+            body: Expr::new(ExprKind::Call {
+                name: String::from("foo"),
+                args: vec![Expr::new(ExprKind::Variable(String::from("i")))],
+            }),
+        });
+        assert_eq!(got, expected);
+    }
+    #[test]
+    fn test_for_parsing_without_step() {
+        let mut parser = Parser::from_source("for i = 0, i < 10 in i");
+        match parser.parse_expression().unwrap().kind {
+            ExprKind::For { step: None, .. } => (),
+            ref x => panic!("Expected a for loop without a step, got {:?}", x),
+        }
+    }
+    #[test]
+    fn test_comparison_operator_parsing() {
+        let mut parser = Parser::from_source("1 <= 2");
+        let got = parser.parse_expression().unwrap();
+        let expected = Expr::new(ExprKind::Binary {
+            op: String::from("<="),
+            lhs: Expr::new(ExprKind::Number(1.0)),
+            rhs: Expr::new(ExprKind::Number(2.0)),
+        });
+        assert_eq!(got, expected);
+        assert_eq!(parser.current, lexer::Token::Eof);
+        let mut parser = Parser::from_source("a + 1 != b");
+        let got = parser.parse_expression().unwrap();
+        let expected = Expr::new(ExprKind::Binary {
+            op: String::from("!="),
+            lhs: Expr::new(ExprKind::Binary {
+                op: String::from("+"),
+                lhs: Expr::new(ExprKind::Variable(String::from("a"))),
+                rhs: Expr::new(ExprKind::Number(1.0)),
+            }),
+            rhs: Expr::new(ExprKind::Variable(String::from("b"))),
+        });
+        assert_eq!(got, expected);
+    }
+    #[test]
+    fn test_division_parsing() {
+        let mut parser = Parser::from_source("1 / 2 * 3");
+        let got = parser.parse_expression().unwrap();
+        let expected = Expr::new(ExprKind::Binary {
+            op: String::from("*"),
+            lhs: Expr::new(ExprKind::Binary {
+                op: String::from("/"),
+                lhs: Expr::new(ExprKind::Number(1.0)),
+                rhs: Expr::new(ExprKind::Number(2.0)),
+            }),
+            rhs: Expr::new(ExprKind::Number(3.0)),
+        });
+        assert_eq!(got, expected);
+    }
+    #[test]
+    fn test_error_variants() {
+        let mut parser = Parser::from_source("foo a");
+        let got = parser.parse_prototype();
+        let expected = Err(CompileError::UnexpectedToken {
+            expected: String::from("( in prototype"),
+            found: lexer::Token::Identifier(String::from("a")),
+            span: lexer::Span { line: 1, col: 5 },
+        });
+        assert_eq!(got, expected);
+        let mut parser = Parser::from_source("1 + )");
+        assert_eq!(parser.parse_expression(),
+                   Err(CompileError::ExpectedExpression(lexer::Token::UnknownChar(')'),
+                                                        lexer::Span { line: 1, col: 5 })));
+    }
+    #[test]
+    fn test_error_spans() {
+        let mut parser = Parser::from_source("1 +\n )");
+        let err = parser.parse_expression().unwrap_err();
+        assert_eq!(err, CompileError::ExpectedExpression(lexer::Token::UnknownChar(')'),
+                                                         lexer::Span { line: 2, col: 2 }));
+        assert_eq!(err.to_string(),
+                   "Unknown token ')' when expecting an expression at line 2, column 2");
+    }
+    #[test]
+    fn test_malformed_number_error() {
+        let mut parser = Parser::from_source("1 + 1.2.3");
+        let expected = Err(CompileError::Lex(lexer::LexError::MalformedNumber(String::from("1.2.3")),
+                                             lexer::Span { line: 1, col: 5 }));
+        assert_eq!(parser.parse_expression(), expected);
+    }
+    #[test]
+    fn test_paren_expression_parsing() {
+        let mut parser = Parser::from_source("(1 + 2) * 3");
+        let got = parser.parse_expression().unwrap();
+        let expected = Expr::new(ExprKind::Binary {
+            op: String::from("*"),
+            lhs: Expr::new(ExprKind::Binary {
+                op: String::from("+"),
+                lhs: Expr::new(ExprKind::Number(1.0)),
+                rhs: Expr::new(ExprKind::Number(2.0)),
+            }),
+            rhs: Expr::new(ExprKind::Number(3.0)),
+        });
+        assert_eq!(got, expected);
+    }
+    #[test]
+    fn test_unary_parsing() {
+        // Negating a literal gives a negative literal
+        let mut parser = Parser::from_source("-5");
+        assert_eq!(parser.parse_expression().unwrap(), Expr::new(ExprKind::Number(-5.0)));
+        let mut parser = Parser::from_source("-3.5");
+        assert_eq!(parser.parse_expression().unwrap(), Expr::new(ExprKind::Number(-3.5)));
+        let mut parser = Parser::from_source("-7i");
+        assert_eq!(parser.parse_expression().unwrap(), Expr::new(ExprKind::Integer(-7)));
+        let mut parser = Parser::from_source("3 - -2");
+        let got = parser.parse_expression().unwrap();
+        let expected = Expr::new(ExprKind::Binary {
+            op: String::from("-"),
+            lhs: Expr::new(ExprKind::Number(3.0)),
+            rhs: Expr::new(ExprKind::Number(-2.0)),
+        });
+        assert_eq!(got, expected);
+        let mut parser = Parser::from_source("--1");
+        assert_eq!(parser.parse_expression().unwrap(), Expr::new(ExprKind::Number(1.0)));
+        let mut parser = Parser::from_source("--x");
+        let got = parser.parse_expression().unwrap();
+        let expected = Expr::new(ExprKind::Unary {
+            op: '-',
+            operand: Expr::new(ExprKind::Unary {
+                op: '-',
+                operand: Expr::new(ExprKind::Variable(String::from("x"))),
+            }),
+        });
+        assert_eq!(got, expected);
+        let mut parser = Parser::from_source("-(a + b)");
+        let got = parser.parse_expression().unwrap();
+        let expected = Expr::new(ExprKind::Unary {
+            op: '-',
+            operand: Expr::new(ExprKind::Binary {
+                op: String::from("+"),
+                lhs: Expr::new(ExprKind::Variable(String::from("a"))),
+                rhs: Expr::new(ExprKind::Variable(String::from("b"))),
+            }),
+        });
+        assert_eq!(got, expected);
+        let mut parser = Parser::from_source("!x");
+        let got = parser.parse_expression().unwrap();
+        let expected = Expr::new(ExprKind::Unary {
+            op: '!',
+            operand: Expr::new(ExprKind::Variable(String::from("x"))),
+        });
+        assert_eq!(got, expected);
+    }
+    #[test]
+    fn test_binary_operator_definition() {
+        let mut parser = Parser::from_source("def binary| 5 (a b) if a then 1 else b");
+        let got = parser.parse_definition().unwrap();
+        assert_eq!(got.prototype.name, "binary|");
+        assert_eq!(got.prototype.args, vec![String::from("a"), String::from("b")]);
+        assert_eq!(got.prototype.precedence, Some(5));
+        assert!(got.prototype.is_binary_op());
+        assert_eq!(got.prototype.operator_name(), "|");
+        let mut parser = Parser::from_source("def binary& (a b) a * b");
+        assert_eq!(parser.parse_definition().unwrap().prototype.precedence, Some(30));
+        let mut parser = Parser::from_source("def binaryish(a) a");
+        assert_eq!(parser.parse_definition().unwrap().prototype.precedence, None);
+        let mut parser = Parser::from_source("def binary| 5 (a) a");
+        assert_eq!(parser.parse_definition().map(|f| f.prototype),
+                   Err(CompileError::ArityMismatch {
+                       name: String::from("binary|"),
+                       expected: 2,
+                       found: 1,
+                   }));
+    }
+    #[test]
+    fn test_user_defined_operator_parsing() {
+        let mut parser = Parser::from_source("def binary| 5 (a b) a + b 1 < 2 | 3");
+        parser.parse_definition().unwrap();
+        // | binds looser than < (precedence 10)
+        let got = parser.parse_expression().unwrap();
+        let expected = Expr::new(ExprKind::Binary {
+            op: String::from("|"),
+            lhs: Expr::new(ExprKind::Binary {
+                op: String::from("<"),
+                lhs: Expr::new(ExprKind::Number(1.0)),
+                rhs: Expr::new(ExprKind::Number(2.0)),
+            }),
+            rhs: Expr::new(ExprKind::Number(3.0)),
+        });
+        assert_eq!(got, expected);
+    }
+    #[test]
+    fn test_set_precedence() {
+        let mut parser = Parser::from_source("1 + 2 * 3");
+        parser.set_precedence("*", 10);
+        let got = parser.parse_expression().unwrap();
+        let expected = Expr::new(ExprKind::Binary {
+            op: String::from("*"),
+            lhs: Expr::new(ExprKind::Binary {
+                op: String::from("+"),
+                lhs: Expr::new(ExprKind::Number(1.0)),
+                rhs: Expr::new(ExprKind::Number(2.0)),
+            }),
+            rhs: Expr::new(ExprKind::Number(3.0)),
+        });
+        assert_eq!(got, expected);
+    }
+    #[test]
+    fn test_unary_operator_definition() {
+        let mut parser = Parser::from_source("def unary~(v) 0 - v ~~x");
+        let got = parser.parse_definition().unwrap();
+        assert_eq!(got.prototype.name, "unary~");
+        assert!(got.prototype.is_unary_op());
+        assert_eq!(got.prototype.operator_name(), "~");
+        let got = parser.parse_expression().unwrap();
+        let expected = Expr::new(ExprKind::Call {
+            name: String::from("unary~"),
+            args: vec![Expr::new(ExprKind::Call {
+                name: String::from("unary~"),
+                args: vec![Expr::new(ExprKind::Variable(String::from("x")))],
+            })],
+        });
+        assert_eq!(got, expected);
+        let mut parser = Parser::from_source("def unary~(a b) a");
+        assert!(parser.parse_definition().is_err());
+    }
+    #[test]
+    fn test_from_tokens() {
+        use lexer::Token;
+        let tokens = vec![Token::Identifier(String::from("x")), Token::UnknownChar('*'),
+                          Token::UnknownChar('('), Token::Number(1.0), Token::UnknownChar('+'),
+                          Token::Number(2.0), Token::UnknownChar(')')];
+        let mut parser = Parser::from_tokens(tokens.clone());
+        assert_eq!(parser.parse_expression(), Parser::from_source("x * (1 + 2)").parse_expression());
+        assert!(parser.is_at_end());
+        let mut with_eof = tokens.clone();
+        with_eof.push(Token::Eof);
+        assert_eq!(Parser::from_tokens(with_eof).parse_program().map(|items| items.len()), Ok(1));
+        let mut parser = Parser::from_tokens(vec![Token::Number(1.0), Token::UnknownChar('+')]);
+        assert_eq!(parser.parse_expression(), Err(CompileError::UnexpectedEof(lexer::Span { line: 1, col: 3 })));
+    }
+    #[test]
+    fn test_expr_builders() {
+        let mut parser = Parser::from_source("1 + 2 * 3");
+        let expected = Expr::binary("+", Expr::number(1.0), Expr::binary("*", Expr::number(2.0), Expr::number(3.0)));
+        assert_eq!(parser.parse_expression().unwrap(), expected);
+        let mut parser = Parser::from_source("f(x, g())");
+        let expected = Expr::call("f", vec![Expr::variable("x"), Expr::call("g", vec![])]);
+        assert_eq!(parser.parse_expression().unwrap(), expected);
+    }
+    #[test]
+    fn test_zero_argument_calls() {
+        let mut parser = Parser::from_source("answer() + f(answer())");
+        assert_eq!(pretty(&parser.parse_expression().unwrap()), "(answer() + f(answer()))");
+        let mut parser = Parser::from_source("answer()");
+        assert_eq!(parser.parse_expression().unwrap(), Expr::new(ExprKind::Call {
+            name: String::from("answer"),
+            args: vec![],
+        }));
+        // Empty arguments are still errors
+        assert!(Parser::from_source("f(1,)").parse_expression().is_err());
+        assert!(Parser::from_source("f(,)").parse_expression().is_err());
+    }
+    #[test]
+    fn test_assignment_parsing() {
+        let mut parser = Parser::from_source("a = b = 1");
+        assert_eq!(pretty(&parser.parse_expression().unwrap()), "(a = (b = 1))");
+        let mut parser = Parser::from_source("a = b + 1 < c");
+        assert_eq!(pretty(&parser.parse_expression().unwrap()), "(a = ((b + 1) < c))");
+        let mut parser = Parser::from_source("a == b");
+        assert_eq!(parser.parse_expression().unwrap(), Expr::new(ExprKind::Binary {
+            op: String::from("=="),
+            lhs: Expr::new(ExprKind::Variable(String::from("a"))),
+            rhs: Expr::new(ExprKind::Variable(String::from("b"))),
+        }));
+    }
+    #[test]
+    fn test_var_parsing() {
+        let mut parser = Parser::from_source("var x = 4 in x = x + 1");
+        let got = parser.parse_expression().unwrap();
+        let expected = Expr::new(ExprKind::VarIn {
+            vars: vec![(String::from("x"), Some(Expr::new(ExprKind::Number(4.0))))],
+            body: Expr::new(ExprKind::Binary {
+                op: String::from("="),
+                lhs: Expr::new(ExprKind::Variable(String::from("x"))),
+                rhs: Expr::new(ExprKind::Binary {
+                    op: String::from("+"),
+                    lhs: Expr::new(ExprKind::Variable(String::from("x"))),
+                    rhs: Expr::new(ExprKind::Number(1.0)),
+                }),
+            }),
+        });
+        assert_eq!(got, expected);
+        let mut parser = Parser::from_source("var a, b = 2 in a");
+        let got = parser.parse_expression().unwrap();
+        let expected = Expr::new(ExprKind::VarIn {
+            vars: vec![(String::from("a"), None),
+                       (String::from("b"), Some(Expr::new(ExprKind::Number(2.0))))],
+            body: Expr::new(ExprKind::Variable(String::from("a"))),
+        });
+        assert_eq!(got, expected);
+        let mut parser = Parser::from_source("var a = 1 a");
+        assert!(parser.parse_expression().is_err());
+    }
+    #[test]
+    fn test_program_parsing() {
+        let mut parser = Parser::from_source("extern sin(x) def f(x) sin(x) f(1)");
+        let got = parser.parse_program().unwrap();
+        let call = |name: &str, arg: ExprKind| Expr::new(ExprKind::Call {
+            name: String::from(name),
+            args: vec![Expr::new(arg)],
+        });
+        let expected = vec![
+            Item::Extern(Prototype::new(String::from("sin"), vec![String::from("x")])),
+            Item::Definition(Function::new(Prototype::new(String::from("f"), vec![String::from("x")]),
+                                           call("sin", ExprKind::Variable(String::from("x"))))),
+            Item::TopLevel(Function::new(Prototype::new(String::from("__top_level_expr"), vec![]),
+                                         call("f", ExprKind::Number(1.0)))),
+        ];
+        assert_eq!(got, expected);
+        let mut parser = Parser::from_source("");
+        assert_eq!(parser.parse_program(), Ok(vec![]));
+    }
+    #[test]
+    fn test_items() {
+        let mut parser = Parser::from_source("extern sin(x)\ndef f(x) sin(x)\nf(1)");
+        let mut items = parser.items();
+        match items.next() {
+            Some(Ok(Item::Extern(ref proto))) => assert_eq!(proto.name, "sin"),
+            other => panic!("expected an extern, got {:?}", other),
+        }
+        match items.next() {
+            Some(Ok(Item::Definition(ref func))) => assert_eq!(func.prototype.name, "f"),
+            other => panic!("expected a definition, got {:?}", other),
+        }
+        match items.next() {
+            Some(Ok(Item::TopLevel(ref func))) => assert_eq!(pretty(&func.body), "f(1)"),
+            other => panic!("expected a top level expression, got {:?}", other),
+        }
+        assert_eq!(items.next(), None);
+        assert_eq!(items.next(), None);
+        // Nothing past the first error is parsed
+        let mut parser = Parser::from_source("1 ) 2");
+        let got: Vec<_> = parser.items().collect();
+        assert_eq!(got.len(), 2);
+        assert!(got[0].is_ok());
+        assert!(got[1].is_err());
+    }
+    #[test]
+    fn test_error_recovery() {
+        let names = |items: &[Item]| items.iter().map(|item| match *item {
+            Item::Definition(ref func) | Item::TopLevel(ref func) => func.prototype.name.clone(),
+            Item::Extern(ref proto) => proto.name.clone(),
+        }).collect::<Vec<_>>();
+        let mut parser = Parser::from_source("def good(x) x\ndef bad(x y + 1\ndef better(y) y");
+        let (items, errors) = parser.parse_program_recovering();
+        assert_eq!(names(&items), vec!["good", "better"]);
+        assert_eq!(errors, vec![CompileError::UnexpectedToken {
+            expected: String::from(") in prototype"),
+            found: lexer::Token::UnknownChar('+'),
+            span: lexer::Span { line: 2, col: 13 },
+        }]);
+        // Parsing picks up again at the next line, or at a def on the same line
+        let mut parser = Parser::from_source(") 1\n2 + 3\ndef f(x) ) def g(x) x\nextern h(");
+        let (items, errors) = parser.parse_program_recovering();
+        assert_eq!(names(&items), vec!["__top_level_expr", "g"]);
+        assert_eq!(errors.len(), 3);
+        assert_eq!(errors[2], CompileError::UnexpectedEof(lexer::Span { line: 4, col: 10 }));
+    }
+    #[test]
+    fn test_sequence_parsing() {
+        let mut parser = Parser::from_source("a = 1; b; a + b");
+        let got = parser.parse_expression().unwrap();
+        let expected = Expr::new(ExprKind::Sequence(vec![
+            Expr::new(ExprKind::Binary {
+                op: String::from("="),
+                lhs: Expr::new(ExprKind::Variable(String::from("a"))),
+                rhs: Expr::new(ExprKind::Number(1.0)),
+            }),
+            Expr::new(ExprKind::Variable(String::from("b"))),
+            Expr::new(ExprKind::Binary {
+                op: String::from("+"),
+                lhs: Expr::new(ExprKind::Variable(String::from("a"))),
+                rhs: Expr::new(ExprKind::Variable(String::from("b"))),
+            }),
+        ]));
+        assert_eq!(got, expected);
+        // Empty statements and trailing semicolons are errors
+        let mut parser = Parser::from_source("a;; b");
+        assert!(parser.parse_expression().is_err());
+        let mut parser = Parser::from_source("a;");
+        assert!(parser.parse_expression().is_err());
+    }
+    #[test]
+    fn test_block_parsing() {
+        let mut parser = Parser::from_source("{1; 2; 3}");
+        let got = parser.parse_expression().unwrap();
+        assert_eq!(got, Expr::new(ExprKind::Sequence(vec![
+            Expr::new(ExprKind::Number(1.0)),
+            Expr::new(ExprKind::Number(2.0)),
+            Expr::new(ExprKind::Number(3.0)),
+        ])));
+        assert_eq!(got.span, Some(SourceRange {
+            start: lexer::Span { line: 1, col: 1 },
+            end: lexer::Span { line: 1, col: 10 },
+        }));
+        let mut parser = Parser::from_source("if c then { a = 1; a } else { b } + 1");
+        assert_eq!(pretty(&parser.parse_expression().unwrap()),
+                   "(if c then ((a = 1); a) else (b + 1))");
+        let mut parser = Parser::from_source("{1; 2");
+        assert_eq!(parser.parse_expression(), Err(CompileError::UnexpectedEof(lexer::Span { line: 1, col: 6 })));
+        let mut parser = Parser::from_source("{}");
+        assert!(parser.parse_expression().is_err());
+    }
+    #[test]
+    fn test_pretty() {
+        let mut parser = Parser::from_source("1 + 2 * 3 - 2");
+        assert_eq!(pretty(&parser.parse_expression().unwrap()), "((1 + (2 * 3)) - 2)");
+        let mut parser = Parser::from_source("if x < 1.5 then foo(x, -y) else bar(z)");
+        assert_eq!(pretty(&parser.parse_expression().unwrap()),
+                   "(if (x < 1.5) then foo(x, (-y)) else bar(z))");
+        let mut parser = Parser::from_source("var a = 1, b in for i = 0, i < 3 in a = a + i; a");
+        assert_eq!(pretty(&parser.parse_expression().unwrap()),
+                   "(var a = 1, b in (for i = 0, (i < 3) in ((a = (a + i)); a)))");
+    }
+    #[test]
+    fn test_fold_constants() {
+        let mut parser = Parser::from_source("(2 + 3) * x");
+        let got = fold_constants(parser.parse_expression().unwrap());
+        let expected = Expr::new(ExprKind::Binary {
+            op: String::from("*"),
+            lhs: Expr::new(ExprKind::Number(5.0)),
+            rhs: Expr::new(ExprKind::Variable(String::from("x"))),
+        });
+        assert_eq!(got, expected);
+        let mut parser = Parser::from_source("f(1 - 4 / 2, 2 * 3 * y)");
+        let got = fold_constants(parser.parse_expression().unwrap());
+        assert_eq!(pretty(&got), "f(-1, (6 * y))");
+        // Division by zero is left for codegen to deal with
+        let mut parser = Parser::from_source("1 / (1 - 1)");
+        let got = fold_constants(parser.parse_expression().unwrap());
+        let expected = Expr::new(ExprKind::Binary {
+            op: String::from("/"),
+            lhs: Expr::new(ExprKind::Number(1.0)),
+            rhs: Expr::new(ExprKind::Number(0.0)),
+        });
+        assert_eq!(got, expected);
+    }
+    #[test]
+    fn test_peek_token() {
+        let mut parser = Parser::from_source("x = 1");
+        assert_eq!(parser.current, lexer::Token::Identifier(String::from("x")));
+        assert_eq!(parser.peek_token(), Some(&lexer::Token::Assign));
+        // Peeking doesn't consume anything
+        assert_eq!(parser.peek_token(), Some(&lexer::Token::Assign));
+        parser.get_next_token();
+        assert_eq!(parser.current, lexer::Token::Assign);
+        assert_eq!(parser.peek_token(), Some(&lexer::Token::Number(1.0)));
+        parser.get_next_token();
+        assert_eq!(parser.peek_token(), Some(&lexer::Token::Eof));
+        parser.get_next_token();
+        assert_eq!(parser.current, lexer::Token::Eof);
+        assert_eq!(parser.peek_token(), None);
+        // Moving past the end stays at the end
+        parser.get_next_token();
+        assert_eq!(parser.current, lexer::Token::Eof);
+        assert_eq!(parser.span, lexer::Span { line: 1, col: 6 });
+    }
+    #[test]
+    fn test_integer_parsing() {
+        let mut parser = Parser::from_source("1i + 2.0");
+        let got = parser.parse_expression().unwrap();
+        let expected = Expr::new(ExprKind::Binary {
+            op: String::from("+"),
+            lhs: Expr::new(ExprKind::Integer(1)),
+            rhs: Expr::new(ExprKind::Number(2.0)),
+        });
+        assert_eq!(got, expected);
+        assert_eq!(pretty(&got), "(1i + 2)");
+    }
+    #[test]
+    fn test_unexpected_eof() {
+        let mut parser = Parser::from_source("1 +  \n");
+        assert_eq!(parser.parse_expression(), Err(CompileError::UnexpectedEof(lexer::Span { line: 1, col: 4 })));
+        let mut parser = Parser::from_source("def foo(");
+        assert_eq!(parser.parse_definition(), Err(CompileError::UnexpectedEof(lexer::Span { line: 1, col: 9 })));
+        // Unclosed calls and parentheses
+        let mut parser = Parser::from_source("foo(1, 2");
+        assert_eq!(parser.parse_expression(), Err(CompileError::UnexpectedEof(lexer::Span { line: 1, col: 9 })));
+        let mut parser = Parser::from_source("foo(1,");
+        assert_eq!(parser.parse_expression(), Err(CompileError::UnexpectedEof(lexer::Span { line: 1, col: 7 })));
+        let mut parser = Parser::from_source("foo(");
+        assert_eq!(parser.parse_expression(), Err(CompileError::UnexpectedEof(lexer::Span { line: 1, col: 5 })));
+        let mut parser = Parser::from_source("(1 + 2");
+        assert_eq!(parser.parse_expression(), Err(CompileError::UnexpectedEof(lexer::Span { line: 1, col: 7 })));
+        let mut parser = Parser::from_source("((1)");
+        assert_eq!(parser.parse_expression(), Err(CompileError::UnexpectedEof(lexer::Span { line: 1, col: 5 })));
+        let mut parser = Parser::from_source("foo(1 2)");
+        assert_eq!(parser.parse_expression(), Err(CompileError::UnexpectedToken {
+            expected: String::from("\",\" or \")\""),
+            found: lexer::Token::Number(2.0),
+            span: lexer::Span { line: 1, col: 7 },
+        }));
+        // Tokens that are there but wrong are still reported as such
+        let mut parser = Parser::from_source("1 + )");
+        assert_eq!(parser.parse_expression(),
+                   Err(CompileError::ExpectedExpression(lexer::Token::UnknownChar(')'),
+                                                        lexer::Span { line: 1, col: 5 })));
+    }
+    #[test]
+    fn test_visitor() {
+        struct CallCounter {
+            calls: usize,
+        }
+        impl Visitor for CallCounter {
+            fn visit_call(&mut self, _name: &str, _args: &[Box<Expr>]) -> bool {
+                self.calls += 1;
+                true
+            }
+        }
+        let mut parser = Parser::from_source("f(g(x), 1) + if h(y) then 2 else k(3)");
+        let expr = parser.parse_expression().unwrap();
+        let mut counter = CallCounter { calls: 0 };
+        assert!(walk_expr(&mut counter, &expr));
+        assert_eq!(counter.calls, 4);
+
+        // Stops at the first variable it sees
+        struct FirstVariable(Option<String>);
+        impl Visitor for FirstVariable {
+            fn visit_variable(&mut self, name: &str) -> bool {
+                self.0 = Some(String::from(name));
+                false
+            }
+        }
+        let mut first = FirstVariable(None);
+        assert!(!walk_expr(&mut first, &expr));
+        assert_eq!(first.0, Some(String::from("x")));
+    }
+    #[test]
+    fn test_free_variables() {
+        let set = |names: &[&str]| names.iter().map(|name| String::from(*name)).collect::<HashSet<_>>();
+        let mut parser = Parser::from_source("f(x) + g(x, y)");
+        let expr = parser.parse_expression().unwrap();
+        assert_eq!(free_variables(&expr), set(&["x", "y"]));
+        assert_eq!(called_functions(&expr), set(&["f", "g"]));
+        let mut parser = Parser::from_source("var a = b, c = a in for i = i, i < c in a + d");
+        let expr = parser.parse_expression().unwrap();
+        assert_eq!(free_variables(&expr), set(&["b", "i", "d"]));
+        assert_eq!(called_functions(&expr), set(&[]));
+    }
+    #[test]
+    fn test_expression_spans() {
+        let range = |start: (u32, u32), end: (u32, u32)| Some(SourceRange {
+            start: lexer::Span { line: start.0, col: start.1 },
+            end: lexer::Span { line: end.0, col: end.1 },
+        });
+        let mut parser = Parser::from_source("1 + 2");
+        let expr = parser.parse_expression().unwrap();
+        assert_eq!(expr.span, range((1, 1), (1, 6)));
+        match expr.kind {
+            ExprKind::Binary {ref lhs, ref rhs, ..} => {
+                assert_eq!(lhs.span, range((1, 1), (1, 2)));
+                assert_eq!(rhs.span, range((1, 5), (1, 6)));
+            },
+            _ => panic!("expected a binary expression"),
+        }
+        // Parentheses are part of the range
+        let mut parser = Parser::from_source("def f(x)\n  (x + -1) * foo(x, 2)");
+        let func = parser.parse_definition().unwrap();
+        assert_eq!(func.body.span, range((2, 3), (2, 23)));
+        assert_eq!(func.prototype.span, range((1, 5), (1, 9)));
+        match func.body.kind {
+            ExprKind::Binary {ref lhs, ref rhs, ..} => {
+                assert_eq!(lhs.span, range((2, 3), (2, 11)));
+                assert_eq!(rhs.span, range((2, 14), (2, 23)));
+            },
+            _ => panic!("expected a binary expression"),
+        }
+        // Folding keeps the range of the folded arithmetic
+        let folded = fold_constants(Parser::from_source("x * (1 + 2)").parse_expression().unwrap());
+        match folded.kind {
+            ExprKind::Binary {ref rhs, ..} => {
+                assert_eq!(rhs.kind, ExprKind::Number(3.0));
+                assert_eq!(rhs.span, range((1, 5), (1, 12)));
+            },
+            _ => panic!("expected a binary expression"),
+        }
+        // Nodes built by hand don't have one, and it doesn't matter when comparing
+        assert_eq!(Expr::number(1.0).span, None);
+        assert_eq!(Parser::from_source("1").parse_expression().unwrap(), Expr::number(1.0));
+    }
+    #[test]
+    fn test_parse_expression_str() {
+        assert_eq!(parse_expression_str("1+2"), Ok(Expr::binary("+", Expr::number(1.0), Expr::number(2.0))));
+        assert_eq!(parse_expression_str("x 1"), Err(CompileError::UnexpectedToken {
+            expected: String::from("end of input"),
+            found: lexer::Token::Number(1.0),
+            span: lexer::Span { line: 1, col: 3 },
+        }));
+        // Definitions aren't expressions
+        assert!(parse_expression_str("def f(x) x").is_err());
+    }
+    #[test]
+    fn test_unused_params() {
+        let unused = |source| unused_params(&Parser::from_source(source).parse_definition().unwrap());
+        assert_eq!(unused("def foo(a b) a"), vec![String::from("b")]);
+        assert_eq!(unused("def foo(a) a+a"), Vec::<String>::new());
+        assert_eq!(unused("def apply(f x) f(1)"), vec![String::from("x")]);
+        // A for variable with the same name hides the parameter
+        assert_eq!(unused("def foo(i) for i = 0, i < 3 in 1"), vec![String::from("i")]);
+    }
+    #[test]
+    fn test_duplicate_params() {
+        let mut parser = Parser::from_source("foo(a a)");
+        assert_eq!(parser.parse_prototype(), Err(CompileError::DuplicateParam {
+            name: String::from("foo"),
+            param: String::from("a"),
+            span: lexer::Span { line: 1, col: 7 },
+        }));
+        let mut parser = Parser::from_source("foo(a b)");
+        assert_eq!(parser.parse_prototype(),
+                   Ok(Prototype::new(String::from("foo"), vec![String::from("a"), String::from("b")])));
+    }
+    #[test]
+    fn test_while_parsing() {
+        let mut parser = Parser::from_source("while x > 0 in x = x - 1");
+        let got = parser.parse_expression().unwrap();
+        assert_eq!(pretty(&got), "(while (x > 0) in (x = (x - 1)))");
+        let mut parser = Parser::from_source("while x");
+        assert_eq!(parser.parse_expression(), Err(CompileError::UnexpectedEof(lexer::Span { line: 1, col: 8 })));
+    }
+    #[test]
+    fn test_trailing_tokens() {
+        let mut parser = Parser::from_source("1 + 1");
+        assert!(parser.parse_top_level_expr().is_ok());
+        assert!(parser.is_at_end());
+        let mut parser = Parser::from_source("1 + 1 2");
+        assert_eq!(parser.parse_top_level_expr(), Err(CompileError::UnexpectedToken {
+            expected: String::from("end of input"),
+            found: lexer::Token::Number(2.0),
+            span: lexer::Span { line: 1, col: 7 },
+        }));
+        // Items in a program can still follow each other
+        let mut parser = Parser::from_source("1 + 1 2");
+        assert_eq!(parser.parse_program().map(|items| items.len()), Ok(2));
+    }
+    #[test]
+    fn test_string_parsing() {
+        let mut parser = Parser::from_source(r#"printf("%d\n", 1)"#);
+        let got = parser.parse_expression().unwrap();
+        assert_eq!(got, Expr::new(ExprKind::Call {
+            name: String::from("printf"),
+            args: vec![Expr::new(ExprKind::Str(String::from("%d\n"))), Expr::new(ExprKind::Number(1.0))],
+        }));
+        assert_eq!(pretty(&got), r#"printf("%d\n", 1)"#);
+        let mut parser = Parser::from_source(r#"1 + "oops"#);
+        assert_eq!(parser.parse_expression(),
+                   Err(CompileError::Lex(lexer::LexError::UnterminatedString, lexer::Span { line: 1, col: 5 })));
+    }
+    #[test]
+    fn test_modulo_precedence() {
+        let mut parser = Parser::from_source("1 + 10 % 3 * 2");
+        assert_eq!(pretty(&parser.parse_expression().unwrap()), "(1 + ((10 % 3) * 2))");
+    }
+    #[test]
+    fn test_normalize_comparisons() {
+        let normalize = |source| {
+            let expr = Parser::from_source(source).parse_expression().unwrap();
+            normalize_comparisons(expr)
+        };
+        assert_eq!(normalize("a > b"), Expr::new(ExprKind::Binary {
+            op: String::from("<"),
+            lhs: Expr::new(ExprKind::Variable(String::from("b"))),
+            rhs: Expr::new(ExprKind::Variable(String::from("a"))),
+        }));
+        assert_eq!(pretty(&normalize("a >= b + 1")), "((b + 1) <= a)");
+        assert_eq!(pretty(&normalize("if x > 0 then (x > 1) < 2 else 0")),
+                   "(if (0 < x) then ((1 < x) < 2) else 0)");
+        // Swapping these would change the order things happen in
+        assert_eq!(pretty(&normalize("f(a) > b")), "(f(a) > b)");
+        assert_eq!(pretty(&normalize("a >= (b = 2)")), "(a >= (b = 2))");
+    }
+    #[test]
+    fn test_exponent_associativity() {
+        let parse = |source| pretty(&Parser::from_source(source).parse_expression().unwrap());
+        assert_eq!(parse("2 ^ 3 ^ 2"), "(2 ^ (3 ^ 2))");
+        assert_eq!(parse("2 ^ 3 ^ 2 ^ 1"), "(2 ^ (3 ^ (2 ^ 1)))");
+        assert_eq!(parse("2 * 3 ^ 2 * 4"), "((2 * (3 ^ 2)) * 4)");
+        assert_eq!(parse("a ^ b + c ^ d"), "((a ^ b) + (c ^ d))");
+        // Everything else still groups to the left
+        assert_eq!(parse("1 - 2 - 3"), "((1 - 2) - 3)");
+    }
+    #[test]
+    fn test_validate() {
+        let mut known = HashMap::new();
+        let params = || vec![String::from("a"), String::from("b")];
+        known.insert(String::from("foo"), Prototype::new(String::from("foo"), params()));
+        known.insert(String::from("binary|"), Prototype::new(String::from("binary|"), params()));
+        let mut printf = Prototype::new(String::from("printf"), vec![String::from("fmt")]);
+        printf.is_vararg = true;
+        known.insert(String::from("printf"), printf);
+        let validate_source = |source: &str| {
+            let mut parser = Parser::from_source(source);
+            parser.set_precedence("|", 5);
+            validate(&parser.parse_definition().unwrap(), &known)
+        };
+        assert_eq!(validate_source("def f(x) foo(x, 1) | f(x - 1)"), Ok(()));
+        assert_eq!(validate_source("def f(x) bar(x)"), Err(CompileError::UnknownFunction(String::from("bar"))));
+        assert_eq!(validate_source("def f(x) foo(x)"), Err(CompileError::ArityMismatch {
+            name: String::from("foo"),
+            expected: 2,
+            found: 1,
+        }));
+        assert_eq!(validate_source("def f(x) f(x, x)"), Err(CompileError::ArityMismatch {
+            name: String::from("f"),
+            expected: 1,
+            found: 2,
+        }));
+        assert_eq!(validate_source("def f(x) z + y"), Err(CompileError::UnknownVariable(String::from("y"))));
+        assert_eq!(validate_source("def f(x) var y = x in y"), Ok(()));
+        assert_eq!(validate_source("def f(x) sqrt(x)"), Ok(()));
+        // Functions as values
+        assert_eq!(validate_source("def f(g x) g(x, x)"), Ok(()));
+        assert_eq!(validate_source("def f(x) foo + f"), Ok(()));
+        assert_eq!(validate_source("def f(x) var g = foo in g(x)"), Ok(()));
+        assert_eq!(validate_source("def f(x) max(x, 1) + min(x, 2) + assert(x)"), Ok(()));
+        assert_eq!(validate_source("def f(x) max(x)"), Err(CompileError::ArityMismatch {
+            name: String::from("max"),
+            expected: 2,
+            found: 1,
+        }));
+        assert_eq!(validate_source("def f(x) printf(x) + printf(x, 1, 2)"), Ok(()));
+        assert_eq!(validate_source("def f(x) printf()"), Err(CompileError::ArityMismatch {
+            name: String::from("printf"),
+            expected: 1,
+            found: 0,
+        }));
+    }
+    #[test]
+    fn test_logical_precedence() {
+        let mut parser = Parser::from_source("a < 1 || b && c == 2");
+        assert_eq!(pretty(&parser.parse_expression().unwrap()), "((a < 1) || (b && (c == 2)))");
+        let mut parser = Parser::from_source("x = a || b");
+        assert_eq!(pretty(&parser.parse_expression().unwrap()), "(x = (a || b))");
+    }
+    #[test]
+    fn test_sequence_operator() {
+        let parse = |source: &str| pretty(&Parser::from_source(source).parse_expression().unwrap());
+        assert_eq!(parse("a : b + c"), "(a : (b + c))");
+        assert_eq!(parse("a + b : c"), "((a + b) : c)");
+        assert_eq!(parse("x = 1 : y = 2 : x + y"), "(((x = 1) : (y = 2)) : (x + y))");
+        assert_eq!(parse("a || b : c"), "((a || b) : c)");
+    }
+    #[test]
+    fn test_ternary() {
+        let parse = |source: &str| Parser::from_source(source).parse_expression().unwrap();
+        assert_eq!(parse("x < 1 ? a + 1 : b"), parse("if x < 1 then a + 1 else b"));
+        assert_eq!(pretty(&parse("x = a || b ? 1 : c ? 2 : 3")),
+                   "(x = (if (a || b) then 1 else (if c then 2 else 3)))");
+        assert_eq!(pretty(&parse("a ? b ? 1 : 2 : 3")), "(if a then (if b then 1 else 2) else 3)");
+        assert_eq!(pretty(&parse("a ? b : c : d")), "((if a then b else c) : d)");
+        let mut parser = Parser::from_source("a ? b c");
+        assert_eq!(parser.parse_expression(), Err(CompileError::UnexpectedToken {
+            expected: String::from(": after ?"),
+            found: lexer::Token::Identifier(String::from("c")),
+            span: lexer::Span { line: 1, col: 7 },
+        }));
+    }
+    #[test]
+    fn test_empty_bodies() {
+        let mut parser = Parser::from_source("def noop(x)");
+        assert_eq!(parser.parse_definition(), Err(CompileError::UnexpectedEof(lexer::Span { line: 1, col: 12 })));
+        let mut parser = Parser::from_source("def noop(x) def two() 2 extern sin(x) def last()");
+        parser.allow_empty_bodies(true);
+        let bodies: Vec<Box<Expr>> = parser.parse_program().unwrap().into_iter().filter_map(|item| match item {
+            Item::Definition(func) => Some(func.body),
+            _ => None,
+        }).collect();
+        assert_eq!(bodies, vec![Expr::new(ExprKind::Number(0.0)), Expr::new(ExprKind::Number(2.0)),
+                                Expr::new(ExprKind::Number(0.0))]);
+        let mut parser = Parser::from_source("def noop(x) )");
+        parser.allow_empty_bodies(true);
+        assert!(parser.parse_definition().is_err());
+    }
+    #[test]
+    fn test_expr_key() {
+        use std::collections::hash_map::DefaultHasher;
+        fn hash(key: ExprKey) -> u64 {
+            let mut hasher = DefaultHasher::new();
+            key.hash(&mut hasher);
+            hasher.finish()
+        }
+        let parse = |source| Parser::from_source(source).parse_expression().unwrap();
+        let a = parse("(a*b) + (a*b)");
+        let b = parse("(a*b) + (a*b)");
+        assert_eq!(ExprKey(&a), ExprKey(&b));
+        assert_eq!(hash(ExprKey(&a)), hash(ExprKey(&b)));
+        match a.kind {
+            ExprKind::Binary {ref lhs, ref rhs, ..} => {
+                assert_eq!(ExprKey(lhs), ExprKey(rhs));
+                assert_eq!(hash(ExprKey(lhs)), hash(ExprKey(rhs)));
+            },
+            _ => panic!("expected a binary expression"),
+        }
+        let c = parse("(a*b) + (a*c)");
+        assert!(ExprKey(&a) != ExprKey(&c));
+        assert!(hash(ExprKey(&a)) != hash(ExprKey(&c)));
+        assert!(ExprKey(&parse("for i = 1, i < n in f(i)")) != ExprKey(&parse("for i = 1, i < n, 1 in f(i)")));
+        // Numbers are compared bit for bit
+        assert!(ExprKey(&Expr::number(0.0)) != ExprKey(&Expr::number(-0.0)));
+        assert_eq!(ExprKey(&Expr::number(f64::NAN)), ExprKey(&Expr::number(f64::NAN)));
+        assert!(ExprKey(&Expr::number(1.0)) != ExprKey(&Expr::new(ExprKind::Integer(1))));
+    }
+    #[test]
+    fn test_eliminate_common_subexpressions() {
+        let cse = |source| {
+            let mut fun = Parser::from_source(source).parse_definition().unwrap();
+            let count = eliminate_common_subexpressions(&mut fun);
+            (count, pretty(&fun.body))
+        };
+        assert_eq!(cse("def f(a b) (a*b) + (a*b)"), (1, String::from("(var cse0 = (a * b) in (cse0 + cse0))")));
+        // The biggest duplicate is shared first, then what's left inside it
+        assert_eq!(cse("def f(a b c) (a*b + c) * (a*b + c) - a*b"),
+                   (2, String::from("(var cse1 = (a * b), cse0 = (cse1 + c) in ((cse0 * cse0) - cse1))")));
+        assert_eq!(cse("def f(a b) if a*b then a*b else 0"),
+                   (1, String::from("(var cse0 = (a * b) in (if cse0 then cse0 else 0))")));
+        // Names already in use are skipped
+        assert_eq!(cse("def f(cse0 b) cse0*b + cse0*b"),
+                   (1, String::from("(var cse0_ = (cse0 * b) in (cse0_ + cse0_))")));
+        // Calls might have side effects, and variables might change
+        assert_eq!(cse("def f(a) g(a) + g(a)"), (0, String::from("(g(a) + g(a))")));
+        assert_eq!(cse("def f(a b) (a*b) + (b = 2) + (a*b)").0, 0);
+        assert_eq!(cse("def f(a) for i = 0, i < a*2 in i*2 + i*2").0, 0);
+        assert_eq!(cse("def f(a) for i = 0, i < a*2 in i + a*2").0, 1);
+        assert_eq!(cse("def f(a) a + a").0, 0);
     }
 }